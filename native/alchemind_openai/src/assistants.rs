@@ -0,0 +1,483 @@
+use rustler::{Error, NifMap, NifResult, ResourceArc, Term};
+use serde_json::{json, Map, Value};
+
+use crate::deadline::Deadline;
+use crate::http::Transport;
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::OpenAIClientResource;
+
+const ASSISTANTS_BETA: &str = "assistants=v2";
+
+// The API attaches at most this many vector stores to an assistant
+const MAX_VECTOR_STORES: usize = 1;
+
+#[derive(NifMap, Default)]
+struct TokenUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+}
+
+#[derive(NifMap)]
+struct StepToolCall {
+    id: Option<String>,
+    // function, code_interpreter or file_search
+    tool_type: String,
+    // Function name; nil for built-in tools
+    name: Option<String>,
+    // Function arguments, or the code_interpreter input
+    arguments: Option<String>,
+    output: Option<String>,
+}
+
+#[derive(NifMap)]
+struct RunStep {
+    id: String,
+    // message_creation or tool_calls
+    step_type: String,
+    status: String,
+    created_at: Option<i64>,
+    completed_at: Option<i64>,
+    // Message created by a message_creation step
+    message_id: Option<String>,
+    tool_calls: Vec<StepToolCall>,
+    usage: Option<TokenUsage>,
+    last_error: Option<String>,
+}
+
+#[derive(NifMap)]
+struct RunUsage {
+    id: String,
+    status: String,
+    model: Option<String>,
+    // nil until the run reaches a terminal state
+    usage: Option<TokenUsage>,
+}
+
+#[derive(NifMap)]
+struct Assistant {
+    id: String,
+    model: Option<String>,
+    // Types of the assistant's tools, e.g. file_search
+    tools: Vec<String>,
+    vector_store_ids: Vec<String>,
+}
+
+#[derive(NifMap)]
+struct FileCitation {
+    file_id: String,
+    // Text in the message content that cites the file, and where it is
+    text: String,
+    start_index: u64,
+    end_index: u64,
+    // Quoted passage of the file, when the API reports one
+    quote: Option<String>,
+}
+
+#[derive(NifMap)]
+struct ThreadMessage {
+    id: String,
+    role: String,
+    // Run that created the message; nil for messages added to the thread directly
+    run_id: Option<String>,
+    // Text parts joined, which citation indices point into
+    content: String,
+    citations: Vec<FileCitation>,
+}
+
+fn string(value: &Value, key: &str) -> Option<String> {
+    value.get(key).and_then(Value::as_str).map(str::to_string)
+}
+
+fn decode_usage(value: &Value) -> Option<TokenUsage> {
+    let usage = value.get("usage").filter(|usage| usage.is_object())?;
+    let count = |key: &str| usage.get(key).and_then(Value::as_u64).unwrap_or(0);
+    Some(TokenUsage {
+        prompt_tokens: count("prompt_tokens"),
+        completion_tokens: count("completion_tokens"),
+        total_tokens: count("total_tokens"),
+    })
+}
+
+fn decode_tool_call(call: &Value) -> StepToolCall {
+    let tool_type = string(call, "type").unwrap_or_default();
+    let details = call.get(&tool_type).cloned().unwrap_or(Value::Null);
+    let (name, arguments, output) = match tool_type.as_str() {
+        "function" => (string(&details, "name"), string(&details, "arguments"), string(&details, "output")),
+        "code_interpreter" => (
+            None,
+            string(&details, "input"),
+            details
+                .get("outputs")
+                .and_then(Value::as_array)
+                .map(|outputs| outputs.iter().filter_map(|output| string(output, "logs")).collect::<Vec<_>>().join("\n")),
+        ),
+        // Built-in tools without a text payload keep their raw details
+        _ => (None, None, (!details.is_null()).then(|| details.to_string())),
+    };
+
+    StepToolCall { id: string(call, "id"), tool_type, name, arguments, output }
+}
+
+fn decode_step(step: &Value) -> RunStep {
+    let details = step.get("step_details").cloned().unwrap_or(Value::Null);
+    RunStep {
+        id: string(step, "id").unwrap_or_default(),
+        step_type: string(step, "type").unwrap_or_default(),
+        status: string(step, "status").unwrap_or_default(),
+        created_at: step.get("created_at").and_then(Value::as_i64),
+        completed_at: step.get("completed_at").and_then(Value::as_i64),
+        message_id: details.get("message_creation").and_then(|creation| string(creation, "message_id")),
+        tool_calls: details
+            .get("tool_calls")
+            .and_then(Value::as_array)
+            .map(|calls| calls.iter().map(decode_tool_call).collect())
+            .unwrap_or_default(),
+        usage: decode_usage(step),
+        last_error: step.get("last_error").and_then(|error| string(error, "message")),
+    }
+}
+
+// `:auto`, or a number to keep only that many of the most recent messages
+fn decode_truncation(opts: &Opts) -> NifResult<Option<Value>> {
+    match opts.get("truncation_strategy") {
+        Some(term) if term.is_atom() => match options::get_atom(opts, "truncation_strategy")?.as_deref() {
+            None => Ok(None),
+            Some("auto") => Ok(Some(json!({"type": "auto"}))),
+            Some(other) => Err(options::invalid(
+                &options::path("truncation_strategy"),
+                format!("must be :auto or a number of messages, got :{}", other),
+            )),
+        },
+        _ => Ok(options::get_usize(opts, "truncation_strategy")?
+            .map(|count| json!({"type": "last_messages", "last_messages": count}))),
+    }
+}
+
+// `:none`, `:auto`, `:required`, a built-in tool, or the name of a function to call
+fn decode_tool_choice(opts: &Opts) -> NifResult<Option<Value>> {
+    match opts.get("tool_choice") {
+        Some(term) if term.is_atom() => match options::get_atom(opts, "tool_choice")?.as_deref() {
+            None => Ok(None),
+            Some(mode @ ("none" | "auto" | "required")) => Ok(Some(json!(mode))),
+            Some(tool @ ("file_search" | "code_interpreter")) => Ok(Some(json!({"type": tool}))),
+            Some(other) => Err(options::invalid(
+                &options::path("tool_choice"),
+                format!("must be :none, :auto, :required, a built-in tool or a function name, got :{}", other),
+            )),
+        },
+        _ => Ok(options::get_string(opts, "tool_choice")?
+            .map(|name| json!({"type": "function", "function": {"name": name}}))),
+    }
+}
+
+// `file_search` settings: how many chunks to retrieve, and how to rank them
+fn decode_file_search(opts: &Opts) -> NifResult<Option<Value>> {
+    let Some(term) = options::get_in::<Term>(opts, "opts", "file_search", "keyword list")? else {
+        return Ok(None);
+    };
+    let path = options::path("file_search");
+    let settings: Opts = options::entries(term, &path)?.into_iter().collect();
+
+    let mut file_search = Map::new();
+    if let Some(count) = options::get_in::<usize>(&settings, &path, "max_num_results", "non-negative integer")? {
+        if !(1..=50).contains(&count) {
+            return Err(options::invalid(&format!("{}.max_num_results", path), format!("must be between 1 and 50, got {}", count)));
+        }
+        file_search.insert("max_num_results".to_string(), json!(count));
+    }
+    let mut ranking = Map::new();
+    // `:auto`, or a ranker version such as "default_2024_08_21"
+    if let Some(ranker) = options::get_in::<Term>(&settings, &path, "ranker", "atom or string")? {
+        let ranker = match ranker.is_atom() {
+            true => ranker.atom_to_string()?,
+            false => options::decode_at::<String>(ranker, &format!("{}.ranker", path), "atom or string")?,
+        };
+        ranking.insert("ranker".to_string(), json!(ranker));
+    }
+    if let Some(threshold) = options::get_f32_in(&settings, &path, "score_threshold")? {
+        if !(0.0..=1.0).contains(&threshold) {
+            return Err(options::invalid(&format!("{}.score_threshold", path), format!("must be between 0 and 1, got {}", threshold)));
+        }
+        ranking.insert("score_threshold".to_string(), json!(threshold));
+    }
+    if !ranking.is_empty() {
+        file_search.insert("ranking_options".to_string(), Value::Object(ranking));
+    }
+    Ok(Some(Value::Object(file_search)))
+}
+
+// Built-in tools, `:file_search` and `:code_interpreter`, with the `file_search`
+// settings on its tool. Settings alone enable file_search.
+fn decode_tools(opts: &Opts) -> NifResult<Option<Vec<Value>>> {
+    let file_search = decode_file_search(opts)?;
+    let Some(tools) = options::get_in::<Vec<Term>>(opts, "opts", "tools", "list of atoms")? else {
+        return Ok(file_search.map(|settings| vec![json!({"type": "file_search", "file_search": settings})]));
+    };
+
+    let mut decoded = Vec::new();
+    let mut searches = false;
+    for (index, tool) in tools.into_iter().enumerate() {
+        let path = format!("opts.tools[{}]", index);
+        if !tool.is_atom() {
+            return Err(options::type_error(&path, "atom", tool));
+        }
+        match tool.atom_to_string()?.as_str() {
+            "file_search" => {
+                searches = true;
+                match &file_search {
+                    Some(settings) => decoded.push(json!({"type": "file_search", "file_search": settings})),
+                    None => decoded.push(json!({"type": "file_search"})),
+                }
+            },
+            "code_interpreter" => decoded.push(json!({"type": "code_interpreter"})),
+            other => return Err(options::invalid(&path, format!("must be :file_search or :code_interpreter, got :{}", other))),
+        }
+    }
+    if file_search.is_some() && !searches {
+        return Err(options::invalid(&options::path("file_search"), "requires :file_search in opts.tools"));
+    }
+    Ok(Some(decoded))
+}
+
+fn transport(client_resource: &OpenAIClientResource, opts: &Opts) -> NifResult<Transport> {
+    Ok(client_resource
+        .transport_for(Endpoint::Other, opts)?
+        .with_deadline(Deadline::decode(opts)?)
+        .with_beta(ASSISTANTS_BETA))
+}
+
+fn decode_assistant(assistant: &Value) -> Assistant {
+    Assistant {
+        id: string(assistant, "id").unwrap_or_default(),
+        model: string(assistant, "model"),
+        tools: assistant
+            .get("tools")
+            .and_then(Value::as_array)
+            .map(|tools| tools.iter().filter_map(|tool| string(tool, "type")).collect())
+            .unwrap_or_default(),
+        vector_store_ids: assistant
+            .pointer("/tool_resources/file_search/vector_store_ids")
+            .and_then(Value::as_array)
+            .map(|ids| ids.iter().filter_map(Value::as_str).map(str::to_string).collect())
+            .unwrap_or_default(),
+    }
+}
+
+// Creates an assistant, with vector stores attached for its file_search tool
+#[rustler::nif(schedule = "DirtyIo")]
+fn create_assistant(client_resource: ResourceArc<OpenAIClientResource>, model: String, opts: Opts) -> NifResult<Assistant> {
+    let transport = transport(&client_resource, &opts)?;
+
+    let mut body = Map::new();
+    body.insert("model".to_string(), json!(model));
+    for key in ["name", "description", "instructions"] {
+        if let Some(value) = options::get_string(&opts, key)? {
+            body.insert(key.to_string(), json!(value));
+        }
+    }
+    let tools = decode_tools(&opts)?.unwrap_or_default();
+    if let Some(ids) = options::get_strings(&opts, "vector_store_ids")? {
+        if ids.len() > MAX_VECTOR_STORES {
+            return Err(options::invalid(
+                &options::path("vector_store_ids"),
+                format!("may hold at most {} vector store, got {}", MAX_VECTOR_STORES, ids.len()),
+            ));
+        }
+        if !tools.iter().any(|tool| tool["type"] == "file_search") {
+            return Err(options::invalid(&options::path("vector_store_ids"), "requires :file_search in opts.tools"));
+        }
+        body.insert("tool_resources".to_string(), json!({"file_search": {"vector_store_ids": ids}}));
+    }
+    body.insert("tools".to_string(), json!(tools));
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let assistant: Value = runtime
+        .block_on(transport.post_json("/assistants", &Value::Object(body)))
+        .map_err(|e| e.context("Failed to create assistant"))?
+        .json()
+        .map_err(|e| Error::Term(Box::new(e)))?;
+
+    Ok(decode_assistant(&assistant))
+}
+
+// Starts a run of `assistant_id` on a thread. The truncation strategy and token
+// limits bound how much of a long thread each run reads and writes.
+#[rustler::nif(schedule = "DirtyIo")]
+fn create_run(client_resource: ResourceArc<OpenAIClientResource>, thread_id: String, assistant_id: String, opts: Opts) -> NifResult<RunUsage> {
+    let transport = transport(&client_resource, &opts)?;
+
+    let mut body = Map::new();
+    body.insert("assistant_id".to_string(), json!(assistant_id));
+    for key in ["model", "instructions", "additional_instructions"] {
+        if let Some(value) = options::get_string(&opts, key)? {
+            body.insert(key.to_string(), json!(value));
+        }
+    }
+    for key in ["max_prompt_tokens", "max_completion_tokens"] {
+        if let Some(value) = options::get_usize(&opts, key)? {
+            body.insert(key.to_string(), json!(value));
+        }
+    }
+    if let Some(truncation) = decode_truncation(&opts)? {
+        body.insert("truncation_strategy".to_string(), truncation);
+    }
+    if let Some(tool_choice) = decode_tool_choice(&opts)? {
+        body.insert("tool_choice".to_string(), tool_choice);
+    }
+    if let Some(tools) = decode_tools(&opts)? {
+        body.insert("tools".to_string(), json!(tools));
+    }
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let run: Value = runtime
+        .block_on(transport.post_json(&format!("/threads/{}/runs", thread_id), &Value::Object(body)))
+        .map_err(|e| e.context("Failed to create run"))?
+        .json()
+        .map_err(|e| Error::Term(Box::new(e)))?;
+
+    Ok(RunUsage {
+        id: string(&run, "id").unwrap_or_default(),
+        status: string(&run, "status").unwrap_or_default(),
+        model: string(&run, "model"),
+        usage: decode_usage(&run),
+    })
+}
+
+// Every step of a run, oldest first, following pagination to the end
+#[rustler::nif(schedule = "DirtyIo")]
+fn list_run_steps(client_resource: ResourceArc<OpenAIClientResource>, thread_id: String, run_id: String, opts: Opts) -> NifResult<Vec<RunStep>> {
+    let transport = transport(&client_resource, &opts)?;
+    let page_size = options::get_usize(&opts, "page_size")?.unwrap_or(100).clamp(1, 100);
+    let path = format!("/threads/{}/runs/{}/steps", thread_id, run_id);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let mut steps = Vec::new();
+    let mut after: Option<String> = None;
+    loop {
+        let mut query = vec![("order", "asc".to_string()), ("limit", page_size.to_string())];
+        if let Some(cursor) = &after {
+            query.push(("after", cursor.clone()));
+        }
+
+        let page: Value = runtime
+            .block_on(transport.get(&path, &query))
+            .map_err(|e| e.context("Failed to list run steps"))?
+            .json()
+            .map_err(|e| Error::Term(Box::new(e)))?;
+
+        let data = page.get("data").and_then(Value::as_array).cloned().unwrap_or_default();
+        steps.extend(data.iter().map(decode_step));
+
+        after = string(&page, "last_id");
+        let has_more = page.get("has_more").and_then(Value::as_bool).unwrap_or(false);
+        if !has_more || data.is_empty() || after.is_none() {
+            break;
+        }
+    }
+
+    Ok(steps)
+}
+
+// Status and token usage of a run, for costing assistant executions
+#[rustler::nif(schedule = "DirtyIo")]
+fn get_run_usage(client_resource: ResourceArc<OpenAIClientResource>, thread_id: String, run_id: String, opts: Opts) -> NifResult<RunUsage> {
+    let transport = transport(&client_resource, &opts)?;
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let run: Value = runtime
+        .block_on(transport.get(&format!("/threads/{}/runs/{}", thread_id, run_id), &[]))
+        .map_err(|e| e.context("Failed to retrieve run"))?
+        .json()
+        .map_err(|e| Error::Term(Box::new(e)))?;
+
+    Ok(RunUsage {
+        id: string(&run, "id").unwrap_or(run_id),
+        status: string(&run, "status").unwrap_or_default(),
+        model: string(&run, "model"),
+        usage: decode_usage(&run),
+    })
+}
+
+// A message's text parts joined, with the file citations of each moved to point
+// into the joined text
+fn decode_message(message: &Value) -> ThreadMessage {
+    let mut content = String::new();
+    let mut citations = Vec::new();
+    let parts = message.get("content").and_then(Value::as_array).cloned().unwrap_or_default();
+    for text in parts.iter().filter(|part| part["type"] == "text").filter_map(|part| part.get("text")) {
+        let offset = content.chars().count() as u64;
+        content.push_str(text.get("value").and_then(Value::as_str).unwrap_or_default());
+
+        let annotations = text.get("annotations").and_then(Value::as_array).cloned().unwrap_or_default();
+        for annotation in annotations.iter().filter(|annotation| annotation["type"] == "file_citation") {
+            let index = |key: &str| offset + annotation.get(key).and_then(Value::as_u64).unwrap_or(0);
+            let cited = annotation.get("file_citation").cloned().unwrap_or(Value::Null);
+            citations.push(FileCitation {
+                file_id: string(&cited, "file_id").unwrap_or_default(),
+                text: string(annotation, "text").unwrap_or_default(),
+                start_index: index("start_index"),
+                end_index: index("end_index"),
+                quote: string(&cited, "quote"),
+            });
+        }
+    }
+
+    ThreadMessage {
+        id: string(message, "id").unwrap_or_default(),
+        role: string(message, "role").unwrap_or_default(),
+        run_id: string(message, "run_id"),
+        content,
+        citations,
+    }
+}
+
+// Messages of a thread, oldest first, following pagination to the end. With
+// `run_id`, only the messages that run created.
+#[rustler::nif(schedule = "DirtyIo")]
+fn list_thread_messages(client_resource: ResourceArc<OpenAIClientResource>, thread_id: String, opts: Opts) -> NifResult<Vec<ThreadMessage>> {
+    let transport = transport(&client_resource, &opts)?;
+    let page_size = options::get_usize(&opts, "page_size")?.unwrap_or(100).clamp(1, 100);
+    let run_id = options::get_string(&opts, "run_id")?;
+    let path = format!("/threads/{}/messages", thread_id);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let mut messages = Vec::new();
+    let mut after: Option<String> = None;
+    loop {
+        let mut query = vec![("order", "asc".to_string()), ("limit", page_size.to_string())];
+        if let Some(run_id) = &run_id {
+            query.push(("run_id", run_id.clone()));
+        }
+        if let Some(cursor) = &after {
+            query.push(("after", cursor.clone()));
+        }
+
+        let page: Value = runtime
+            .block_on(transport.get(&path, &query))
+            .map_err(|e| e.context("Failed to list messages"))?
+            .json()
+            .map_err(|e| Error::Term(Box::new(e)))?;
+
+        let data = page.get("data").and_then(Value::as_array).cloned().unwrap_or_default();
+        messages.extend(data.iter().map(decode_message));
+
+        after = string(&page, "last_id");
+        let has_more = page.get("has_more").and_then(Value::as_bool).unwrap_or(false);
+        if !has_more || data.is_empty() || after.is_none() {
+            break;
+        }
+    }
+
+    Ok(messages)
+}
@@ -0,0 +1,154 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustler::{Error, NifResult, ResourceArc};
+
+use crate::options::{self, Opts};
+use crate::{atoms, OpenAIClientResource};
+
+// Labels generated text and audio with a marker rendered from the client's template,
+// for organizations that must mark AI-generated content the same way everywhere.
+// `{model}` and `{timestamp}`, in UTC ISO 8601, are filled in for each response.
+#[derive(Clone)]
+pub struct Attribution {
+    template: String,
+    // Whether text content gets the marker appended, or only returned alongside it
+    append_text: bool,
+}
+
+impl Attribution {
+    // None without a `template`
+    fn decode(opts: &Opts) -> NifResult<Option<Self>> {
+        let Some(template) = options::get_string(opts, "template")? else {
+            return Ok(None);
+        };
+        if template.trim().is_empty() {
+            return Err(options::invalid(&options::path("template"), "must not be empty"));
+        }
+        let append_text = match options::get_atom(opts, "text")?.as_deref() {
+            None | Some("append") => true,
+            Some("none") => false,
+            Some(other) => return Err(options::invalid(&options::path("text"), format!("must be :append or :none, got :{}", other))),
+        };
+        Ok(Some(Attribution { template, append_text }))
+    }
+
+    pub fn marker(&self, model: &str) -> String {
+        self.template.replace("{model}", model).replace("{timestamp}", &utc_timestamp())
+    }
+
+    // The text to add after content that isn't empty, if any
+    pub fn text_suffix(&self, content: &str, marker: &str) -> Option<String> {
+        (self.append_text && !content.trim().is_empty()).then(|| format!("\n\n{}", marker))
+    }
+
+    // Embeds the marker as a comment in MP3 and WAV audio. Other formats have no
+    // metadata to put it in and are returned as they are.
+    pub fn tag_audio(&self, audio: Vec<u8>, format: &str, model: &str) -> Vec<u8> {
+        let marker = self.marker(model);
+        match format {
+            "mp3" => id3_comment(audio, &marker),
+            "wav" => wav_comment(audio, &marker),
+            _ => audio,
+        }
+    }
+}
+
+fn utc_timestamp() -> String {
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, time) = ((seconds / 86_400) as i64, seconds % 86_400);
+
+    // The civil date of a day count since 1970-01-01, after Howard Hinnant's algorithm
+    let shifted = days + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time % 3600 / 60, time % 60)
+}
+
+fn syncsafe(size: usize) -> [u8; 4] {
+    [(size >> 21) as u8 & 0x7F, (size >> 14) as u8 & 0x7F, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F]
+}
+
+// Prepends an ID3v2.4 tag with a UTF-8 COMM frame. A tag the audio already has is kept
+// after it; players and `probe_duration` skip consecutive tags.
+fn id3_comment(audio: Vec<u8>, comment: &str) -> Vec<u8> {
+    // Encoding 3 is UTF-8, followed by the language and an empty description
+    let mut body = vec![3];
+    body.extend_from_slice(b"eng\0");
+    body.extend_from_slice(comment.as_bytes());
+
+    let frame_len = 10 + body.len();
+    let mut tagged = Vec::with_capacity(10 + frame_len + audio.len());
+    tagged.extend_from_slice(b"ID3\x04\x00\x00");
+    tagged.extend_from_slice(&syncsafe(frame_len));
+    tagged.extend_from_slice(b"COMM");
+    tagged.extend_from_slice(&syncsafe(body.len()));
+    tagged.extend_from_slice(&[0, 0]);
+    tagged.extend_from_slice(&body);
+    tagged.extend_from_slice(&audio);
+    tagged
+}
+
+// Inserts a LIST/INFO chunk with an ICMT comment before the data chunk, where readers
+// that stop at the data of a streamed WAV still find it
+fn wav_comment(mut audio: Vec<u8>, comment: &str) -> Vec<u8> {
+    if audio.len() < 12 || &audio[0..4] != b"RIFF" || &audio[8..12] != b"WAVE" {
+        return audio;
+    }
+    let size_at = |at: usize| u32::from_le_bytes([audio[at], audio[at + 1], audio[at + 2], audio[at + 3]]) as usize;
+    let mut at = 12;
+    while at + 8 <= audio.len() && &audio[at..at + 4] != b"data" {
+        let size = size_at(at + 4);
+        at = at.saturating_add(8 + size + (size & 1));
+    }
+    if at + 8 > audio.len() {
+        return audio;
+    }
+
+    // The comment is NUL-terminated, and chunks are padded to an even size
+    let text_len = comment.len() + 1;
+    let mut list = Vec::with_capacity(20 + text_len + 1);
+    list.extend_from_slice(b"LIST");
+    list.extend_from_slice(&((12 + text_len + (text_len & 1)) as u32).to_le_bytes());
+    list.extend_from_slice(b"INFOICMT");
+    list.extend_from_slice(&(text_len as u32).to_le_bytes());
+    list.extend_from_slice(comment.as_bytes());
+    list.push(0);
+    if text_len & 1 == 1 {
+        list.push(0);
+    }
+
+    // Streamed WAVs leave the RIFF size at its maximum
+    let riff_size = size_at(4);
+    if riff_size != u32::MAX as usize {
+        audio[4..8].copy_from_slice(&((riff_size + list.len()) as u32).to_le_bytes());
+    }
+    audio.splice(at..at, list);
+    audio
+}
+
+impl OpenAIClientResource {
+    pub fn attribution(&self) -> NifResult<Option<Attribution>> {
+        match self.attribution.lock() {
+            Ok(attribution) => Ok(attribution.clone()),
+            Err(e) => Err(Error::Term(Box::new(format!("Failed to lock attribution: {}", e)))),
+        }
+    }
+}
+
+// Sets the client's attribution from its `template` and `text` options, or clears it
+// when there is no template
+#[rustler::nif]
+fn set_attribution(client_resource: ResourceArc<OpenAIClientResource>, opts: Opts) -> NifResult<rustler::Atom> {
+    let decoded = Attribution::decode(&opts)?;
+    let mut current = client_resource.attribution.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock attribution: {}", e))))?;
+    *current = decoded;
+    Ok(atoms::ok())
+}
@@ -0,0 +1,386 @@
+use rustler::{Binary, Env, Error, NifResult, OwnedBinary};
+
+use crate::options::{self, Opts};
+
+// OpenAI's `pcm` speech format: 24kHz mono signed 16-bit little-endian
+const DEFAULT_PCM_SAMPLE_RATE: u32 = 24_000;
+const DEFAULT_PCM_CHANNELS: u16 = 1;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+struct PcmFormat {
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+}
+
+impl PcmFormat {
+    fn frame_size(&self) -> usize {
+        self.channels as usize * (self.bits_per_sample as usize / 8)
+    }
+}
+
+// Decoded audio: interleaved samples plus whether they came from a WAV container
+struct Audio<'a> {
+    format: PcmFormat,
+    data: &'a [u8],
+    wav: bool,
+}
+
+fn error(message: String) -> Error {
+    Error::Term(Box::new(message))
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+// Find the fmt and data chunks of a RIFF/WAVE file
+fn parse_wav(bytes: &[u8]) -> Result<(PcmFormat, &[u8]), String> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err("not a WAV file".to_string());
+    }
+
+    let mut format = None;
+    let mut at = 12;
+    while at + 8 <= bytes.len() {
+        let id = &bytes[at..at + 4];
+        let size = u32_at(bytes, at + 4) as usize;
+        let body = at + 8;
+        // Streamed WAVs (including OpenAI's) leave the size at its maximum
+        let end = body.saturating_add(size).min(bytes.len());
+
+        match id {
+            b"fmt " if end - body >= 16 => {
+                let encoding = u16_at(bytes, body);
+                // 1 is integer PCM, 0xFFFE is WAVE_FORMAT_EXTENSIBLE
+                if encoding != 1 && encoding != 0xFFFE {
+                    return Err(format!("unsupported WAV encoding {}", encoding));
+                }
+                format = Some(PcmFormat {
+                    channels: u16_at(bytes, body + 2),
+                    sample_rate: u32_at(bytes, body + 4),
+                    bits_per_sample: u16_at(bytes, body + 14),
+                });
+            },
+            b"data" => {
+                let format = format.ok_or("WAV data chunk comes before fmt chunk")?;
+                return Ok((format, &bytes[body..end]));
+            },
+            _ => {},
+        }
+
+        // Chunks are padded to an even size
+        at = end + (size & 1);
+    }
+
+    Err("WAV file has no data chunk".to_string())
+}
+
+// `format: :pcm` reads raw samples described by `sample_rate` and `channels`;
+// otherwise the input must be a 16-bit PCM WAV file
+fn decode_audio<'a>(bytes: &'a [u8], opts: &Opts) -> NifResult<Audio<'a>> {
+    let audio = match options::get_atom(opts, "format")?.as_deref() {
+        None | Some("wav") => {
+            let (format, data) = parse_wav(bytes).map_err(|e| error(format!("Invalid audio: {}", e)))?;
+            Audio { format, data, wav: true }
+        },
+        Some("pcm") => Audio {
+            format: PcmFormat {
+                channels: match options::get_usize(opts, "channels")? {
+                    Some(channels) => u16::try_from(channels).map_err(|_| {
+                        options::invalid(&options::path("channels"), format!("must be at most {}, got {}", u16::MAX, channels))
+                    })?,
+                    None => DEFAULT_PCM_CHANNELS,
+                },
+                sample_rate: match options::get_usize(opts, "sample_rate")? {
+                    Some(rate) => u32::try_from(rate).map_err(|_| {
+                        options::invalid(&options::path("sample_rate"), format!("must be at most {}, got {}", u32::MAX, rate))
+                    })?,
+                    None => DEFAULT_PCM_SAMPLE_RATE,
+                },
+                bits_per_sample: 16,
+            },
+            data: bytes,
+            wav: false,
+        },
+        Some(other) => return Err(options::invalid(&options::path("format"), format!("must be :wav or :pcm, got :{}", other))),
+    };
+
+    if audio.format.bits_per_sample != 16 || audio.format.channels == 0 {
+        return Err(error(format!(
+            "Unsupported audio: {} channel(s) of {}-bit samples, only 16-bit PCM is supported",
+            audio.format.channels, audio.format.bits_per_sample
+        )));
+    }
+    Ok(audio)
+}
+
+// Sizes in a WAV header are 32-bit, which caps the data at just under 4 GiB
+fn wav_header(format: PcmFormat, data_len: usize) -> NifResult<Vec<u8>> {
+    let too_large = || error(format!("Audio is too large for a WAV file: {} bytes of samples", data_len));
+    let data_size = u32::try_from(data_len).ok().filter(|size| *size <= u32::MAX - 36).ok_or_else(too_large)?;
+    let block_align = u16::try_from(format.frame_size())
+        .map_err(|_| error(format!("Audio has too many channels for a WAV file: {}", format.channels)))?;
+    let byte_rate = format
+        .sample_rate
+        .checked_mul(block_align as u32)
+        .ok_or_else(|| error(format!("Audio sample rate is too high for a WAV file: {} Hz", format.sample_rate)))?;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + data_size).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&format.channels.to_le_bytes());
+    header.extend_from_slice(&format.sample_rate.to_le_bytes());
+    header.extend_from_slice(&byte_rate.to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&format.bits_per_sample.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_size.to_le_bytes());
+    Ok(header)
+}
+
+// Re-wrap samples in the container they came in
+fn encode_audio<'a>(env: Env<'a>, format: PcmFormat, wav: bool, parts: &[&[u8]]) -> NifResult<Binary<'a>> {
+    let data_len: usize = parts.iter().map(|part| part.len()).sum();
+    let header = if wav { wav_header(format, data_len)? } else { Vec::new() };
+
+    let mut binary = OwnedBinary::new(header.len() + data_len).ok_or_else(|| error("Failed to allocate audio binary".to_string()))?;
+    let out = binary.as_mut_slice();
+    out[..header.len()].copy_from_slice(&header);
+    let mut at = header.len();
+    for part in parts {
+        out[at..at + part.len()].copy_from_slice(part);
+        at += part.len();
+    }
+    Ok(binary.release(env))
+}
+
+// Duration of WAV or MP3 audio in seconds, the unit Whisper bills by. None for
+// other formats, which would need a demuxer to probe.
+pub fn probe_duration(bytes: &[u8]) -> Option<f64> {
+    if let Ok((format, data)) = parse_wav(bytes) {
+        let bytes_per_second = format.frame_size() * format.sample_rate as usize;
+        return (bytes_per_second > 0).then(|| data.len() as f64 / bytes_per_second as f64);
+    }
+    mp3_duration(bytes)
+}
+
+// File extension for the container the bytes start with, so uploads are named
+// for their format. None when the magic bytes aren't recognised.
+pub fn sniff_extension(bytes: &[u8]) -> Option<&'static str> {
+    match bytes {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("wav"),
+        [b'I', b'D', b'3', ..] => Some("mp3"),
+        [0xFF, second, ..] if second & 0xE0 == 0xE0 => Some("mp3"),
+        [b'O', b'g', b'g', b'S', ..] => Some("ogg"),
+        [b'f', b'L', b'a', b'C', ..] => Some("flac"),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => Some("webm"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'M', b'4', b'A', ..] => Some("m4a"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("mp4"),
+        _ => None,
+    }
+}
+
+// The frames of a 16-bit PCM WAV file, read in place and mixed down to mono on demand
+pub struct MonoPcm<'a> {
+    pub sample_rate: u32,
+    channels: u16,
+    data: &'a [u8],
+}
+
+impl<'a> MonoPcm<'a> {
+    pub fn frames(&self) -> usize {
+        self.data.len() / (self.channels as usize * 2)
+    }
+
+    // Frames `start..end` mixed to mono and scaled to -1.0..1.0
+    pub fn samples(&self, start: usize, end: usize) -> impl Iterator<Item = f32> + 'a {
+        let frame_size = self.channels as usize * 2;
+        let channels = self.channels as f32;
+        self.data[start * frame_size..end * frame_size].chunks_exact(frame_size).map(move |frame| {
+            let sum: f32 = frame
+                .chunks_exact(2)
+                .map(|sample| i16::from_le_bytes([sample[0], sample[1]]) as f32 / i16::MAX as f32)
+                .sum();
+            sum / channels
+        })
+    }
+}
+
+// None for anything but 16-bit PCM WAV, whose samples would need a decoder
+pub fn mono_pcm(bytes: &[u8]) -> Option<MonoPcm<'_>> {
+    let (format, data) = parse_wav(bytes).ok()?;
+    if format.bits_per_sample != 16 || format.channels == 0 || format.sample_rate == 0 {
+        return None;
+    }
+    Some(MonoPcm { sample_rate: format.sample_rate, channels: format.channels, data })
+}
+
+// Bitrates in kbps by bitrate index, for MPEG-1 layers I-III and MPEG-2/2.5 layers I and II/III
+const MP3_BITRATES: [[u32; 14]; 5] = [
+    [32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448],
+    [32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384],
+    [32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320],
+    [32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256],
+    [8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+// Bytes a frame walk may leave unread at the end, for ID3v1/APE tags and a cut-off frame
+const MP3_TRAILER: usize = 2048;
+
+// Sums the duration of every MPEG audio frame, which is exact for variable bitrate
+// files too. Returns None unless frames run from the start of the audio to its end.
+fn mp3_duration(bytes: &[u8]) -> Option<f64> {
+    let mut at = 0;
+    // Attribution puts its tag before any the audio already had
+    while at + 10 <= bytes.len() && &bytes[at..at + 3] == b"ID3" {
+        let size = bytes[at + 6..at + 10].iter().fold(0usize, |size, byte| (size << 7) | (*byte & 0x7F) as usize);
+        let footer = if bytes[at + 5] & 0x10 != 0 { 10 } else { 0 };
+        at += 10 + size + footer;
+    }
+
+    let mut seconds = 0.0;
+    let mut frames = 0;
+    while at + 4 <= bytes.len() {
+        let header = u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]]);
+        if header & 0xFFE0_0000 != 0xFFE0_0000 {
+            break;
+        }
+        let version = (header >> 19) & 3;
+        let layer = (header >> 17) & 3;
+        let bitrate_index = ((header >> 12) & 0xF) as usize;
+        let rate_index = ((header >> 10) & 3) as usize;
+        if version == 1 || layer == 0 || bitrate_index == 0 || bitrate_index == 15 || rate_index == 3 {
+            break;
+        }
+
+        let mpeg1 = version == 3;
+        let sample_rate = [44_100, 48_000, 32_000][rate_index] >> if mpeg1 { 0 } else if version == 2 { 1 } else { 2 };
+        let table = match (mpeg1, layer) {
+            (true, 3) => 0,
+            (true, 2) => 1,
+            (true, _) => 2,
+            (false, 3) => 3,
+            (false, _) => 4,
+        };
+        let bitrate = MP3_BITRATES[table][bitrate_index - 1] * 1000;
+        let padding = ((header >> 9) & 1) as usize;
+        let (samples, length) = match layer {
+            3 => (384, (12 * bitrate / sample_rate) as usize * 4 + padding * 4),
+            1 if !mpeg1 => (576, (72 * bitrate / sample_rate) as usize + padding),
+            _ => (1152, (144 * bitrate / sample_rate) as usize + padding),
+        };
+
+        seconds += samples as f64 / sample_rate as f64;
+        frames += 1;
+        at += length;
+    }
+
+    (frames > 0 && bytes.len().saturating_sub(at) <= MP3_TRAILER).then_some(seconds)
+}
+
+// Joins segments that share a sample rate and channel count into one recording
+#[rustler::nif(schedule = "DirtyCpu")]
+fn concat_audio<'a>(env: Env<'a>, segments: Vec<Binary<'a>>, opts: Opts<'a>) -> NifResult<Binary<'a>> {
+    let decoded = segments
+        .iter()
+        .map(|segment| decode_audio(segment.as_slice(), &opts))
+        .collect::<NifResult<Vec<_>>>()?;
+
+    let first = decoded.first().ok_or_else(|| error("No audio segments given".to_string()))?;
+    if let Some((index, other)) = decoded.iter().enumerate().find(|(_, audio)| audio.format != first.format) {
+        return Err(error(format!(
+            "Audio segment {} is {} Hz with {} channel(s), expected {} Hz with {} channel(s)",
+            index, other.format.sample_rate, other.format.channels, first.format.sample_rate, first.format.channels
+        )));
+    }
+
+    let parts: Vec<&[u8]> = decoded.iter().map(|audio| audio.data).collect();
+    encode_audio(env, first.format, first.wav, &parts)
+}
+
+// Drops leading and trailing frames whose loudest sample stays below `threshold`
+// (a fraction of full scale), keeping `padding_ms` of quiet around the sound
+#[rustler::nif(schedule = "DirtyCpu")]
+fn trim_silence<'a>(env: Env<'a>, audio: Binary<'a>, opts: Opts<'a>) -> NifResult<Binary<'a>> {
+    let decoded = decode_audio(audio.as_slice(), &opts)?;
+    let threshold = options::get_f32(&opts, "threshold")?.unwrap_or(0.01).clamp(0.0, 1.0);
+    let padding_ms = options::get_usize(&opts, "padding_ms")?.unwrap_or(0);
+
+    let frame_size = decoded.format.frame_size();
+    let limit = (threshold * i16::MAX as f32) as i32;
+    let frames: Vec<&[u8]> = decoded.data.chunks_exact(frame_size).collect();
+    let loud = |frame: &&[u8]| {
+        frame
+            .chunks_exact(2)
+            .any(|sample| (i16::from_le_bytes([sample[0], sample[1]]) as i32).abs() > limit)
+    };
+
+    let padding = padding_ms * decoded.format.sample_rate as usize / 1000;
+    let (start, end) = match (frames.iter().position(loud), frames.iter().rposition(loud)) {
+        (Some(first), Some(last)) => (first.saturating_sub(padding), (last + 1 + padding).min(frames.len())),
+        // All silence
+        _ => (0, 0),
+    };
+
+    let trimmed = &decoded.data[start * frame_size..end * frame_size];
+    encode_audio(env, decoded.format, decoded.wav, &[trimmed])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MPEG-1 Layer III at 128 kbps and 44.1 kHz: 417 bytes and 1152 samples a frame
+    fn mp3_frames(count: usize) -> Vec<u8> {
+        let mut frame = vec![0; 417];
+        frame[..4].copy_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        frame.repeat(count)
+    }
+
+    fn id3_tag(size: u8) -> Vec<u8> {
+        let mut tag = b"ID3\x04\x00\x00\x00\x00\x00".to_vec();
+        tag.push(size);
+        tag.extend(vec![0; size as usize]);
+        tag
+    }
+
+    #[test]
+    fn sums_mp3_frames_after_id3_tags() {
+        let expected = 10.0 * 1152.0 / 44_100.0;
+        assert_eq!(mp3_duration(&mp3_frames(10)), Some(expected));
+
+        let tagged = [id3_tag(20), id3_tag(5), mp3_frames(10), vec![0; 128]].concat();
+        assert_eq!(mp3_duration(&tagged), Some(expected));
+    }
+
+    #[test]
+    fn rejects_mp3_frames_that_stop_early() {
+        assert_eq!(mp3_duration(&[mp3_frames(2), vec![0; MP3_TRAILER + 1]].concat()), None);
+        assert_eq!(mp3_duration(b"not audio at all"), None);
+    }
+
+    #[test]
+    fn refuses_wav_sizes_that_overflow_the_header() {
+        let format = PcmFormat { channels: 1, sample_rate: 24_000, bits_per_sample: 16 };
+        assert_eq!(wav_header(format, 1_000).unwrap()[4..8], 1_036u32.to_le_bytes());
+        assert!(wav_header(format, u32::MAX as usize - 35).is_err());
+        assert!(wav_header(PcmFormat { sample_rate: u32::MAX, ..format }, 0).is_err());
+    }
+
+    #[test]
+    fn probes_wav_and_mp3_durations() {
+        let format = PcmFormat { channels: 2, sample_rate: 8_000, bits_per_sample: 16 };
+        let wav = [wav_header(format, 48_000).unwrap(), vec![0; 48_000]].concat();
+        assert_eq!(probe_duration(&wav), Some(1.5));
+        let seconds = probe_duration(&mp3_frames(441)).unwrap();
+        assert!((seconds - 11.52).abs() < 1e-9, "{}", seconds);
+        assert_eq!(probe_duration(&[0x1A, 0x45, 0xDF, 0xA3, 0, 0]), None);
+    }
+}
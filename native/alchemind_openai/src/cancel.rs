@@ -0,0 +1,73 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rustler::{NifResult, ResourceArc};
+use tokio::sync::Notify;
+
+use crate::atoms;
+use crate::options::{self, Opts};
+
+// Cancels batch operations from another process. A batch NIF blocks its caller, so
+// the token is created beforehand and cancelled from elsewhere, e.g. a supervisor
+// shutting down. Cancelled batches return what already finished instead of nothing.
+#[derive(Default)]
+pub struct CancelToken {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl rustler::Resource for CancelToken {}
+
+impl CancelToken {
+    pub fn decode(opts: &Opts) -> NifResult<Option<ResourceArc<CancelToken>>> {
+        options::get_in(opts, "opts", "cancel", "cancel token")
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    async fn cancelled(&self) {
+        let mut notified = std::pin::pin!(self.notify.notified());
+        // Registered before checking the flag, so a cancel in between isn't missed
+        notified.as_mut().enable();
+        if !self.is_cancelled() {
+            notified.await;
+        }
+    }
+}
+
+// Runs `future` until it completes or `token` is cancelled, returning None in the
+// latter case. Work that hasn't started by then never starts.
+pub async fn run<F: Future>(token: Option<&CancelToken>, future: F) -> Option<F::Output> {
+    match token {
+        Some(token) if token.is_cancelled() => None,
+        Some(token) => tokio::select! {
+            output = future => Some(output),
+            _ = token.cancelled() => None,
+        },
+        None => Some(future.await),
+    }
+}
+
+// Runs `future` until it completes or either token is cancelled, as with `run`
+pub async fn run_either<F: Future>(first: Option<&CancelToken>, second: Option<&CancelToken>, future: F) -> Option<F::Output> {
+    run(first, run(second, future)).await.flatten()
+}
+
+#[rustler::nif]
+fn create_cancel_token() -> ResourceArc<CancelToken> {
+    ResourceArc::new(CancelToken::default())
+}
+
+// Idempotent; batches already finished are unaffected
+#[rustler::nif]
+fn cancel_token(token: ResourceArc<CancelToken>) -> rustler::Atom {
+    token.cancel();
+    atoms::ok()
+}
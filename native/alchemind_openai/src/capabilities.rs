@@ -0,0 +1,27 @@
+use rustler::NifMap;
+
+use crate::transcription::TRANSCRIPTION_FORMATS;
+use crate::voice::{SPEECH_FORMATS, SPEECH_MODELS, VOICES};
+
+#[derive(NifMap)]
+struct Capabilities {
+    voices: Vec<String>,
+    speech_models: Vec<String>,
+    speech_formats: Vec<String>,
+    transcription_formats: Vec<String>,
+}
+
+fn names<T>(table: &[(&str, T)]) -> Vec<String> {
+    table.iter().map(|(name, _)| name.to_string()).collect()
+}
+
+// The option values the speech and transcription NIFs map, read from the same tables
+#[rustler::nif]
+fn supported_capabilities() -> Capabilities {
+    Capabilities {
+        voices: names(VOICES),
+        speech_models: names(SPEECH_MODELS),
+        speech_formats: names(SPEECH_FORMATS),
+        transcription_formats: TRANSCRIPTION_FORMATS.iter().map(|name| name.to_string()).collect(),
+    }
+}
@@ -0,0 +1,303 @@
+use regex::Regex;
+use rustler::{NifMap, NifResult};
+use std::sync::OnceLock;
+use tiktoken_rs::CoreBPE;
+
+use crate::options::{self, Opts};
+use crate::tokens;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Strategy {
+    Fixed,
+    Sentence,
+    Paragraph,
+    Markdown,
+}
+
+pub struct ChunkOptions {
+    pub strategy: Strategy,
+    pub chunk_size: usize,
+    pub overlap: usize,
+    pub model: String,
+}
+
+#[derive(NifMap, Clone)]
+pub struct Chunk {
+    pub index: usize,
+    pub text: String,
+    // Byte offsets into the original text, usable with binary_part/3
+    pub start: usize,
+    pub end: usize,
+    pub token_count: usize,
+    // Heading path ("Guide > Install") for markdown chunks
+    pub heading: Option<String>,
+}
+
+// A contiguous range of the input that should not be split unless it is too large on its own
+struct Segment {
+    start: usize,
+    end: usize,
+    tokens: usize,
+    heading: Option<String>,
+}
+
+pub fn decode_options(opts: &Opts) -> NifResult<ChunkOptions> {
+    decode_options_with_defaults(opts, Strategy::Sentence, 512, 64)
+}
+
+pub fn decode_options_with_defaults(opts: &Opts, default_strategy: Strategy, default_size: usize, default_overlap: usize) -> NifResult<ChunkOptions> {
+    let strategy = match options::get_atom(opts, "strategy")?.as_deref() {
+        None => default_strategy,
+        Some("sentence") => Strategy::Sentence,
+        Some("fixed") => Strategy::Fixed,
+        Some("paragraph") => Strategy::Paragraph,
+        Some("markdown") => Strategy::Markdown,
+        Some(other) => {
+            return Err(options::invalid(
+                &options::path("strategy"),
+                format!("must be :sentence, :fixed, :paragraph or :markdown, got :{}", other),
+            ))
+        },
+    };
+    let chunk_size = options::get_usize(opts, "chunk_size")?.unwrap_or(default_size);
+    let overlap = options::get_usize(opts, "overlap")?.unwrap_or(default_overlap);
+    let model = options::get_string(opts, "model")?.unwrap_or_else(|| "text-embedding-3-small".to_string());
+
+    if chunk_size == 0 {
+        return Err(options::invalid(&options::path("chunk_size"), "must be greater than 0"));
+    }
+    if overlap >= chunk_size {
+        return Err(options::invalid(&options::path("overlap"), format!("must be smaller than chunk_size ({}), got {}", chunk_size, overlap)));
+    }
+
+    Ok(ChunkOptions { strategy, chunk_size, overlap, model })
+}
+
+fn floor_char_boundary(text: &str, mut index: usize) -> usize {
+    while index > 0 && !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+// Byte offset of every token boundary in `text`, starting with 0
+fn token_offsets(text: &str, bpe: &CoreBPE) -> Vec<usize> {
+    let mut offsets = vec![0];
+    let mut acc = 0;
+    for token in bpe.encode_ordinary(text) {
+        acc += bpe._decode_native(&[token]).len();
+        offsets.push(acc);
+    }
+    offsets
+}
+
+// Fixed-size token windows with overlap over text[start..end]
+fn split_fixed(text: &str, start: usize, end: usize, options: &ChunkOptions, bpe: &CoreBPE) -> Vec<(usize, usize)> {
+    let offsets = token_offsets(&text[start..end], bpe);
+    let total = offsets.len() - 1;
+    let mut ranges = Vec::new();
+    let mut i = 0;
+
+    while i < total {
+        let j = (i + options.chunk_size).min(total);
+        let range_start = floor_char_boundary(text, start + offsets[i]);
+        let range_end = if j == total { end } else { floor_char_boundary(text, start + offsets[j]) };
+
+        if range_end > range_start {
+            ranges.push((range_start, range_end));
+        }
+        if j == total {
+            break;
+        }
+        i = j - options.overlap;
+    }
+
+    ranges
+}
+
+fn sentence_boundary() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r#"[.!?]+["'”’)\]]*\s+|\n[ \t]*\n\s*"#).unwrap())
+}
+
+fn paragraph_boundary() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\n[ \t]*\n\s*").unwrap())
+}
+
+fn split_on(text: &str, boundary: &Regex) -> Vec<(usize, usize, Option<String>)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    for m in boundary.find_iter(text) {
+        ranges.push((start, m.end(), None));
+        start = m.end();
+    }
+    if start < text.len() {
+        ranges.push((start, text.len(), None));
+    }
+    ranges
+}
+
+// Split markdown into blocks at blank lines and headings, keeping fenced code blocks
+// whole and tracking the heading path each block falls under
+fn split_markdown(text: &str) -> Vec<(usize, usize, Option<String>)> {
+    let mut ranges = Vec::new();
+    let mut headings: Vec<(usize, String)> = Vec::new();
+    let mut in_fence = false;
+    // A heading stays attached to the block that follows it
+    let mut heading_only = false;
+    let mut block_start = 0;
+    let mut offset = 0;
+
+    let heading_path = |headings: &Vec<(usize, String)>| {
+        if headings.is_empty() {
+            None
+        } else {
+            Some(headings.iter().map(|(_, h)| h.as_str()).collect::<Vec<_>>().join(" > "))
+        }
+    };
+
+    for line in text.split_inclusive('\n') {
+        let line_start = offset;
+        offset += line.len();
+        let trimmed = line.trim();
+
+        if trimmed.starts_with("```") {
+            in_fence = !in_fence;
+            heading_only = false;
+            continue;
+        }
+        if in_fence {
+            continue;
+        }
+
+        let level = trimmed.chars().take_while(|c| *c == '#').count();
+        let is_heading = (1..=6).contains(&level) && trimmed[level..].starts_with(' ');
+
+        if is_heading {
+            if line_start > block_start {
+                ranges.push((block_start, line_start, heading_path(&headings)));
+            }
+            headings.retain(|(l, _)| *l < level);
+            headings.push((level, trimmed[level..].trim().to_string()));
+            block_start = line_start;
+            heading_only = true;
+        } else if trimmed.is_empty() {
+            if !heading_only && offset > block_start {
+                ranges.push((block_start, offset, heading_path(&headings)));
+                block_start = offset;
+            }
+        } else {
+            heading_only = false;
+        }
+    }
+
+    if block_start < text.len() {
+        ranges.push((block_start, text.len(), heading_path(&headings)));
+    }
+
+    ranges
+}
+
+// Greedily pack segments into chunks of at most chunk_size tokens, carrying trailing
+// segments of the previous chunk forward as overlap
+fn pack(text: &str, segments: Vec<Segment>, options: &ChunkOptions, bpe: &CoreBPE) -> Vec<(usize, usize, Option<String>)> {
+    let mut ranges = Vec::new();
+    let mut current: Vec<Segment> = Vec::new();
+    let mut current_tokens = 0;
+
+    let flush = |current: &[Segment], ranges: &mut Vec<(usize, usize, Option<String>)>| {
+        if let (Some(first), Some(last)) = (current.first(), current.last()) {
+            ranges.push((first.start, last.end, first.heading.clone()));
+        }
+    };
+
+    for segment in segments {
+        if segment.tokens > options.chunk_size {
+            flush(&current, &mut ranges);
+            current.clear();
+            current_tokens = 0;
+            for (start, end) in split_fixed(text, segment.start, segment.end, options, bpe) {
+                ranges.push((start, end, segment.heading.clone()));
+            }
+            continue;
+        }
+
+        if current_tokens + segment.tokens > options.chunk_size && !current.is_empty() {
+            flush(&current, &mut ranges);
+
+            let mut carried = 0;
+            let keep_from = current
+                .iter()
+                .rposition(|s| {
+                    carried += s.tokens;
+                    carried > options.overlap || s.heading != segment.heading
+                })
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            current.drain(..keep_from);
+            current_tokens = current.iter().map(|s| s.tokens).sum();
+        }
+
+        current_tokens += segment.tokens;
+        current.push(segment);
+    }
+    flush(&current, &mut ranges);
+
+    ranges
+}
+
+pub fn chunk(text: &str, options: &ChunkOptions, bpe: &CoreBPE) -> Vec<Chunk> {
+    let ranges = match options.strategy {
+        Strategy::Fixed => split_fixed(text, 0, text.len(), options, bpe)
+            .into_iter()
+            .map(|(start, end)| (start, end, None))
+            .collect(),
+        strategy => {
+            let raw = match strategy {
+                Strategy::Paragraph => split_on(text, paragraph_boundary()),
+                Strategy::Markdown => split_markdown(text),
+                _ => split_on(text, sentence_boundary()),
+            };
+            let segments = raw
+                .into_iter()
+                .map(|(start, end, heading)| Segment {
+                    start,
+                    end,
+                    tokens: tokens::count(bpe, &text[start..end]),
+                    heading,
+                })
+                .collect();
+            pack(text, segments, options, bpe)
+        },
+    };
+
+    ranges
+        .into_iter()
+        .filter_map(|(start, end, heading)| {
+            let slice = &text[start..end];
+            let trimmed_start = start + (slice.len() - slice.trim_start().len());
+            let trimmed_end = end - (slice.len() - slice.trim_end().len());
+            if trimmed_end <= trimmed_start {
+                return None;
+            }
+            let chunk_text = text[trimmed_start..trimmed_end].to_string();
+            Some((trimmed_start, trimmed_end, heading, chunk_text))
+        })
+        .enumerate()
+        .map(|(index, (start, end, heading, chunk_text))| Chunk {
+            index,
+            token_count: tokens::count(bpe, &chunk_text),
+            text: chunk_text,
+            start,
+            end,
+            heading,
+        })
+        .collect()
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn chunk_text(text: String, opts: Opts) -> NifResult<Vec<Chunk>> {
+    let options = decode_options(&opts)?;
+    Ok(tokens::with_bpe(&options.model, |bpe| chunk(&text, &options, bpe)))
+}
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+use async_openai::config::OpenAIConfig;
+use async_openai::error::OpenAIError;
+use async_openai::types::{CompletionUsage, CreateChatCompletionRequest, CreateChatCompletionStreamResponse};
+use async_openai::Client as OpenAIClient;
+use futures_util::stream::BoxStream;
+use futures_util::StreamExt;
+use rustler::NifResult;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::deadline::Deadline;
+use crate::http::{self, Transport};
+use crate::params::ChatParams;
+use crate::ratelimit::RateLimit;
+use crate::{developer_indices, restore_developer, Message, OpenAIClientResource};
+
+// An item of a chat stream. Streams opened with usage end with a chunk that has no
+// choices and the usage of the whole completion.
+#[derive(Deserialize)]
+pub struct ChatChunk {
+    #[serde(flatten)]
+    pub response: CreateChatCompletionStreamResponse,
+    #[serde(default)]
+    pub usage: Option<CompletionUsage>,
+}
+
+pub type ChunkStream = BoxStream<'static, Result<ChatChunk, OpenAIError>>;
+
+// How a chat stream is opened. async-openai's typed request has no `stream_options`,
+// developer role or some tool settings, and its chunks drop `usage`, so a stream that
+// needs any of them is sent through the transport with its body patched, and its
+// events are read here.
+pub enum Opener {
+    // The transport holds streams to the client's rate limit itself; the typed client
+    // takes a token here
+    Typed(OpenAIClient<OpenAIConfig>, Option<RateLimit>),
+    Raw {
+        transport: Transport,
+        // Set on the body as they are
+        fields: Map<String, Value>,
+        // Indices of the messages sent as system that are developer messages
+        developer: Vec<usize>,
+    },
+}
+
+impl Opener {
+    // The transport's retries are the stream's own, and faults are injected when the
+    // stream is opened instead
+    pub fn new(client_resource: &OpenAIClientResource, messages: &[Message], params: &ChatParams, include_usage: bool, deadline: Deadline) -> NifResult<Self> {
+        let developer = developer_indices(messages);
+        let mut fields = params.stream_fields();
+        if include_usage {
+            fields.insert("stream_options".to_string(), json!({ "include_usage": true }));
+        }
+        if fields.is_empty() && developer.is_empty() {
+            return Ok(Opener::Typed(client_resource.client()?, client_resource.rate_limit()?));
+        }
+        let transport = client_resource
+            .transport()?
+            .with_retry(client_resource.stream_retry()?)
+            .with_deadline(deadline)
+            .with_faults(None);
+        Ok(Opener::Raw { transport, fields, developer })
+    }
+
+    pub async fn open(&self, request: &CreateChatCompletionRequest) -> Result<ChunkStream, String> {
+        match self {
+            Opener::Typed(client, rate_limit) => {
+                if let Some(rate_limit) = rate_limit {
+                    rate_limit.acquire().await;
+                }
+                let stream = client.chat().create_stream(request.clone()).await.map_err(|e| e.to_string())?;
+                Ok(stream.map(|item| item.map(|response| ChatChunk { response, usage: None })).boxed())
+            },
+            Opener::Raw { transport, fields, developer } => {
+                let mut body = serde_json::to_value(request).map_err(|e| format!("Failed to encode request: {}", e))?;
+                for (key, value) in fields {
+                    body[key] = value.clone();
+                }
+                restore_developer(&mut body, developer);
+                let response = transport.post_json_stream("/chat/completions", &body).await.map_err(|e| e.message)?;
+                Ok(read(response))
+            },
+        }
+    }
+}
+
+// Reads the chunks of a response's server-sent events, up to `[DONE]`. Failures are
+// reported the way async-openai's own stream reports them.
+fn read(response: reqwest::Response) -> ChunkStream {
+    let state = (response.bytes_stream(), Vec::new(), VecDeque::new(), false);
+    futures_util::stream::unfold(state, |(mut bytes, mut buffer, mut ready, mut done)| async move {
+        loop {
+            if let Some(item) = ready.pop_front() {
+                return Some((item, (bytes, buffer, ready, done)));
+            }
+            if done {
+                return None;
+            }
+            match bytes.next().await {
+                Some(Ok(received)) => {
+                    buffer.extend(received.iter().filter(|byte| **byte != b'\r'));
+                    for data in http::take_event_data(&mut buffer) {
+                        if data == "[DONE]" {
+                            done = true;
+                            break;
+                        }
+                        ready.push_back(decode(&data));
+                    }
+                },
+                Some(Err(e)) => {
+                    ready.push_back(Err(OpenAIError::Reqwest(e)));
+                    done = true;
+                },
+                None => done = true,
+            }
+        }
+    })
+    .boxed()
+}
+
+fn decode(data: &str) -> Result<ChatChunk, OpenAIError> {
+    let value: Value = serde_json::from_str(data).map_err(OpenAIError::JSONDeserialize)?;
+    if let Some(error) = value.get("error") {
+        return match serde_json::from_value(error.clone()) {
+            Ok(error) => Err(OpenAIError::ApiError(error)),
+            Err(_) => Err(OpenAIError::StreamError(error.to_string())),
+        };
+    }
+    serde_json::from_value(value).map_err(OpenAIError::JSONDeserialize)
+}
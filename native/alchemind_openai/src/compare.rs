@@ -0,0 +1,208 @@
+use async_openai::types::CreateChatCompletionRequestArgs;
+use futures_util::StreamExt;
+use rustler::{Error, NifMap, NifResult, ResourceArc};
+use std::collections::HashMap;
+
+use crate::deadline::Deadline;
+use crate::eval::{self, EvalOptions, EvalResult, EvalStats};
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::{postprocess, request_content, to_request_messages, Message, OpenAIClientResource};
+
+const JUDGE_PROMPT: &str = "You are an impartial judge comparing two responses to the same task. \
+Decide which response is more accurate, helpful and faithful to the input. Ignore response length \
+and the order in which the responses are presented. Reply only with a JSON object of the form \
+{\"winner\": \"A\" | \"B\" | \"tie\", \"reason\": \"<one sentence>\"}.";
+
+#[derive(NifMap)]
+struct Judgement {
+    winner: String,
+    reason: String,
+}
+
+#[derive(NifMap)]
+struct ComparisonPair {
+    index: usize,
+    a: EvalResult,
+    b: EvalResult,
+    judgement: Option<Judgement>,
+}
+
+#[derive(NifMap)]
+struct Wins {
+    a: usize,
+    b: usize,
+    tie: usize,
+}
+
+#[derive(NifMap)]
+struct ComparisonStats {
+    a: EvalStats,
+    b: EvalStats,
+    // Variant B minus variant A
+    mean_latency_delta_ms: f64,
+    total_tokens_delta: i64,
+    wins: Option<Wins>,
+}
+
+#[derive(NifMap)]
+struct ComparisonReport {
+    pairs: Vec<ComparisonPair>,
+    stats: ComparisonStats,
+}
+
+struct Variant {
+    template: String,
+    options: EvalOptions,
+}
+
+fn decode_variant(variant: &Opts, name: &str) -> NifResult<Variant> {
+    let template = options::get_string(variant, "template")?
+        .ok_or_else(|| Error::Term(Box::new(format!("Variant {} is missing a template", name))))?;
+    Ok(Variant { template, options: eval::decode_options(variant)? })
+}
+
+fn judge_input(variables: &HashMap<String, String>, a: &str, b: &str) -> String {
+    let mut fields: Vec<_> = variables.iter().collect();
+    fields.sort();
+    let input = fields
+        .into_iter()
+        .map(|(key, value)| format!("{}: {}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n");
+    format!("Input:\n{}\n\nResponse A:\n{}\n\nResponse B:\n{}", input, a, b)
+}
+
+fn parse_judgement(content: &str) -> Judgement {
+    let value = postprocess::parse_first_json(content);
+    let field = |name: &str| {
+        value
+            .as_ref()
+            .and_then(|v| v.get(name))
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+    };
+
+    let winner = match field("winner").map(|w| w.to_lowercase()).as_deref() {
+        Some("a") => "a",
+        Some("b") => "b",
+        _ => "tie",
+    };
+
+    Judgement {
+        winner: winner.to_string(),
+        reason: field("reason").unwrap_or_else(|| content.to_string()),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn compare_prompts(client_resource: ResourceArc<OpenAIClientResource>, dataset: Vec<HashMap<String, String>>, variant_a: Opts, variant_b: Opts, opts: Opts) -> NifResult<ComparisonReport> {
+    let mut a = decode_variant(&variant_a, "a")?;
+    let mut b = decode_variant(&variant_b, "b")?;
+    // One deadline covers both variants and the judge pass
+    let deadline = Deadline::decode(&opts)?;
+    a.options.deadline = deadline;
+    b.options.deadline = deadline;
+    let concurrency = options::get_usize(&opts, "concurrency")?.unwrap_or(4).max(1);
+    let judge_model = options::get_string(&opts, "judge_model")?;
+
+    let transport = &client_resource.transport_for(Endpoint::Chat, &opts)?;
+
+    let mut jobs = Vec::with_capacity(dataset.len() * 2);
+    for (index, variables) in dataset.iter().enumerate() {
+        for variant in [&a, &b] {
+            let request = eval::build_request(eval::render(&variant.template, variables), &variant.options)
+                .map_err(|e| Error::Term(Box::new(e)))?;
+            jobs.push((index, variant, request));
+        }
+    }
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    // Both variants share one bounded pool so they see the same load conditions
+    let outputs = runtime.block_on(async {
+        futures_util::stream::iter(jobs)
+            .map(|(index, variant, request)| {
+                async move { eval::run_one(transport, index, request, &variant.options).await }
+            })
+            .buffered(concurrency)
+            .collect::<Vec<_>>()
+            .await
+    });
+
+    let mut pairs: Vec<ComparisonPair> = Vec::with_capacity(dataset.len());
+    let mut outputs = outputs.into_iter();
+    while let (Some(result_a), Some(result_b)) = (outputs.next(), outputs.next()) {
+        pairs.push(ComparisonPair { index: result_a.index, a: result_a, b: result_b, judgement: None });
+    }
+
+    if let Some(judge_model) = &judge_model {
+        let judgements = runtime.block_on(async {
+            futures_util::stream::iter(pairs.iter())
+                .map(|pair| {
+                    let content = match (&pair.a.output, &pair.b.output) {
+                        (Some(a), Some(b)) => Some(judge_input(&dataset[pair.index], a, b)),
+                        _ => None,
+                    };
+                    async move {
+                        // Pairs where either variant failed are not judged
+                        let content = content?;
+                        let messages = vec![
+                            Message::new("system", JUDGE_PROMPT.to_string()),
+                            Message::new("user", content),
+                        ];
+                        let request = CreateChatCompletionRequestArgs::default()
+                            .model(judge_model.as_str())
+                            .messages(to_request_messages(messages).ok()?)
+                            .temperature(0.0)
+                            .build()
+                            .ok()?;
+                        let reply = if deadline.passed() {
+                            None
+                        } else {
+                            deadline.run(request_content(transport, request)).await
+                        };
+                        Some(match reply {
+                            Some(Ok(reply)) => parse_judgement(&reply),
+                            Some(Err(e)) => Judgement { winner: "tie".to_string(), reason: e },
+                            None => Judgement { winner: "tie".to_string(), reason: "Deadline exceeded".to_string() },
+                        })
+                    }
+                })
+                .buffered(concurrency)
+                .collect::<Vec<_>>()
+                .await
+        });
+
+        for (pair, judgement) in pairs.iter_mut().zip(judgements) {
+            pair.judgement = judgement;
+        }
+    }
+
+    let results_a: Vec<EvalResult> = pairs.iter().map(|p| p.a.clone()).collect();
+    let results_b: Vec<EvalResult> = pairs.iter().map(|p| p.b.clone()).collect();
+    let stats_a = eval::aggregate(&results_a);
+    let stats_b = eval::aggregate(&results_b);
+
+    let wins = judge_model.as_ref().map(|_| {
+        let count = |winner: &str| {
+            pairs
+                .iter()
+                .filter(|p| p.judgement.as_ref().map(|j| j.winner == winner).unwrap_or(false))
+                .count()
+        };
+        Wins { a: count("a"), b: count("b"), tie: count("tie") }
+    });
+
+    Ok(ComparisonReport {
+        stats: ComparisonStats {
+            mean_latency_delta_ms: stats_b.latency.mean - stats_a.latency.mean,
+            total_tokens_delta: stats_b.usage.total_tokens as i64 - stats_a.usage.total_tokens as i64,
+            a: stats_a,
+            b: stats_b,
+            wins,
+        },
+        pairs,
+    })
+}
@@ -0,0 +1,115 @@
+use rustler::NifResult;
+use serde_json::{json, Value};
+
+use crate::options::{self, Opts};
+
+// Each continuation is another full request, prompt included
+const MAX_CONTINUATIONS: usize = 10;
+
+// How many times a completion cut off by the token limit is continued, from
+// `max_continuations`. 0, the default, returns it cut off as the API did.
+pub fn decode(opts: &Opts) -> NifResult<usize> {
+    let max = options::get_usize(opts, "max_continuations")?.unwrap_or(0);
+    if max > MAX_CONTINUATIONS {
+        return Err(options::invalid(
+            &options::path("max_continuations"),
+            format!("must be at most {}, got {}", MAX_CONTINUATIONS, max),
+        ));
+    }
+    Ok(max)
+}
+
+// The request that continues the single choice of `merged`, the output so far, when
+// the token limit cut it off: `body` with the partial output appended as an assistant
+// message for the model to carry on from. None when it finished, or called tools.
+pub fn request(body: &Value, merged: &Value) -> Option<Value> {
+    let [choice] = merged["choices"].as_array()?.as_slice() else {
+        return None;
+    };
+    let message = &choice["message"];
+    if choice["finish_reason"] != "length" || !message["tool_calls"].is_null() || !message["function_call"].is_null() {
+        return None;
+    }
+    let mut next = body.clone();
+    next["messages"]
+        .as_array_mut()?
+        .push(json!({"role": "assistant", "content": message["content"].as_str().unwrap_or_default()}));
+    Some(next)
+}
+
+// Appends the output of `next`, a continuation, to `merged`, which takes its finish
+// reason. Logprobs are joined and usage is added up across the requests.
+pub fn merge(merged: &mut Value, next: &Value) {
+    let (choice, next_choice) = (&mut merged["choices"][0], &next["choices"][0]);
+    let content = format!(
+        "{}{}",
+        choice["message"]["content"].as_str().unwrap_or_default(),
+        next_choice["message"]["content"].as_str().unwrap_or_default()
+    );
+    choice["message"]["content"] = Value::String(content);
+    choice["finish_reason"] = next_choice["finish_reason"].clone();
+    if let (Some(tokens), Some(next_tokens)) = (
+        choice.pointer_mut("/logprobs/content").and_then(Value::as_array_mut),
+        next_choice["logprobs"]["content"].as_array(),
+    ) {
+        tokens.extend(next_tokens.iter().cloned());
+    }
+
+    if let Some(usage) = merged["usage"].as_object_mut() {
+        for key in ["prompt_tokens", "completion_tokens", "total_tokens"] {
+            let total = usage.get(key).and_then(Value::as_u64).unwrap_or(0) + next["usage"][key].as_u64().unwrap_or(0);
+            usage.insert(key.to_string(), Value::from(total));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(content: &str, finish_reason: &str, completion_tokens: u64) -> Value {
+        json!({
+            "choices": [{"message": {"role": "assistant", "content": content}, "finish_reason": finish_reason}],
+            "usage": {"prompt_tokens": 10, "completion_tokens": completion_tokens, "total_tokens": 10 + completion_tokens},
+        })
+    }
+
+    #[test]
+    fn continues_output_cut_off_by_the_token_limit() {
+        let body = json!({"model": "gpt-4o", "messages": [{"role": "user", "content": "Count"}]});
+        let next = request(&body, &response("1, 2,", "length", 4)).unwrap();
+        assert_eq!(next["model"], "gpt-4o");
+        assert_eq!(next["messages"][1], json!({"role": "assistant", "content": "1, 2,"}));
+        assert_eq!(body["messages"].as_array().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn leaves_finished_tool_and_multi_choice_output() {
+        let body = json!({"messages": []});
+        assert!(request(&body, &response("Done", "stop", 1)).is_none());
+
+        let mut tools = response("", "length", 1);
+        tools["choices"][0]["message"]["tool_calls"] = json!([{"id": "call_1"}]);
+        assert!(request(&body, &tools).is_none());
+
+        let mut choices = response("a", "length", 1);
+        let choice = choices["choices"][0].clone();
+        choices["choices"].as_array_mut().unwrap().push(choice);
+        assert!(request(&body, &choices).is_none());
+    }
+
+    #[test]
+    fn merges_content_finish_reason_logprobs_and_usage() {
+        let mut merged = response("1, 2,", "length", 4);
+        merged["choices"][0]["logprobs"] = json!({"content": [{"token": "1"}]});
+        let mut next = response(" 3.", "stop", 2);
+        next["choices"][0]["logprobs"] = json!({"content": [{"token": " 3"}]});
+
+        merge(&mut merged, &next);
+        let choice = &merged["choices"][0];
+        assert_eq!(choice["message"]["content"], "1, 2, 3.");
+        assert_eq!(choice["finish_reason"], "stop");
+        assert_eq!(choice["logprobs"]["content"], json!([{"token": "1"}, {"token": " 3"}]));
+        assert_eq!(merged["usage"], json!({"prompt_tokens": 20, "completion_tokens": 6, "total_tokens": 26}));
+    }
+}
@@ -0,0 +1,42 @@
+use rustler::sys::{enif_monotonic_time, ErlNifTimeUnit};
+use rustler::NifResult;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::options::{self, Opts};
+
+// Overall deadline shared by nested calls. `deadline_ms` is an absolute Erlang
+// monotonic time (`System.monotonic_time(:millisecond)`), converted to an Instant
+// when the options are decoded so the rest of the code can use the Rust clock.
+#[derive(Clone, Copy, Default)]
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+    pub fn decode(opts: &Opts) -> NifResult<Self> {
+        let deadline_ms = match options::get_i64(opts, "deadline_ms")? {
+            Some(deadline_ms) => deadline_ms,
+            None => return Ok(Deadline(None)),
+        };
+
+        let now_ms = unsafe { enif_monotonic_time(ErlNifTimeUnit::ERL_NIF_MSEC) };
+        let remaining = Duration::from_millis(deadline_ms.saturating_sub(now_ms).max(0) as u64);
+        Ok(Deadline(Some(Instant::now() + remaining)))
+    }
+
+    pub fn passed(&self) -> bool {
+        self.0.is_some_and(|at| Instant::now() >= at)
+    }
+
+    // Whether waiting `delay` would still leave time before the deadline
+    pub fn allows(&self, delay: Duration) -> bool {
+        self.0.is_none_or(|at| Instant::now() + delay < at)
+    }
+
+    // Runs `future` until it completes or the deadline passes, returning None in the latter case
+    pub async fn run<F: Future>(&self, future: F) -> Option<F::Output> {
+        match self.0 {
+            Some(at) => tokio::time::timeout_at(at.into(), future).await.ok(),
+            None => Some(future.await),
+        }
+    }
+}
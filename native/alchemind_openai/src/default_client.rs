@@ -0,0 +1,49 @@
+use std::sync::Mutex;
+
+use rustler::env::{OwnedEnv, SavedTerm};
+use rustler::types::atom;
+use rustler::{Env, Error, NifResult, ResourceArc, Term};
+
+use crate::{atoms, OpenAIClientResource};
+
+// The default `%Alchemind.OpenAI.Client{}`, copied into an environment of its own so
+// it outlives the process that set it. Statics start empty on every load of the
+// library, so reloading it (module purge + load) also clears the default.
+static DEFAULT_CLIENT: Mutex<Option<(OwnedEnv, SavedTerm)>> = Mutex::new(None);
+
+// Stores `client` as the default, or clears it when given nil
+#[rustler::nif]
+fn set_default_client(client: Term) -> NifResult<rustler::Atom> {
+    let default = if client.is_atom() && client.decode::<rustler::Atom>()? == atom::nil() {
+        None
+    } else {
+        // Only clients of this load hold a resource that decodes
+        client
+            .map_get(atoms::rust_client())
+            .and_then(|resource| resource.decode::<ResourceArc<OpenAIClientResource>>())
+            .map_err(|_| Error::Term(Box::new("Default client must be an Alchemind.OpenAI client created by new/1")))?;
+        let owned = OwnedEnv::new();
+        let saved = owned.save(client);
+        Some((owned, saved))
+    };
+
+    let mut current = DEFAULT_CLIENT
+        .lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock default client: {}", e))))?;
+    *current = default;
+
+    Ok(atoms::ok())
+}
+
+// A copy of the default client in the caller's environment, or nil when none is set
+#[rustler::nif]
+fn default_client(env: Env) -> NifResult<Term> {
+    let current = DEFAULT_CLIENT
+        .lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock default client: {}", e))))?;
+
+    Ok(match current.as_ref() {
+        Some((owned, saved)) => owned.run(|owned_env| saved.load(owned_env).in_env(env)),
+        None => atom::nil().to_term(env),
+    })
+}
@@ -0,0 +1,182 @@
+use rustler::{NifMap, NifResult};
+use serde_json::Value;
+
+use crate::audio;
+use crate::options::{self, Opts};
+
+const MAX_SPEAKERS: usize = 8;
+
+// A shorter silence is usually a speaker catching their breath
+const DEFAULT_MIN_PAUSE_MS: usize = 800;
+
+// k-means rounds; a recording has few enough turns to settle well before this
+const CLUSTER_ROUNDS: usize = 20;
+
+// Best-effort speaker labels for verbose_json segments, which Whisper returns without
+// any. Segments are grouped into turns wherever a pause of at least `min_pause_ms`
+// separates them, and turns are clustered by loudness and zero-crossing rate, a
+// rough stand-in for pitch, when the audio is a WAV file whose samples can be read.
+// For other audio, turns alternate between the speakers. Labels are guesses.
+pub struct Diarizer {
+    speakers: usize,
+    min_pause_s: f64,
+}
+
+#[derive(NifMap)]
+pub struct Segment {
+    start: f64,
+    end: f64,
+    text: String,
+    // "speaker_1" for whoever speaks first, "speaker_2" for the next voice, and so on
+    speaker: String,
+}
+
+#[derive(NifMap)]
+pub struct DiarizedTranscript {
+    pub text: String,
+    pub segments: Vec<Segment>,
+    pub audio_duration_s: Option<f64>,
+}
+
+impl Diarizer {
+    // None unless `diarize: true`
+    pub fn decode(opts: &Opts) -> NifResult<Option<Self>> {
+        if !options::get_bool(opts, "diarize")?.unwrap_or(false) {
+            return Ok(None);
+        }
+        let speakers = options::get_usize(opts, "speakers")?.unwrap_or(2);
+        if !(1..=MAX_SPEAKERS).contains(&speakers) {
+            return Err(options::invalid(
+                &options::path("speakers"),
+                format!("must be between 1 and {}, got {}", MAX_SPEAKERS, speakers),
+            ));
+        }
+        let min_pause_ms = options::get_usize(opts, "min_pause_ms")?.unwrap_or(DEFAULT_MIN_PAUSE_MS);
+        Ok(Some(Diarizer { speakers, min_pause_s: min_pause_ms as f64 / 1000.0 }))
+    }
+
+    // Labels the `segments` of a verbose_json response, passing each text through `redact`
+    pub fn label(&self, body: &Value, audio: &[u8], redact: impl Fn(&str) -> String) -> Vec<Segment> {
+        let segments: Vec<(f64, f64, &str)> = body["segments"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .map(|segment| {
+                let time = |key: &str| segment[key].as_f64().unwrap_or(0.0);
+                (time("start"), time("end"), segment["text"].as_str().unwrap_or_default().trim())
+            })
+            .collect();
+
+        // Start and end of each turn, and the turn each segment belongs to
+        let mut turns: Vec<(f64, f64)> = Vec::new();
+        let mut turn_of = Vec::with_capacity(segments.len());
+        for (start, end, _) in &segments {
+            match turns.last_mut() {
+                Some((_, turn_end)) if start - *turn_end < self.min_pause_s => *turn_end = turn_end.max(*end),
+                _ => turns.push((*start, *end)),
+            }
+            turn_of.push(turns.len() - 1);
+        }
+
+        let clusters = match audio::mono_pcm(audio) {
+            Some(pcm) if turns.len() > 1 => {
+                let features = turns.iter().map(|(start, end)| features(&pcm, *start, *end)).collect();
+                cluster(&normalize(features), self.speakers.min(turns.len()))
+            },
+            _ => (0..turns.len()).map(|turn| turn % self.speakers).collect(),
+        };
+
+        // Speakers are numbered in order of appearance rather than by cluster
+        let mut order: Vec<usize> = Vec::new();
+        for cluster in &clusters {
+            if !order.contains(cluster) {
+                order.push(*cluster);
+            }
+        }
+
+        segments
+            .into_iter()
+            .zip(turn_of)
+            .map(|((start, end, text), turn)| {
+                let speaker = order.iter().position(|cluster| *cluster == clusters[turn]).unwrap_or(0) + 1;
+                Segment { start, end, text: redact(text), speaker: format!("speaker_{}", speaker) }
+            })
+            .collect()
+    }
+}
+
+// Loudness in dB and zero crossings per second of the audio between `start` and `end`
+fn features(pcm: &audio::MonoPcm, start: f64, end: f64) -> [f64; 2] {
+    let at = |seconds: f64| ((seconds.max(0.0) * pcm.sample_rate as f64) as usize).min(pcm.frames());
+    let (mut count, mut energy, mut crossings, mut previous) = (0usize, 0.0, 0usize, None);
+    for sample in pcm.samples(at(start), at(end).max(at(start))) {
+        energy += (sample as f64).powi(2);
+        if previous.is_some_and(|previous: f32| (previous < 0.0) != (sample < 0.0)) {
+            crossings += 1;
+        }
+        previous = Some(sample);
+        count += 1;
+    }
+    if count == 0 {
+        return [0.0, 0.0];
+    }
+
+    let power = energy / count as f64;
+    [10.0 * (power + 1e-10).log10(), crossings as f64 * pcm.sample_rate as f64 / count as f64]
+}
+
+// Scales each feature to zero mean and unit variance, so neither dominates the distance
+fn normalize(mut points: Vec<[f64; 2]>) -> Vec<[f64; 2]> {
+    let count = points.len() as f64;
+    for dimension in 0..2 {
+        let mean = points.iter().map(|point| point[dimension]).sum::<f64>() / count;
+        let variance = points.iter().map(|point| (point[dimension] - mean).powi(2)).sum::<f64>() / count;
+        let deviation = variance.sqrt();
+        for point in &mut points {
+            point[dimension] = if deviation > 0.0 { (point[dimension] - mean) / deviation } else { 0.0 };
+        }
+    }
+    points
+}
+
+fn distance(a: &[f64; 2], b: &[f64; 2]) -> f64 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)
+}
+
+// k-means, seeded with the first point and then each point farthest from the seeds so
+// far, which keeps the labels deterministic. Identical points make fewer clusters.
+fn cluster(points: &[[f64; 2]], k: usize) -> Vec<usize> {
+    let mut centroids = vec![points[0]];
+    while centroids.len() < k {
+        let nearest = |point: &[f64; 2]| centroids.iter().map(|centroid| distance(point, centroid)).fold(f64::INFINITY, f64::min);
+        let (farthest, gap) = points
+            .iter()
+            .map(|point| (*point, nearest(point)))
+            .fold(([0.0; 2], 0.0), |best, next| if next.1 > best.1 { next } else { best });
+        if gap == 0.0 {
+            break;
+        }
+        centroids.push(farthest);
+    }
+
+    let mut assignments = vec![0; points.len()];
+    for _ in 0..CLUSTER_ROUNDS {
+        for (point, assignment) in points.iter().zip(&mut assignments) {
+            *assignment = (0..centroids.len())
+                .min_by(|a, b| distance(point, &centroids[*a]).total_cmp(&distance(point, &centroids[*b])))
+                .unwrap_or(0);
+        }
+        for (index, centroid) in centroids.iter_mut().enumerate() {
+            let members: Vec<&[f64; 2]> = points.iter().zip(&assignments).filter(|(_, a)| **a == index).map(|(p, _)| p).collect();
+            if !members.is_empty() {
+                let count = members.len() as f64;
+                *centroid = [
+                    members.iter().map(|point| point[0]).sum::<f64>() / count,
+                    members.iter().map(|point| point[1]).sum::<f64>() / count,
+                ];
+            }
+        }
+    }
+    assignments
+}
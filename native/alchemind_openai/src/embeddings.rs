@@ -0,0 +1,256 @@
+use async_openai::types::{CreateEmbeddingRequestArgs, CreateEmbeddingResponse, EmbeddingInput};
+use futures_util::StreamExt;
+use rustler::{Binary, Encoder, Env, Error, NifMap, NifResult, OwnedBinary, ResourceArc, Term};
+use std::collections::BTreeSet;
+
+use crate::cancel::{self, CancelToken};
+use crate::chunking::{self, Chunk};
+use crate::deadline::Deadline;
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::vectors::pack_f32;
+use crate::{atoms, tokens, OpenAIClientResource};
+
+#[derive(NifMap)]
+struct DocumentChunk {
+    doc_index: usize,
+    index: usize,
+    text: String,
+    start: usize,
+    end: usize,
+    token_count: usize,
+    heading: Option<String>,
+}
+
+#[derive(NifMap)]
+struct EmbeddingUsage {
+    prompt_tokens: u32,
+    total_tokens: u32,
+}
+
+// How quantized vectors were encoded. For int8, a component is recovered as
+// (q + 128) * scale + offset; binary vectors keep only the sign bits.
+#[derive(NifMap)]
+struct Quantization {
+    scheme: String,
+    scale: Option<f32>,
+    offset: Option<f32>,
+}
+
+#[derive(NifMap)]
+struct EmbeddedDocuments<'a> {
+    chunks: Vec<DocumentChunk>,
+    // Row-major little-endian f32 vectors, one row of `dimensions` floats per chunk.
+    // When quantized, one signed byte per component (int8) or one bit per component,
+    // most significant bit first with each row padded to whole bytes (binary).
+    vectors: Binary<'a>,
+    dimensions: usize,
+    quantization: Option<Quantization>,
+    usage: EmbeddingUsage,
+}
+
+// int8 with one scale/offset for the whole batch, mapping its min..max onto -128..127
+fn quantize_int8(vectors: &[Vec<f32>]) -> (Vec<u8>, Quantization) {
+    let (min, max) = vectors
+        .iter()
+        .flatten()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &x| (min.min(x), max.max(x)));
+    let (offset, scale) = if min.is_finite() && max > min { (min, (max - min) / 255.0) } else { (0.0, 1.0) };
+
+    let bytes = vectors
+        .iter()
+        .flatten()
+        .map(|&x| (((x - offset) / scale).round().clamp(0.0, 255.0) as i32 - 128) as i8 as u8)
+        .collect();
+
+    (bytes, Quantization { scheme: "int8".to_string(), scale: Some(scale), offset: Some(offset) })
+}
+
+// One bit per component, set when the component is positive
+fn quantize_binary(vectors: &[Vec<f32>]) -> (Vec<u8>, Quantization) {
+    let mut bytes = Vec::new();
+    for vector in vectors {
+        for bits in vector.chunks(8) {
+            let byte = bits
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (i, &x)| if x > 0.0 { byte | (0x80 >> i) } else { byte });
+            bytes.push(byte);
+        }
+    }
+
+    (bytes, Quantization { scheme: "binary".to_string(), scale: None, offset: None })
+}
+
+fn to_binary<'a>(env: Env<'a>, bytes: &[u8]) -> NifResult<Binary<'a>> {
+    let mut binary = OwnedBinary::new(bytes.len())
+        .ok_or_else(|| Error::Term(Box::new("Failed to allocate vector binary")))?;
+    binary.as_mut_slice().copy_from_slice(bytes);
+    Ok(binary.release(env))
+}
+
+// Matryoshka-style shortening: keep the leading components and rescale to unit length
+fn truncate_and_normalize(vector: &mut Vec<f32>, dimensions: usize) {
+    vector.truncate(dimensions);
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
+    }
+}
+
+// When the `cancel` token cuts the batches short, returns `{:cancelled, embedded, pending}`:
+// the documents whose chunks were all embedded, and the indices of the rest
+#[rustler::nif(schedule = "DirtyIo")]
+fn embed_documents<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, docs: Vec<String>, opts: Opts<'a>) -> NifResult<Term<'a>> {
+    // The embedding model also selects the tokenizer used for chunking
+    let chunk_options = chunking::decode_options(&opts)?;
+    let model = chunk_options.model.clone();
+    let batch_size = options::get_usize(&opts, "batch_size")?.unwrap_or(128).max(1);
+    let concurrency = options::get_usize(&opts, "concurrency")?.unwrap_or(4).max(1);
+    // `dimensions` asks the API for shorter vectors; `truncate_to` shortens them locally,
+    // for models and compatible servers that don't accept the parameter
+    let requested_dimensions = options::get_usize(&opts, "dimensions")?;
+    let truncate_to = options::get_usize(&opts, "truncate_to")?;
+    let user = options::get_string(&opts, "user")?;
+    let quantize = options::get_atom(&opts, "quantize")?;
+    if let Some(scheme) = quantize.as_deref().filter(|scheme| !matches!(*scheme, "int8" | "binary")) {
+        return Err(options::invalid(&options::path("quantize"), format!("must be :int8 or :binary, got :{}", scheme)));
+    }
+    let cancel = CancelToken::decode(&opts)?;
+
+    let chunks: Vec<(usize, Chunk)> = tokens::with_bpe(&model, |bpe| {
+        docs.iter()
+            .enumerate()
+            .flat_map(|(doc_index, doc)| {
+                chunking::chunk(doc, &chunk_options, bpe)
+                    .into_iter()
+                    .map(move |chunk| (doc_index, chunk))
+            })
+            .collect()
+    });
+
+    let transport = client_resource.transport_for(Endpoint::Other, &opts)?.with_deadline(Deadline::decode(&opts)?);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let batches: Vec<Vec<String>> = chunks
+        .chunks(batch_size)
+        .map(|batch| batch.iter().map(|(_, chunk)| chunk.text.clone()).collect())
+        .collect();
+
+    // Batches run concurrently but results are collected in submission order
+    let batch_sizes: Vec<usize> = batches.iter().map(Vec::len).collect();
+    let cancel_token = cancel.as_deref();
+    let results = runtime.block_on(async {
+        futures_util::stream::iter(batches)
+            .map(|inputs| {
+                let transport = transport.clone();
+                let model = model.clone();
+                let user = user.clone();
+                cancel::run(cancel_token, async move {
+                    let mut args = CreateEmbeddingRequestArgs::default();
+                    args.model(model).input(EmbeddingInput::StringArray(inputs));
+                    if let Some(dimensions) = requested_dimensions {
+                        args.dimensions(dimensions as u32);
+                    }
+                    if let Some(user) = user {
+                        args.user(user);
+                    }
+                    let request = args
+                        .build()
+                        .map_err(|e| format!("Failed to build embedding request: {}", e))?;
+                    transport
+                        .post_typed::<_, CreateEmbeddingResponse>("/embeddings", &request)
+                        .await
+                        .map_err(|e| format!("API embedding request failed: {}", e.message))
+                })
+            })
+            .buffered(concurrency)
+            .collect::<Vec<_>>()
+            .await
+    });
+
+    // One vector per chunk, None for chunks whose batch was cancelled
+    let mut vectors: Vec<Option<Vec<f32>>> = Vec::with_capacity(chunks.len());
+    let mut usage = EmbeddingUsage { prompt_tokens: 0, total_tokens: 0 };
+    for (result, batch_size) in results.into_iter().zip(batch_sizes) {
+        let Some(result) = result else {
+            vectors.extend(std::iter::repeat_with(|| None).take(batch_size));
+            continue;
+        };
+        let mut response = result.map_err(|e| Error::Term(Box::new(e)))?;
+        if response.data.len() != batch_size {
+            return Err(Error::Term(Box::new(format!(
+                "Expected {} embeddings but received {}",
+                batch_size,
+                response.data.len()
+            ))));
+        }
+        response.data.sort_by_key(|embedding| embedding.index);
+        usage.prompt_tokens += response.usage.prompt_tokens;
+        usage.total_tokens += response.usage.total_tokens;
+        vectors.extend(response.data.into_iter().map(|embedding| Some(embedding.embedding)));
+    }
+
+    // A partly embedded document is left out whole, so it can simply be embedded again
+    let pending: BTreeSet<usize> = chunks
+        .iter()
+        .zip(&vectors)
+        .filter(|(_, vector)| vector.is_none())
+        .map(|((doc_index, _), _)| *doc_index)
+        .collect();
+    let (chunks, mut vectors): (Vec<(usize, Chunk)>, Vec<Vec<f32>>) = chunks
+        .into_iter()
+        .zip(vectors)
+        .filter(|((doc_index, _), _)| !pending.contains(doc_index))
+        .filter_map(|(chunk, vector)| Some((chunk, vector?)))
+        .unzip();
+
+    if let Some(dimensions) = truncate_to {
+        if let Some(vector) = vectors.iter().find(|vector| vector.len() < dimensions) {
+            return Err(Error::Term(Box::new(format!(
+                "Cannot truncate {}-dimensional embeddings to {} dimensions",
+                vector.len(),
+                dimensions
+            ))));
+        }
+        vectors.iter_mut().for_each(|vector| truncate_and_normalize(vector, dimensions));
+    }
+
+    let dimensions = vectors.first().map(Vec::len).unwrap_or(0);
+    let (vectors, quantization) = match quantize.as_deref() {
+        Some("int8") => {
+            let (bytes, quantization) = quantize_int8(&vectors);
+            (to_binary(env, &bytes)?, Some(quantization))
+        },
+        Some(_) => {
+            let (bytes, quantization) = quantize_binary(&vectors);
+            (to_binary(env, &bytes)?, Some(quantization))
+        },
+        None => (pack_f32(env, &vectors)?, None),
+    };
+
+    let embedded = EmbeddedDocuments {
+        chunks: chunks
+            .into_iter()
+            .map(|(doc_index, chunk)| DocumentChunk {
+                doc_index,
+                index: chunk.index,
+                text: chunk.text,
+                start: chunk.start,
+                end: chunk.end,
+                token_count: chunk.token_count,
+                heading: chunk.heading,
+            })
+            .collect(),
+        vectors,
+        dimensions,
+        quantization,
+        usage,
+    };
+    if !pending.is_empty() {
+        return Ok((atoms::cancelled(), embedded, pending.into_iter().collect::<Vec<_>>()).encode(env));
+    }
+    Ok(embedded.encode(env))
+}
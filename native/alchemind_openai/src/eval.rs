@@ -0,0 +1,236 @@
+use async_openai::types::{CreateChatCompletionRequest, CreateChatCompletionRequestArgs, CreateChatCompletionResponse};
+use futures_util::StreamExt;
+use rustler::{Error, LocalPid, NifMap, NifResult, ResourceArc};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::deadline::Deadline;
+use crate::options::{self, Opts};
+use crate::http::Transport;
+use crate::limits::Endpoint;
+use crate::{atoms, to_request_messages, Message, OpenAIClientResource};
+
+#[derive(NifMap, Clone)]
+pub struct EvalResult {
+    pub index: usize,
+    pub output: Option<String>,
+    pub error: Option<String>,
+    pub latency_ms: u64,
+    pub attempts: u32,
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+#[derive(NifMap)]
+pub struct LatencyStats {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+#[derive(NifMap)]
+pub struct UsageStats {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+#[derive(NifMap)]
+pub struct EvalStats {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub latency: LatencyStats,
+    pub usage: UsageStats,
+}
+
+#[derive(NifMap)]
+struct EvalReport {
+    results: Vec<EvalResult>,
+    stats: EvalStats,
+}
+
+pub struct EvalOptions {
+    pub model: String,
+    pub system: Option<String>,
+    pub temperature: Option<f32>,
+    pub concurrency: usize,
+    pub max_retries: u32,
+    pub retry_delay_ms: u64,
+    pub deadline: Deadline,
+}
+
+pub fn decode_options(opts: &Opts) -> NifResult<EvalOptions> {
+    Ok(EvalOptions {
+        model: options::get_string(opts, "model")?.unwrap_or_else(|| "gpt-4o-mini".to_string()),
+        system: options::get_string(opts, "system")?,
+        temperature: options::get_f32(opts, "temperature")?,
+        concurrency: options::get_usize(opts, "concurrency")?.unwrap_or(4).max(1),
+        max_retries: options::get_usize(opts, "max_retries")?.unwrap_or(2) as u32,
+        retry_delay_ms: options::get_usize(opts, "retry_delay_ms")?.unwrap_or(500) as u64,
+        deadline: Deadline::decode(opts)?,
+    })
+}
+
+// Replace {{name}} placeholders with the matching dataset fields
+pub fn render(template: &str, variables: &HashMap<String, String>) -> String {
+    variables.iter().fold(template.to_string(), |acc, (key, value)| {
+        acc.replace(&format!("{{{{{}}}}}", key), value)
+    })
+}
+
+pub fn build_request(prompt: String, options: &EvalOptions) -> Result<CreateChatCompletionRequest, String> {
+    let mut messages = Vec::new();
+    if let Some(system) = &options.system {
+        messages.push(Message::new("system", system.clone()));
+    }
+    messages.push(Message::new("user", prompt));
+
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(options.model.clone()).messages(to_request_messages(messages)?);
+    if let Some(temperature) = options.temperature {
+        args.temperature(temperature);
+    }
+    args.build().map_err(|e| format!("Failed to build request: {}", e))
+}
+
+// Run one request, retrying failures with exponential backoff until the deadline
+pub async fn run_one(transport: &Transport, index: usize, request: CreateChatCompletionRequest, options: &EvalOptions) -> EvalResult {
+    let started = Instant::now();
+    let mut attempts = 0;
+    let failed = |error: String, attempts: u32| EvalResult {
+        index,
+        output: None,
+        error: Some(error),
+        latency_ms: started.elapsed().as_millis() as u64,
+        attempts,
+        prompt_tokens: 0,
+        completion_tokens: 0,
+    };
+
+    loop {
+        if options.deadline.passed() {
+            return failed("Deadline exceeded".to_string(), attempts);
+        }
+        attempts += 1;
+        let response = match options.deadline.run(transport.post_typed::<_, CreateChatCompletionResponse>("/chat/completions", &request)).await {
+            Some(response) => response,
+            None => return failed("Deadline exceeded".to_string(), attempts),
+        };
+        match response {
+            Ok(completion) => {
+                let output = completion
+                    .choices
+                    .into_iter()
+                    .next()
+                    .and_then(|choice| choice.message.content)
+                    .unwrap_or_default();
+                let (prompt_tokens, completion_tokens) = completion
+                    .usage
+                    .map(|usage| (usage.prompt_tokens, usage.completion_tokens))
+                    .unwrap_or((0, 0));
+
+                return EvalResult {
+                    index,
+                    output: Some(output),
+                    error: None,
+                    latency_ms: started.elapsed().as_millis() as u64,
+                    attempts,
+                    prompt_tokens,
+                    completion_tokens,
+                };
+            },
+            Err(e) if attempts > options.max_retries => {
+                return failed(format!("API request failed: {}", e.message), attempts);
+            },
+            Err(e) => {
+                let delay = Duration::from_millis(options.retry_delay_ms.saturating_mul(1 << (attempts - 1).min(10)));
+                // Report the real failure rather than waiting past the deadline for a retry
+                if !options.deadline.allows(delay) {
+                    return failed(format!("API request failed: {}", e.message), attempts);
+                }
+                tokio::time::sleep(delay).await;
+            },
+        }
+    }
+}
+
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank.min(sorted.len() - 1)]
+}
+
+pub fn aggregate(results: &[EvalResult]) -> EvalStats {
+    let succeeded: Vec<&EvalResult> = results.iter().filter(|r| r.error.is_none()).collect();
+
+    let mut latencies: Vec<u64> = succeeded.iter().map(|r| r.latency_ms).collect();
+    latencies.sort_unstable();
+
+    let prompt_tokens: u64 = results.iter().map(|r| r.prompt_tokens as u64).sum();
+    let completion_tokens: u64 = results.iter().map(|r| r.completion_tokens as u64).sum();
+
+    EvalStats {
+        total: results.len(),
+        succeeded: succeeded.len(),
+        failed: results.len() - succeeded.len(),
+        latency: LatencyStats {
+            min: latencies.first().copied().unwrap_or(0),
+            max: latencies.last().copied().unwrap_or(0),
+            mean: if latencies.is_empty() {
+                0.0
+            } else {
+                latencies.iter().sum::<u64>() as f64 / latencies.len() as f64
+            },
+            p50: percentile(&latencies, 50.0),
+            p90: percentile(&latencies, 90.0),
+            p99: percentile(&latencies, 99.0),
+        },
+        usage: UsageStats {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        },
+    }
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn run_eval(env: rustler::Env, client_resource: ResourceArc<OpenAIClientResource>, dataset: Vec<HashMap<String, String>>, prompt_template: String, opts: Opts, pid: LocalPid) -> NifResult<EvalReport> {
+    let options = decode_options(&opts)?;
+    let transport = client_resource.transport_for(Endpoint::Chat, &opts)?;
+
+    let requests = dataset
+        .iter()
+        .map(|variables| build_request(render(&prompt_template, variables), &options))
+        .collect::<Result<Vec<_>, String>>()
+        .map_err(|e| Error::Term(Box::new(e)))?;
+    let total = requests.len();
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let results = runtime.block_on(async {
+        let mut pending = futures_util::stream::iter(requests.into_iter().enumerate())
+            .map(|(index, request)| run_one(&transport, index, request, &options))
+            .buffer_unordered(options.concurrency);
+
+        let mut results = Vec::with_capacity(total);
+        while let Some(result) = pending.next().await {
+            let status = if result.error.is_none() { atoms::ok() } else { atoms::error() };
+            let _ = env.send(&pid, (atoms::eval_progress(), results.len() + 1, total, result.index, status));
+            results.push(result);
+        }
+        results
+    });
+
+    let mut results = results;
+    results.sort_by_key(|r| r.index);
+    let stats = aggregate(&results);
+
+    Ok(EvalReport { results, stats })
+}
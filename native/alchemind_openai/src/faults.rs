@@ -0,0 +1,157 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_openai::error::OpenAIError;
+use futures_util::StreamExt;
+use reqwest::StatusCode;
+use rustler::{Error, NifResult, ResourceArc};
+
+use crate::chunks::ChunkStream;
+use crate::options::{self, Opts};
+use crate::{atoms, OpenAIClientResource};
+
+// Injected errors say so, to tell them apart from real ones in logs
+const MARK: &str = "(injected fault)";
+
+// Fails a client's requests and slows its streams at random, so applications can test
+// their retries and supervision against failures that look like the API's own,
+// without a server. Each attempt draws at most one fault.
+#[derive(Clone)]
+pub struct Faults {
+    // Chances, from 0 to 1, of an attempt failing with each fault
+    rate_limit: f64,
+    server_error: f64,
+    timeout: f64,
+    // Chance of a stream waiting `slow_stream_delay` before each chunk
+    slow_stream: f64,
+    slow_stream_delay: Duration,
+    // Shared by the clones handed to each request, so a seed gives one sequence
+    state: Arc<AtomicU64>,
+}
+
+#[derive(Clone, Copy)]
+pub enum Fault {
+    RateLimit,
+    ServerError,
+    Timeout,
+}
+
+impl Faults {
+    // None when no fault can happen, which leaves the client as it was
+    fn decode(opts: &Opts) -> NifResult<Option<Self>> {
+        let chance = |key: &str| -> NifResult<f64> {
+            match options::get_f64(opts, key)? {
+                Some(chance) if !(0.0..=1.0).contains(&chance) => {
+                    Err(options::invalid(&options::path(key), format!("must be between 0 and 1, got {}", chance)))
+                },
+                chance => Ok(chance.unwrap_or(0.0)),
+            }
+        };
+        let (rate_limit, server_error, timeout, slow_stream) =
+            (chance("rate_limit")?, chance("server_error")?, chance("timeout")?, chance("slow_stream")?);
+        // Chances such as 0.1, 0.2 and 0.7 add up to a hair over 1 in floating point
+        if rate_limit + server_error + timeout > 1.0 + 1e-9 {
+            return Err(options::invalid("opts.rate_limit, opts.server_error and opts.timeout", "must add up to at most 1"));
+        }
+        let slow_stream_delay = Duration::from_millis(options::get_usize(opts, "slow_stream_delay_ms")?.unwrap_or(1000) as u64);
+        let seed = match options::get_i64(opts, "seed")? {
+            Some(seed) => seed as u64,
+            None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64,
+        };
+
+        if rate_limit + server_error + timeout + slow_stream == 0.0 {
+            return Ok(None);
+        }
+        Ok(Some(Faults {
+            rate_limit,
+            server_error,
+            timeout,
+            slow_stream,
+            slow_stream_delay,
+            state: Arc::new(AtomicU64::new(seed)),
+        }))
+    }
+
+    // Uniform in [0, 1), from SplitMix64
+    fn roll(&self) -> f64 {
+        let mut z = self.state.fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed).wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) as f64 / (u64::MAX as f64 + 1.0)
+    }
+
+    // The fault the next attempt fails with, if any
+    pub fn draw(&self) -> Option<Fault> {
+        let roll = self.roll();
+        if roll < self.rate_limit {
+            Some(Fault::RateLimit)
+        } else if roll < self.rate_limit + self.server_error {
+            Some(Fault::ServerError)
+        } else if roll < self.rate_limit + self.server_error + self.timeout {
+            Some(Fault::Timeout)
+        } else {
+            None
+        }
+    }
+
+    // Delays each chunk of `stream`, if it is drawn to be slow
+    pub fn slow(&self, stream: ChunkStream) -> ChunkStream {
+        if self.roll() >= self.slow_stream {
+            return stream;
+        }
+        let delay = self.slow_stream_delay;
+        stream
+            .then(move |item| async move {
+                tokio::time::sleep(delay).await;
+                item
+            })
+            .boxed()
+    }
+}
+
+impl Fault {
+    // The status and body the API answers with, or None for a timeout, which gets no answer
+    pub fn response(self) -> Option<(StatusCode, String)> {
+        let (status, message, error_type, code) = match self {
+            Fault::RateLimit => (StatusCode::TOO_MANY_REQUESTS, "Rate limit reached", "requests", "rate_limit_exceeded"),
+            Fault::ServerError => (StatusCode::INTERNAL_SERVER_ERROR, "The server had an error while processing your request", "server_error", "server_error"),
+            Fault::Timeout => return None,
+        };
+        let body = serde_json::json!({ "error": { "message": format!("{} {}", message, MARK), "type": error_type, "code": code } });
+        Some((status, body.to_string()))
+    }
+
+    pub fn timeout_message() -> String {
+        format!("http error: operation timed out {}", MARK)
+    }
+
+    // The error opening a stream fails with, as async-openai reports it, so stream
+    // retries treat it like the real thing
+    pub fn stream_error(self) -> OpenAIError {
+        match self.response() {
+            Some((status, _)) => OpenAIError::StreamError(format!("Invalid status code: {} {}", status, MARK)),
+            None => OpenAIError::StreamError(format!("operation timed out {}", MARK)),
+        }
+    }
+}
+
+impl OpenAIClientResource {
+    pub fn faults(&self) -> NifResult<Option<Faults>> {
+        match self.faults.lock() {
+            Ok(faults) => Ok(faults.clone()),
+            Err(e) => Err(Error::Term(Box::new(format!("Failed to lock faults: {}", e)))),
+        }
+    }
+}
+
+// Sets the faults injected into the client's requests and streams from their chances,
+// or turns injection off when none is given
+#[rustler::nif]
+fn set_faults(client_resource: ResourceArc<OpenAIClientResource>, opts: Opts) -> NifResult<rustler::Atom> {
+    let decoded = Faults::decode(&opts)?;
+    let mut current = client_resource.faults.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock faults: {}", e))))?;
+    *current = decoded;
+    Ok(atoms::ok())
+}
@@ -0,0 +1,179 @@
+use rustler::{NifMap, NifResult};
+use std::collections::{HashMap, HashSet};
+
+use crate::options::{self, Opts};
+use crate::{tokens, Message};
+
+#[derive(NifMap)]
+struct CompressionStats {
+    original_tokens: usize,
+    compressed_tokens: usize,
+    saved_tokens: usize,
+    removed_messages: usize,
+    dictionary_entries: usize,
+}
+
+// Drop repeated few-shot examples. A user message followed by an assistant reply is
+// treated as one example; any other message is compared on its own. Messages from
+// different named participants are never duplicates of each other. The final message
+// (the actual query) is always kept, as are tool calls and their results, which only
+// make sense together, and messages with content parts.
+fn dedupe(messages: Vec<Message>) -> (Vec<Message>, usize) {
+    let last = messages.len().saturating_sub(1);
+    let mut seen = HashSet::new();
+    let mut kept = Vec::with_capacity(messages.len());
+    let mut removed = 0;
+    let mut iter = messages.into_iter().enumerate().peekable();
+
+    while let Some((i, msg)) = iter.next() {
+        if i == last {
+            kept.push(msg);
+            break;
+        }
+        if is_kept_whole(&msg) {
+            kept.push(msg);
+            continue;
+        }
+
+        let is_pair = msg.role == "user"
+            && matches!(iter.peek(), Some((j, next)) if *j < last && next.role == "assistant" && !is_kept_whole(next));
+
+        if is_pair {
+            let (_, reply) = iter.next().unwrap();
+            let key = (msg.name.clone(), msg.content.clone(), reply.content.clone(), true);
+            if seen.insert(key) {
+                kept.push(msg);
+                kept.push(reply);
+            } else {
+                removed += 2;
+            }
+        } else {
+            let key = (msg.name.clone(), msg.role.clone(), msg.content.clone(), false);
+            if seen.insert(key) {
+                kept.push(msg);
+            } else {
+                removed += 1;
+            }
+        }
+    }
+
+    (kept, removed)
+}
+
+// Tool turns, and messages with images, whose text alone doesn't identify them
+fn is_kept_whole(msg: &Message) -> bool {
+    msg.role == "tool" || msg.tool_calls.is_some() || msg.parts.is_some()
+}
+
+// Replace long lines repeated across messages with short [[En]] references and
+// prepend a system message defining them. Entries are only used when they save tokens.
+fn compress(messages: &mut Vec<Message>, min_length: usize, bpe: &tiktoken_rs::CoreBPE) -> usize {
+    let last = messages.len().saturating_sub(1);
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut order = Vec::new();
+    // Parts are sent as given, so their messages' text is left alone
+    for msg in messages[..last].iter().filter(|msg| msg.parts.is_none()) {
+        for line in msg.content.lines().map(str::trim).filter(|l| l.len() >= min_length) {
+            let count = counts.entry(line.to_string()).or_insert_with(|| {
+                order.push(line.to_string());
+                0
+            });
+            *count += 1;
+        }
+    }
+
+    let mut dictionary: HashMap<String, String> = HashMap::new();
+    let mut definitions = Vec::new();
+    for line in order {
+        let occurrences = counts[&line];
+        if occurrences < 2 {
+            continue;
+        }
+
+        let reference = format!("[[E{}]]", definitions.len() + 1);
+        let definition = format!("{}: {}", reference, line);
+        let line_tokens = tokens::count(bpe, &line);
+        let ref_tokens = tokens::count(bpe, &reference);
+        let cost = occurrences * ref_tokens + tokens::count(bpe, &definition);
+
+        if occurrences * line_tokens > cost {
+            definitions.push(definition);
+            dictionary.insert(line, reference);
+        }
+    }
+
+    if dictionary.is_empty() {
+        return 0;
+    }
+
+    for msg in messages[..last].iter_mut().filter(|msg| msg.parts.is_none()) {
+        msg.content = msg
+            .content
+            .lines()
+            .map(|line| match dictionary.get(line.trim()) {
+                Some(reference) => {
+                    let indent = &line[..line.len() - line.trim_start().len()];
+                    format!("{}{}", indent, reference)
+                },
+                None => line.to_string(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    let header = Message::new(
+        "system",
+        format!(
+            "The examples below use these placeholders; expand them when reading:\n{}",
+            definitions.join("\n")
+        ),
+    );
+    let insert_at = messages.iter().take_while(|m| m.role == "system").count();
+    messages.insert(insert_at, header);
+
+    definitions.len()
+}
+
+fn total_tokens(messages: &[Message], bpe: &tiktoken_rs::CoreBPE) -> usize {
+    messages
+        .iter()
+        .map(|m| {
+            let calls: usize = m
+                .tool_calls
+                .iter()
+                .flatten()
+                .map(|call| tokens::count(bpe, &call.name) + tokens::count(bpe, &call.arguments))
+                .sum();
+            tokens::count(bpe, &m.content) + calls
+        })
+        .sum()
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn compress_examples(messages: Vec<Message>, opts: Opts) -> NifResult<(Vec<Message>, CompressionStats)> {
+    let model = options::get_string(&opts, "model")?.unwrap_or_else(|| "gpt-4o".to_string());
+    let dictionary = options::get_bool(&opts, "compress")?.unwrap_or(false);
+    let min_length = options::get_usize(&opts, "min_length")?.unwrap_or(40);
+
+    tokens::with_bpe(&model, |bpe| {
+        let original_tokens = total_tokens(&messages, bpe);
+
+        let (mut messages, removed_messages) = dedupe(messages);
+        let dictionary_entries = if dictionary {
+            compress(&mut messages, min_length, bpe)
+        } else {
+            0
+        };
+
+        let compressed_tokens = total_tokens(&messages, bpe);
+
+        Ok((messages, CompressionStats {
+            original_tokens,
+            compressed_tokens,
+            saved_tokens: original_tokens.saturating_sub(compressed_tokens),
+            removed_messages,
+            dictionary_entries,
+        }))
+    })
+}
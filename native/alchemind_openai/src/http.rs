@@ -0,0 +1,451 @@
+use async_openai::config::{Config, OpenAIConfig};
+use backoff::backoff::Backoff as _;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE};
+use reqwest::multipart::Form;
+use reqwest::{Method, StatusCode};
+use rustler::{Encoder, Env, Error, NifMap, NifResult, Term};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::deadline::Deadline;
+use crate::faults::{Fault, Faults};
+use crate::limits::Limits;
+use crate::options::{self, Opts};
+use crate::ratelimit::RateLimit;
+use crate::retry::RetryPolicy;
+use crate::stats::ClientStats;
+
+// Headers returned for `return_headers: true`
+const DEFAULT_HEADERS: &[&str] = &[
+    "x-request-id",
+    "openai-processing-ms",
+    "openai-model",
+    "openai-version",
+    "openai-organization",
+    "x-model-version",
+    "x-ratelimit-limit-requests",
+    "x-ratelimit-remaining-requests",
+    "x-ratelimit-limit-tokens",
+    "x-ratelimit-remaining-tokens",
+];
+
+pub struct Response {
+    pub body: Vec<u8>,
+    pub headers: HeaderMap,
+}
+
+#[derive(NifMap)]
+pub struct ResponseMetadata {
+    request_id: Option<String>,
+    organization: Option<String>,
+    version: Option<String>,
+    // Time OpenAI spent processing the request, excluding network and queueing on our side
+    processing_ms: Option<u64>,
+}
+
+// Error from an API request. `retryable` says whether sending the same request
+// again could succeed: timeouts, connection failures, 408, 409, 429 and 5xx are
+// retryable, other statuses and exhausted quota are not.
+#[derive(NifMap, Debug, Clone)]
+pub struct ApiError {
+    pub message: String,
+    pub status: Option<u16>,
+    pub error_type: Option<String>,
+    pub code: Option<String>,
+    pub retryable: bool,
+}
+
+impl ApiError {
+    // Failure sending the request or reading the response
+    pub fn transport(error: reqwest::Error) -> Self {
+        ApiError {
+            message: format!("http error: {}", error),
+            status: error.status().map(|status| status.as_u16()),
+            error_type: None,
+            code: None,
+            retryable: error.is_timeout() || error.is_connect() || error.is_request(),
+        }
+    }
+
+    // The caller's deadline passed before a response arrived. Retrying under the
+    // same deadline can't succeed, so this is never retryable.
+    pub fn deadline_exceeded() -> Self {
+        ApiError {
+            message: "Deadline exceeded".to_string(),
+            status: None,
+            error_type: None,
+            code: Some("deadline_exceeded".to_string()),
+            retryable: false,
+        }
+    }
+
+    // Request that couldn't be built, e.g. an invalid multipart part or unreadable file
+    // An attempt cut off by the limits' timeout
+    fn timed_out(message: String) -> Self {
+        ApiError {
+            message,
+            status: None,
+            error_type: None,
+            code: None,
+            retryable: true,
+        }
+    }
+
+    pub fn local(message: String) -> Self {
+        ApiError {
+            message,
+            status: None,
+            error_type: None,
+            code: None,
+            retryable: false,
+        }
+    }
+
+    fn from_response(status: StatusCode, body: &[u8]) -> Self {
+        let error = serde_json::from_slice::<Value>(body).ok().and_then(|v| v.get("error").cloned());
+        let field = |name: &str| {
+            error
+                .as_ref()
+                .and_then(|e| e.get(name))
+                .and_then(|v| v.as_str())
+                .map(str::to_string)
+        };
+        let error_type = field("type");
+        let code = field("code");
+
+        // 429 is also returned when the account is out of quota, which won't clear up
+        let out_of_quota = error_type.as_deref() == Some("insufficient_quota") || code.as_deref() == Some("insufficient_quota");
+        let retryable = match status.as_u16() {
+            408 | 409 => true,
+            429 => !out_of_quota,
+            _ => status.is_server_error(),
+        };
+
+        ApiError {
+            message: field("message").unwrap_or_else(|| format!("{}: {}", status, String::from_utf8_lossy(body))),
+            status: Some(status.as_u16()),
+            error_type,
+            code,
+            retryable,
+        }
+    }
+
+    // Prefix the message with what was being attempted
+    pub fn context(mut self, action: &str) -> Self {
+        self.message = format!("{}: {}", action, self.message);
+        self
+    }
+}
+
+impl From<ApiError> for Error {
+    fn from(error: ApiError) -> Self {
+        Error::Term(Box::new(error))
+    }
+}
+
+impl Response {
+    pub fn json<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_json::from_slice(&self.body).map_err(|e| format!("Failed to decode response: {}", e))
+    }
+
+    pub fn metadata(&self) -> ResponseMetadata {
+        let header = |name: &str| {
+            self.headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+
+        ResponseMetadata {
+            request_id: header("x-request-id"),
+            organization: header("openai-organization"),
+            version: header("openai-version"),
+            processing_ms: header("openai-processing-ms")
+                .and_then(|ms| ms.trim().parse::<f64>().ok())
+                .map(|ms| ms.round() as u64),
+        }
+    }
+
+    // With return_headers the result becomes a {value, headers, metadata} tuple
+    pub fn attach<'a>(&self, env: Env<'a>, value: Term<'a>, selection: &Option<Vec<String>>) -> Term<'a> {
+        match selection {
+            Some(names) => (value, select_headers(&self.headers, names), self.metadata()).encode(env),
+            None => value,
+        }
+    }
+}
+
+// Sends requests with the client's config, keeping response headers that the
+// async-openai client discards
+#[derive(Clone)]
+pub struct Transport {
+    http: reqwest::Client,
+    config: OpenAIConfig,
+    deadline: Deadline,
+    // Overrides the client's `OpenAI-Beta` header, which pins Assistants v1
+    beta: Option<&'static str>,
+    retry: RetryPolicy,
+    limits: Limits,
+    stats: Arc<ClientStats>,
+    // Failures injected in place of sending, if the client was given `faults`
+    faults: Option<Faults>,
+    rate_limit: Option<RateLimit>,
+}
+
+impl Transport {
+    pub fn new(http: reqwest::Client, config: OpenAIConfig, stats: Arc<ClientStats>) -> Self {
+        Transport {
+            http,
+            config,
+            deadline: Deadline::default(),
+            beta: None,
+            retry: RetryPolicy::requests(),
+            limits: Limits::default(),
+            stats,
+            faults: None,
+            rate_limit: None,
+        }
+    }
+
+    // Per-attempt timeout and retry cap for the endpoint being called
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    // Backoff for rate limited requests
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    // Stop sending requests, including rate limit retries, once `deadline` has passed
+    pub fn with_deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn with_faults(mut self, faults: Option<Faults>) -> Self {
+        self.faults = faults;
+        self
+    }
+
+    pub fn with_rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    pub fn with_beta(mut self, beta: &'static str) -> Self {
+        self.beta = Some(beta);
+        self
+    }
+
+    pub async fn get(&self, path: &str, query: &[(&str, String)]) -> Result<Response, ApiError> {
+        self.execute(|| Ok(self.request(Method::GET, path).query(query))).await
+    }
+
+    pub async fn post_json<I: Serialize>(&self, path: &str, body: &I) -> Result<Response, ApiError> {
+        self.execute(|| Ok(self.post(path).json(body))).await
+    }
+
+    // Posts one of async-openai's request types and decodes its response type
+    pub async fn post_typed<I: Serialize, O: DeserializeOwned>(&self, path: &str, body: &I) -> Result<O, ApiError> {
+        self.post_json(path, body).await?.json().map_err(ApiError::local)
+    }
+
+    // The response is returned as soon as its status is in, for reading the body as it
+    // arrives. The limits' timeout only bounds the wait for the status.
+    pub async fn post_json_stream<I: Serialize>(&self, path: &str, body: &I) -> Result<reqwest::Response, ApiError> {
+        self.send(|| Ok(self.post(path).json(body)), true).await
+    }
+
+    // Sends `body`, already encoded as JSON, byte for byte
+    pub async fn post_raw_json(&self, path: &str, body: &str) -> Result<Response, ApiError> {
+        self.execute(|| Ok(self.post(path).header(CONTENT_TYPE, "application/json").body(body.to_string())))
+            .await
+    }
+
+    // The URL and body `post_json` would send, for inspecting a request without sending it
+    pub fn preview_json<I: Serialize>(&self, path: &str, body: &I) -> Result<(String, String), ApiError> {
+        let request = self.post(path).json(body).build().map_err(ApiError::transport)?;
+        let body = request.body().and_then(reqwest::Body::as_bytes).unwrap_or_default();
+        Ok((request.url().to_string(), String::from_utf8_lossy(body).into_owned()))
+    }
+
+    // Multipart forms can't be cloned, so every attempt builds a new one
+    pub async fn post_form(&self, path: &str, make_form: impl Fn() -> Result<Form, String>) -> Result<Response, ApiError> {
+        self.execute(|| Ok(self.post(path).multipart(make_form().map_err(ApiError::local)?)))
+            .await
+    }
+
+    fn post(&self, path: &str) -> reqwest::RequestBuilder {
+        self.request(Method::POST, path)
+    }
+
+    fn request(&self, method: Method, path: &str) -> reqwest::RequestBuilder {
+        let mut headers = self.config.headers();
+        if let Some(beta) = self.beta {
+            headers.insert("OpenAI-Beta", HeaderValue::from_static(beta));
+        }
+
+        self.http
+            .request(method, self.config.url(path))
+            .query(&self.config.query())
+            .headers(headers)
+    }
+
+    async fn execute(&self, make_request: impl Fn() -> Result<reqwest::RequestBuilder, ApiError>) -> Result<Response, ApiError> {
+        let response = self.send(make_request, false).await?;
+        let headers = response.headers().clone();
+        let _reading = self.stats.reading();
+        let body = self
+            .deadline
+            .run(response.bytes())
+            .await
+            .ok_or_else(ApiError::deadline_exceeded)?
+            .map_err(ApiError::transport)?
+            .to_vec();
+        Ok(Response { body, headers })
+    }
+
+    // Retry rate limited requests with exponential backoff, like the async-openai client.
+    // Attempts cut off by the limits' timeout are retried the same way only if the limits
+    // say so, since the API may have acted on a request whose response never arrived.
+    // A `streamed` body is read long after `send` returns, so its timeout stops at the status.
+    async fn send(&self, make_request: impl Fn() -> Result<reqwest::RequestBuilder, ApiError>, streamed: bool) -> Result<reqwest::Response, ApiError> {
+        let mut backoff = self.retry.backoff();
+        let mut retries = 0;
+
+        loop {
+            if self.deadline.passed() {
+                return Err(ApiError::deadline_exceeded());
+            }
+
+            // Attempts that would wait for a token past the deadline aren't made
+            if let Some(rate_limit) = &self.rate_limit {
+                let wait = rate_limit.reserve(|wait| self.deadline.allows(wait)).ok_or_else(ApiError::deadline_exceeded)?;
+                if !wait.is_zero() {
+                    let _queued = self.stats.queued();
+                    tokio::time::sleep(wait).await;
+                }
+            }
+
+            let mut request = make_request()?;
+            if let Some(timeout) = self.limits.timeout.filter(|_| !streamed) {
+                request = request.timeout(timeout);
+            }
+            let status_timeout = self.limits.timeout.filter(|_| streamed);
+            let response = async {
+                match status_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, request.send()).await.ok(),
+                    None => Some(request.send().await),
+                }
+            };
+            let attempt = self.stats.request();
+            let (error, retry) = match self.faults.as_ref().and_then(Faults::draw) {
+                Some(fault) => self.inject(fault).await?,
+                None => match self.deadline.run(response).await.ok_or_else(ApiError::deadline_exceeded)? {
+                    Some(Ok(response)) if response.status().is_success() => return Ok(response),
+                    Some(Ok(response)) => {
+                        let status = response.status();
+                        let body = self
+                            .deadline
+                            .run(response.bytes())
+                            .await
+                            .ok_or_else(ApiError::deadline_exceeded)?
+                            .map_err(ApiError::transport)?;
+                        let error = ApiError::from_response(status, &body);
+                        let retry = status == StatusCode::TOO_MANY_REQUESTS && error.retryable;
+                        (error, retry)
+                    },
+                    Some(Err(e)) => {
+                        let retry = e.is_timeout() && self.retries_timeouts();
+                        (ApiError::transport(e), retry)
+                    },
+                    None => (ApiError::timed_out("http error: timed out waiting for the response".to_string()), self.retries_timeouts()),
+                },
+            };
+            drop(attempt);
+
+            let allowed = self.limits.max_retries.is_none_or(|max| retries < max);
+            match backoff.next_backoff() {
+                Some(delay) if retry && allowed && self.deadline.allows(delay) => {
+                    retries += 1;
+                    let _queued = self.stats.queued();
+                    tokio::time::sleep(delay).await
+                },
+                _ => return Err(error),
+            }
+        }
+    }
+
+    fn retries_timeouts(&self) -> bool {
+        self.limits.timeout.is_some() && self.limits.retry_timeouts.unwrap_or(false)
+    }
+
+    // Fails an attempt the way the API or the connection would, and says whether it is
+    // retried like the real failure. A timeout takes the attempt's timeout to fail.
+    async fn inject(&self, fault: Fault) -> Result<(ApiError, bool), ApiError> {
+        if let Some((status, body)) = fault.response() {
+            let error = ApiError::from_response(status, body.as_bytes());
+            let retry = status == StatusCode::TOO_MANY_REQUESTS && error.retryable;
+            return Ok((error, retry));
+        }
+        if let Some(timeout) = self.limits.timeout {
+            self.deadline.run(tokio::time::sleep(timeout)).await.ok_or_else(ApiError::deadline_exceeded)?;
+        }
+        Ok((ApiError::timed_out(Fault::timeout_message()), self.retries_timeouts()))
+    }
+}
+
+// Decode `return_headers`: true for the default selection, or a list of header names
+pub fn decode_header_selection(opts: &Opts) -> NifResult<Option<Vec<String>>> {
+    let term = match opts.get("return_headers") {
+        Some(term) => *term,
+        None => return Ok(None),
+    };
+
+    if let Ok(enabled) = term.decode::<bool>() {
+        return Ok(enabled.then(|| DEFAULT_HEADERS.iter().map(|h| h.to_string()).collect()));
+    }
+    if term.is_atom() {
+        // nil
+        return Ok(None);
+    }
+
+    options::decode_at::<Vec<String>>(term, &options::path("return_headers"), "boolean or list of strings")
+        .map(|names| Some(names.into_iter().map(|name| name.to_lowercase()).collect()))
+}
+
+// Splits the complete server-sent events off the front of `buffer` and returns their
+// data, leaving a partial event in the buffer until the rest arrives. Carriage returns
+// must already be removed, since events are separated by a blank line that may use CRLF.
+pub fn take_event_data(buffer: &mut Vec<u8>) -> Vec<String> {
+    let mut events = Vec::new();
+    while let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+        let event: Vec<u8> = buffer.drain(..end + 2).collect();
+        let data = String::from_utf8_lossy(&event)
+            .lines()
+            .filter_map(|line| line.strip_prefix("data:"))
+            .map(str::trim_start)
+            .collect::<Vec<_>>()
+            .join("\n");
+        if !data.is_empty() {
+            events.push(data);
+        }
+    }
+    events
+}
+
+fn select_headers(headers: &HeaderMap, names: &[String]) -> HashMap<String, String> {
+    names
+        .iter()
+        .filter_map(|name| {
+            let value = headers.get(name.as_str())?.to_str().ok()?;
+            Some((name.clone(), value.to_string()))
+        })
+        .collect()
+}
@@ -0,0 +1,189 @@
+use base64::Engine;
+use rustler::{Env, Error, LocalPid, NifMap, NifResult, ResourceArc, Term};
+use serde_json::{json, Map, Value};
+
+use crate::deadline::Deadline;
+use crate::http::{self, ApiError};
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::output::Output;
+use crate::{atoms, OpenAIClientResource};
+
+// The API sends at most three partial images before the final one
+const MAX_PARTIAL_IMAGES: usize = 3;
+
+#[derive(NifMap)]
+struct GeneratedImage<'a> {
+    // A binary, or its base64 or file path with `output`
+    image: Term<'a>,
+    // The prompt DALL·E 3 rewrote the request into; nil for models that use it as given
+    revised_prompt: Option<String>,
+}
+
+// Settings shared by streamed and one-shot generation. Options the model doesn't
+// support are rejected by the API.
+fn decode_request(prompt: String, opts: &Opts) -> NifResult<Map<String, Value>> {
+    let model = options::get_string(opts, "model")?.unwrap_or_else(|| "gpt-image-1".to_string());
+
+    let mut body = Map::new();
+    body.insert("model".to_string(), json!(model));
+    body.insert("prompt".to_string(), json!(prompt));
+    for key in ["size", "quality", "style", "background", "output_format", "moderation", "user"] {
+        if let Some(value) = options::get_string(opts, key)? {
+            body.insert(key.to_string(), json!(value));
+        }
+    }
+    if let Some(compression) = options::get_usize(opts, "output_compression")? {
+        if compression > 100 {
+            return Err(options::invalid(&options::path("output_compression"), format!("must be at most 100, got {}", compression)));
+        }
+        body.insert("output_compression".to_string(), json!(compression));
+    }
+
+    Ok(body)
+}
+
+// Decodes the complete server-sent events at the front of `buffer`
+fn take_events(buffer: &mut Vec<u8>) -> Result<Vec<Value>, ApiError> {
+    http::take_event_data(buffer)
+        .into_iter()
+        .filter(|data| data != "[DONE]")
+        .map(|data| {
+            serde_json::from_str(&data)
+                .map_err(|e| ApiError::local(format!("Failed to decode image stream event: {}", e)))
+        })
+        .collect()
+}
+
+// The API always sends base64; it is decoded even for `output: :base64` to check it
+fn image_term<'a>(env: Env<'a>, event: &Value, output: Output, extension: &str) -> Result<Term<'a>, ApiError> {
+    let payload = event
+        .get("b64_json")
+        .and_then(Value::as_str)
+        .ok_or_else(|| ApiError::local("Image stream event has no image".to_string()))?;
+    let image = base64::engine::general_purpose::STANDARD
+        .decode(payload)
+        .map_err(|e| ApiError::local(format!("Invalid image payload: {}", e)))?;
+    output.encode(env, &image, extension).map_err(ApiError::local)
+}
+
+// File extension for `output: :file`
+fn extension(body: &Map<String, Value>) -> String {
+    body.get("output_format").and_then(Value::as_str).unwrap_or("png").to_string()
+}
+
+// Generates an image, sending each partial image to `pid` as it is rendered so a
+// preview can be shown before the final image is done. Sends, all tagged with `ref_term`:
+//
+//   {:image_partial, index, image, ref}  for every partial image, in order
+//   {:image_done, image, ref} or {:stream_error, error, ref} at the end
+#[rustler::nif(schedule = "DirtyIo")]
+fn stream_image(env: Env, client_resource: ResourceArc<OpenAIClientResource>, prompt: String, opts: Opts, pid: LocalPid, ref_term: Term) -> NifResult<rustler::Atom> {
+    let partial_images = options::get_usize(&opts, "partial_images")?.unwrap_or(2);
+    if partial_images > MAX_PARTIAL_IMAGES {
+        return Err(options::invalid(
+            &options::path("partial_images"),
+            format!("must be at most {}, got {}", MAX_PARTIAL_IMAGES, partial_images),
+        ));
+    }
+
+    let mut body = decode_request(prompt, &opts)?;
+    body.insert("stream".to_string(), json!(true));
+    body.insert("partial_images".to_string(), json!(partial_images));
+    let deadline = Deadline::decode(&opts)?;
+    let transport = client_resource.transport_for(Endpoint::Other, &opts)?.with_deadline(deadline);
+    let output = Output::decode(&opts)?;
+    let extension = extension(&body);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let _stream = client_resource.stats.stream();
+    let result: Result<(), ApiError> = runtime.block_on(async {
+        let mut response = transport
+            .post_json_stream("/images/generations", &body)
+            .await
+            .map_err(|e| e.context("Image generation failed"))?;
+
+        let mut buffer = Vec::new();
+        loop {
+            let chunk = deadline
+                .run(response.chunk())
+                .await
+                .ok_or_else(ApiError::deadline_exceeded)?
+                .map_err(ApiError::transport)?;
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None => return Err(ApiError::local("Image stream ended before the image was completed".to_string())),
+            };
+            // Events are separated by a blank line, which may use CRLF
+            buffer.extend(chunk.iter().filter(|byte| **byte != b'\r'));
+
+            for event in take_events(&mut buffer)? {
+                match event.get("type").and_then(Value::as_str) {
+                    Some("image_generation.partial_image") => {
+                        let index = event.get("partial_image_index").and_then(Value::as_u64).unwrap_or(0);
+                        let image = image_term(env, &event, output, &extension)?;
+                        let _ = env.send(&pid, (atoms::image_partial(), index, image, ref_term));
+                    },
+                    Some("image_generation.completed") => {
+                        let image = image_term(env, &event, output, &extension)?;
+                        let _ = env.send(&pid, (atoms::image_done(), image, ref_term));
+                        return Ok(());
+                    },
+                    _ => {
+                        if let Some(error) = event.get("error") {
+                            let message = error.get("message").and_then(Value::as_str).unwrap_or("unknown error");
+                            return Err(ApiError::local(format!("Image generation failed: {}", message)));
+                        }
+                    },
+                }
+            }
+        }
+    });
+
+    if let Err(error) = result {
+        let _ = env.send(&pid, (atoms::stream_error(), error, ref_term));
+    }
+
+    Ok(atoms::ok())
+}
+
+// Generates `n` images in one request, each with the prompt the model actually used
+#[rustler::nif(schedule = "DirtyIo")]
+fn generate_image<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, prompt: String, opts: Opts<'a>) -> NifResult<Vec<GeneratedImage<'a>>> {
+    let mut body = decode_request(prompt, &opts)?;
+    if let Some(n) = options::get_usize(&opts, "n")? {
+        body.insert("n".to_string(), json!(n));
+    }
+    // DALL·E returns URLs by default; gpt-image-1 only returns base64 and rejects the option
+    if body["model"].as_str().is_some_and(|model| model.starts_with("dall-e")) {
+        body.insert("response_format".to_string(), json!("b64_json"));
+    }
+    let transport = client_resource.transport_for(Endpoint::Other, &opts)?.with_deadline(Deadline::decode(&opts)?);
+    let output = Output::decode(&opts)?;
+    let extension = extension(&body);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let response: Value = runtime
+        .block_on(transport.post_json("/images/generations", &body))
+        .map_err(|e| e.context("Image generation failed"))?
+        .json()
+        .map_err(|e| Error::Term(Box::new(e)))?;
+
+    response
+        .get("data")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|data| {
+            Ok(GeneratedImage {
+                image: image_term(env, data, output, &extension)?,
+                revised_prompt: data.get("revised_prompt").and_then(Value::as_str).map(str::to_string),
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use regex::Regex;
+use rustler::{NifMap, NifResult};
+
+use crate::options::{self, Opts};
+
+const DEFAULT_THRESHOLD: f32 = 0.5;
+
+// Category, how strongly a match suggests an injection, and the patterns that match it.
+// Patterns are case-insensitive and aimed at phrasings seen in real attacks; they are
+// heuristics, so benign text can match and reworded attacks won't.
+const RULES: &[(&str, f64, &[&str])] = &[
+    (
+        "instruction_override",
+        0.6,
+        &[
+            r"\b(?:ignore|disregard|forget|override|skip)\s+(?:all\s+|any\s+)?(?:of\s+)?(?:the\s+|your\s+|my\s+)?(?:previous|prior|above|earlier|preceding|original|initial|system)\s+(?:instructions?|prompts?|rules|directions|guidelines|context|messages?)",
+            r"\bnew\s+(?:instructions?|rules|task)\s*:",
+            r"\bfrom\s+now\s+on,?\s+you\s+(?:will|must|are|shall)\b",
+            r"\bdo\s+not\s+follow\s+(?:the\s+|your\s+)?(?:previous|prior|above|system)\s+(?:instructions?|rules)",
+        ],
+    ),
+    (
+        "role_override",
+        0.4,
+        &[
+            r"\byou\s+are\s+(?:now|no\s+longer)\s+(?:an?\s+|in\s+)?(?:DAN|unrestricted|unfiltered|jailbroken|evil|developer\s+mode|bound\s+by)",
+            r"\b(?:act|behave|respond)\s+as\s+(?:an?\s+)?(?:unrestricted|unfiltered|uncensored|jailbroken)",
+            r"\b(?:developer|god|jailbreak)\s+mode\b",
+            r"\bdo\s+anything\s+now\b",
+            r"\bwithout\s+(?:any\s+)?(?:restrictions|filters|guidelines|limitations)\b",
+        ],
+    ),
+    (
+        "prompt_leak",
+        0.5,
+        &[
+            r"\b(?:reveal|show|print|repeat|output|display|tell\s+me|leak|dump)\s+(?:me\s+)?(?:your|the)\s+(?:full\s+|entire\s+|hidden\s+|initial\s+|original\s+)?(?:system\s+prompt|system\s+message|instructions|initial\s+prompt|hidden\s+prompt|prompt)",
+            r"\bwhat\s+(?:is|are|was|were)\s+your\s+(?:system\s+prompt|initial\s+instructions|original\s+instructions)",
+        ],
+    ),
+    (
+        "exfiltration",
+        0.6,
+        // Markdown images load as soon as they are rendered, sending their query string.
+        // Requests to send data count only when they name where to, to spare ordinary mail.
+        &[
+            r"!\[[^\]]*\]\(\s*https?://[^)\s]*\?[^)\s]*=[^)]*\)",
+            r"\b(?:send|post|upload|forward|transmit|leak|exfiltrate)\s+(?:this|the|all|any|every|our|your)?\s*(?:conversation|chat\s+history|messages|data|context|secrets?|credentials|api\s+keys?|passwords?|emails?)\s+(?:to|into)\s+(?:https?://|[\w.+-]+@[\w-]+\.|this\s+(?:url|address|link)|the\s+following\s+(?:url|address|link))",
+            r"https?://[^\s)]*\{[^}\s]*\}",
+        ],
+    ),
+    (
+        "fake_delimiter",
+        0.5,
+        // Chat template tokens and role headers that try to start a new turn
+        &[
+            r"<\|(?:im_start|im_end|system|endoftext|start_header_id|end_header_id|eot_id)\|>",
+            r"\[/?(?:INST|SYS)\]|<</?SYS>>",
+            r"(?m)^\s*(?:#{1,6}\s*)?(?:system|assistant)\s*(?:prompt|message)?\s*:",
+            r"</?(?:system|assistant|instructions?)>",
+        ],
+    ),
+    (
+        // Zero-width, bidirectional override and Unicode tag characters hide text from human reviewers
+        "hidden_text",
+        0.3,
+        &[r"[\u{200B}-\u{200D}\u{2060}\u{FEFF}\u{202A}-\u{202E}\u{2066}-\u{2069}\u{E0000}-\u{E007F}]+"],
+    ),
+    (
+        "encoded_payload",
+        0.2,
+        &[r"\b[A-Za-z0-9+/]{120,}={0,2}", r"(?:\\x[0-9a-fA-F]{2}){16,}"],
+    ),
+];
+
+#[derive(NifMap)]
+struct Finding {
+    category: String,
+    // The matched text, and its byte offsets in the scanned text
+    text: String,
+    start: usize,
+    end: usize,
+}
+
+#[derive(NifMap)]
+struct InjectionScan {
+    flagged: bool,
+    // Chance-like combination of the category scores, from 0 to 1
+    score: f64,
+    // Category name to its weight, for categories with at least one finding
+    category_scores: HashMap<String, f64>,
+    findings: Vec<Finding>,
+}
+
+fn rules() -> &'static [(&'static str, f64, Vec<Regex>)] {
+    static RE: OnceLock<Vec<(&'static str, f64, Vec<Regex>)>> = OnceLock::new();
+    RE.get_or_init(|| {
+        RULES
+            .iter()
+            .map(|(category, weight, patterns)| {
+                let patterns = patterns.iter().map(|pattern| Regex::new(&format!("(?i){}", pattern)).unwrap()).collect();
+                (*category, *weight, patterns)
+            })
+            .collect()
+    })
+}
+
+fn scan(text: &str, threshold: f32) -> InjectionScan {
+    let mut findings = Vec::new();
+    let mut category_scores = HashMap::new();
+    for (category, weight, patterns) in rules() {
+        for pattern in patterns {
+            for found in pattern.find_iter(text) {
+                category_scores.insert(category.to_string(), *weight);
+                findings.push(Finding { category: category.to_string(), text: found.as_str().to_string(), start: found.start(), end: found.end() });
+            }
+        }
+    }
+    findings.sort_by_key(|finding| (finding.start, finding.end));
+
+    // Each category counts once, however often it matches, and independent signals add up
+    let score = 1.0 - category_scores.values().map(|weight| 1.0 - weight).product::<f64>();
+    InjectionScan { flagged: score as f32 >= threshold, score, category_scores, findings }
+}
+
+// Flags common prompt-injection patterns in untrusted text, such as user input or
+// retrieved documents, before it reaches the model. Runs locally, without a request.
+#[rustler::nif(schedule = "DirtyCpu")]
+fn scan_prompt_injection(texts: Vec<String>, opts: Opts) -> NifResult<Vec<InjectionScan>> {
+    let threshold = options::get_f32(&opts, "threshold")?.unwrap_or(DEFAULT_THRESHOLD);
+    if !(0.0..=1.0).contains(&threshold) {
+        return Err(options::invalid(&options::path("threshold"), format!("must be between 0 and 1, got {}", threshold)));
+    }
+    Ok(texts.iter().map(|text| scan(text, threshold)).collect())
+}
@@ -0,0 +1,130 @@
+use rustler::types::map::MapIterator;
+use rustler::{Encoder, Env, NifResult, Term, TermType};
+use serde_json::Value;
+
+use crate::options::{self, Opts};
+
+// Floats above this can't hold every integer, so an integral float this large has
+// likely lost digits, e.g. an id too long for u64 that was parsed as a float
+const MAX_EXACT_FLOAT: f64 = 9_007_199_254_740_992.0;
+
+// How JSON numbers that don't fit i64 or u64 are decoded. Lossy turns them into the
+// nearest float; strict fails instead, for gateways whose ids or counts overflow.
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum Numbers {
+    #[default]
+    Lossy,
+    Strict,
+}
+
+impl Numbers {
+    pub fn decode(opts: &Opts) -> NifResult<Self> {
+        match options::get_atom(opts, "json_numbers")?.as_deref() {
+            None | Some("lossy") => Ok(Numbers::Lossy),
+            Some("strict") => Ok(Numbers::Strict),
+            Some(other) => Err(options::invalid(&options::path("json_numbers"), format!("must be :lossy or :strict, got :{}", other))),
+        }
+    }
+
+    // Fails on the first number in `value` that strict mode rejects, naming its path
+    pub fn check(self, value: &Value) -> Result<(), String> {
+        if self == Numbers::Strict {
+            check_exact(value, "$")?;
+        }
+        Ok(())
+    }
+}
+
+fn check_exact(value: &Value, path: &str) -> Result<(), String> {
+    match value {
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() >= MAX_EXACT_FLOAT => {
+                Err(format!("JSON number at {} can't be represented exactly: {}", path, n))
+            },
+            _ => Ok(()),
+        },
+        Value::Array(items) => items
+            .iter()
+            .enumerate()
+            .try_for_each(|(i, item)| check_exact(item, &format!("{}[{}]", path, i))),
+        Value::Object(fields) => fields
+            .iter()
+            .try_for_each(|(key, field)| check_exact(field, &format!("{}.{}", path, key))),
+        _ => Ok(()),
+    }
+}
+
+// Convert a JSON value into the equivalent Elixir term: objects become maps with
+// string keys, arrays become lists and null becomes nil. Integers in i64 or u64
+// range stay integers; see Numbers for larger ones.
+pub fn to_term<'a>(env: Env<'a>, value: &Value) -> Term<'a> {
+    match value {
+        Value::Null => rustler::types::atom::nil().encode(env),
+        Value::Bool(b) => b.encode(env),
+        Value::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                i.encode(env)
+            } else if let Some(u) = n.as_u64() {
+                u.encode(env)
+            } else {
+                n.as_f64().unwrap_or_default().encode(env)
+            }
+        },
+        Value::String(s) => s.encode(env),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| to_term(env, item))
+            .collect::<Vec<_>>()
+            .encode(env),
+        Value::Object(fields) => {
+            let (keys, values): (Vec<Term>, Vec<Term>) = fields
+                .iter()
+                .map(|(k, v)| (k.encode(env), to_term(env, v)))
+                .unzip();
+            Term::map_from_term_arrays(env, &keys, &values)
+                .unwrap_or_else(|_| rustler::types::map::map_new(env))
+        },
+    }
+}
+
+// Convert an Elixir term into JSON, e.g. a schema written as an Elixir map. Atom
+// keys and values become strings, except true, false and nil.
+pub fn from_term(term: Term) -> Result<Value, String> {
+    match term.get_type() {
+        TermType::Atom => {
+            let atom = term.atom_to_string().map_err(|e| format!("{:?}", e))?;
+            Ok(match atom.as_str() {
+                "nil" => Value::Null,
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ => Value::String(atom),
+            })
+        },
+        TermType::Binary => term.decode::<String>().map(Value::String).map_err(|_| "Binaries must be UTF-8 strings".to_string()),
+        TermType::Integer => term
+            .decode::<i64>()
+            .map(Value::from)
+            .or_else(|_| term.decode::<u64>().map(Value::from))
+            .map_err(|_| "Integers must fit in 64 bits".to_string()),
+        TermType::Float => term.decode::<f64>().map(Value::from).map_err(|e| format!("{:?}", e)),
+        TermType::List => term
+            .decode::<Vec<Term>>()
+            .map_err(|e| format!("{:?}", e))?
+            .into_iter()
+            .map(from_term)
+            .collect::<Result<Vec<_>, _>>()
+            .map(Value::Array),
+        TermType::Map => {
+            let mut object = serde_json::Map::new();
+            for (key, value) in term.decode::<MapIterator>().map_err(|e| format!("{:?}", e))? {
+                let key = match from_term(key)? {
+                    Value::String(key) => key,
+                    other => other.to_string(),
+                };
+                object.insert(key, from_term(value)?);
+            }
+            Ok(Value::Object(object))
+        },
+        other => Err(format!("Can't convert {:?} to JSON", other)),
+    }
+}
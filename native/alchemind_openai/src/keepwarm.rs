@@ -0,0 +1,119 @@
+use std::time::{Duration, Instant};
+
+use async_openai::error::OpenAIError;
+use futures_util::StreamExt;
+use rustler::NifResult;
+
+use crate::chunks::ChunkStream;
+use crate::options::{self, Opts};
+
+// Starts the message of the error a stream fails with when it went quiet and was most
+// likely closed by a proxy, so the error can be told apart from others
+const PROXY_IDLE: &str = "Stream idle";
+
+// Keeps streams alive through proxies that close SSE connections left idle while the
+// model thinks, and recognizes when one was closed anyway
+#[derive(Clone, Copy)]
+pub struct KeepWarm {
+    // How often idle connections are probed, with TCP keepalives and HTTP/2 pings
+    ping_interval: Option<Duration>,
+    // How long a stream may go without an event before it is given up on
+    idle_timeout: Option<Duration>,
+    // Whether events whose data isn't JSON at all, such as a proxy's own pings, are
+    // skipped instead of failing the stream. SSE comments and lines other than `data:`
+    // never reach here, and JSON that isn't a chunk always fails it, since that is
+    // most likely an error from the API.
+    tolerate_proxy_comments: bool,
+}
+
+impl KeepWarm {
+    // None when no option is given, which leaves streams as they were
+    pub fn decode(opts: &Opts) -> NifResult<Option<Self>> {
+        if opts.is_empty() {
+            return Ok(None);
+        }
+        let millis = |key: &str| -> NifResult<Option<Duration>> {
+            match options::get_usize(opts, key)? {
+                Some(0) => Err(options::invalid(&options::path(key), "must be positive")),
+                ms => Ok(ms.map(|ms| Duration::from_millis(ms as u64))),
+            }
+        };
+        Ok(Some(KeepWarm {
+            ping_interval: millis("ping_interval_ms")?,
+            idle_timeout: millis("idle_timeout_ms")?,
+            tolerate_proxy_comments: options::get_bool(opts, "tolerate_proxy_comments")?.unwrap_or(false),
+        }))
+    }
+
+    // The HTTP client for a client with these settings. Pings only keep the connection
+    // itself alive; a proxy that closes streams with no events still can.
+    pub fn http_client(keep_warm: Option<Self>) -> NifResult<reqwest::Client> {
+        let Some(interval) = keep_warm.and_then(|keep_warm| keep_warm.ping_interval) else {
+            return Ok(reqwest::Client::new());
+        };
+        reqwest::Client::builder()
+            .tcp_keepalive(interval)
+            .http2_keep_alive_interval(interval)
+            .http2_keep_alive_while_idle(true)
+            .build()
+            .map_err(|e| rustler::Error::Term(Box::new(format!("Failed to build HTTP client: {}", e))))
+    }
+
+    // Wraps `stream` so it skips tolerated events and fails with a proxy idle error when
+    // no event arrives within the idle timeout, or the connection drops after the
+    // stream was quiet for longer than the ping interval
+    pub fn watch(self, stream: ChunkStream) -> ChunkStream {
+        futures_util::stream::unfold((stream, Instant::now(), false), move |(mut stream, mut last_event, failed)| async move {
+            if failed {
+                return None;
+            }
+            loop {
+                let next = match self.idle_timeout {
+                    Some(idle_timeout) => match tokio::time::timeout(idle_timeout, stream.next()).await {
+                        Ok(next) => next,
+                        Err(_) => return Some((Err(proxy_idle(idle_timeout)), (stream, last_event, true))),
+                    },
+                    None => stream.next().await,
+                };
+                let quiet = last_event.elapsed();
+                match next {
+                    // The event still shows the connection is alive
+                    Some(Err(OpenAIError::JSONDeserialize(e))) if self.tolerate_proxy_comments && not_json(&e) => last_event = Instant::now(),
+                    Some(Err(e)) if dropped(&e) && self.ping_interval.is_some_and(|interval| quiet >= interval) => {
+                        return Some((Err(proxy_idle(quiet)), (stream, last_event, true)));
+                    },
+                    Some(item) => return Some((item, (stream, Instant::now(), false))),
+                    None => return None,
+                }
+            }
+        })
+        .boxed()
+    }
+}
+
+// Whether an event's data failed to parse at its very first character, so it was never
+// JSON, rather than being a chunk or error that didn't parse
+fn not_json(error: &serde_json::Error) -> bool {
+    error.is_syntax() && error.line() == 1 && error.column() <= 1
+}
+
+fn proxy_idle(quiet: Duration) -> OpenAIError {
+    OpenAIError::StreamError(format!(
+        "{} for {}ms, most likely closed by a proxy; consider a shorter keep_warm ping_interval_ms",
+        PROXY_IDLE,
+        quiet.as_millis()
+    ))
+}
+
+// Whether the connection failed, rather than the API returning an error
+fn dropped(error: &OpenAIError) -> bool {
+    match error {
+        OpenAIError::Reqwest(_) => true,
+        OpenAIError::StreamError(message) => !message.starts_with("Invalid header value"),
+        _ => false,
+    }
+}
+
+pub fn is_proxy_idle(error: &OpenAIError) -> bool {
+    matches!(error, OpenAIError::StreamError(message) if message.starts_with(PROXY_IDLE))
+}
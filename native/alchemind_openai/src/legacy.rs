@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use crate::ToolCall;
+
+// Legacy function calls have no id. Tool messages answering one still need a
+// tool_call_id, which is mapped back to the function's name when sent.
+const CALL_ID: &str = "function_call";
+
+// Rewrites a chat request for backends that only know the deprecated function calling
+// API: `tools` become `functions`, `tool_choice` becomes `function_call`, assistant tool
+// calls become a `function_call` and tool messages become `function` messages. The
+// legacy API has one call per assistant message.
+pub fn downgrade(body: &mut Value) -> Result<(), String> {
+    let Some(request) = body.as_object_mut() else {
+        return Ok(());
+    };
+    if let Some(tools) = request.remove("tools") {
+        let functions = tools.as_array().into_iter().flatten().map(|tool| tool["function"].clone()).collect();
+        request.insert("functions".to_string(), Value::Array(functions));
+    }
+    // "none" and "auto" are spelled the same; a named tool becomes {"name": ...}
+    if let Some(choice) = request.remove("tool_choice") {
+        let call = match choice {
+            Value::String(mode) => Value::String(mode),
+            named => json!({"name": named["function"]["name"]}),
+        };
+        request.insert("function_call".to_string(), call);
+    }
+
+    let mut names: HashMap<Value, Value> = HashMap::new();
+    for (index, message) in request.get_mut("messages").and_then(Value::as_array_mut).into_iter().flatten().enumerate() {
+        let Some(message) = message.as_object_mut() else {
+            continue;
+        };
+        if let Some(calls) = message.remove("tool_calls") {
+            let calls = calls.as_array().cloned().unwrap_or_default();
+            if calls.len() > 1 {
+                return Err(format!(
+                    "messages[{}]: legacy function calling allows one call per assistant message, got {}",
+                    index,
+                    calls.len()
+                ));
+            }
+            for call in calls {
+                names.insert(call["id"].clone(), call["function"]["name"].clone());
+                message.insert("function_call".to_string(), call["function"].clone());
+            }
+        }
+        if message.get("role").and_then(Value::as_str) == Some("tool") {
+            let id = message.remove("tool_call_id").unwrap_or_default();
+            message.insert("role".to_string(), json!("function"));
+            message.insert("name".to_string(), names.get(&id).cloned().unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+// The `function_call` of the raw response's choice `index`, as a tool call. Backends
+// return it with or without `legacy_functions`, and async-openai only has it deprecated.
+pub fn call(raw: &Value, index: usize) -> Option<ToolCall> {
+    let call = &raw["choices"][index]["message"]["function_call"];
+    Some(ToolCall {
+        id: CALL_ID.to_string(),
+        name: call["name"].as_str()?.to_string(),
+        arguments: call["arguments"].as_str().unwrap_or_default().to_string(),
+    })
+}
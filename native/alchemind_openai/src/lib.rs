@@ -1,487 +1,1221 @@
-use rustler::{Env, Error, NifResult, NifStruct, ResourceArc, Term};
+use rustler::{Encoder, Env, Error, NifMap, NifResult, NifStruct, ResourceArc, Term};
+use reqwest::multipart::Form;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use serde::{Deserialize, Serialize};
 
 use async_openai::{
     config::OpenAIConfig,
-    types::{ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, 
-            CreateTranscriptionRequestArgs, CreateSpeechRequestArgs, SpeechModel, Voice, AudioInput, AudioResponseFormat},
+    types::{ChatChoice, ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage, ChatCompletionRequestFunctionMessage, ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart,
+            ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText, ChatCompletionRequestSystemMessage,
+            ChatCompletionRequestToolMessage, ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent, ChatCompletionToolType, FinishReason, CreateChatCompletionRequest,
+            CreateChatCompletionRequestArgs, CreateChatCompletionResponse, CreateSpeechRequest, FunctionCall, ImageUrl, ImageUrlDetail, Role, SpeechResponseFormat},
     Client as OpenAIClient,
 };
 use std::collections::HashMap;
 // Used for the StreamExt trait which provides the next() method for async streams
 use futures_util::StreamExt;
 
+mod assistants;
+mod attribution;
+mod audio;
+mod cancel;
+mod capabilities;
+mod chunking;
+mod chunks;
+mod compare;
+mod continuation;
+mod deadline;
+mod default_client;
+mod diarize;
+mod embeddings;
+mod eval;
+mod faults;
+mod fewshot;
+mod http;
+mod images;
+mod injection;
+mod json;
+mod keepwarm;
+mod legacy;
+mod limits;
+mod locale;
+mod moderation;
+mod ocr;
+mod options;
+mod output;
+mod params;
+mod postprocess;
+mod prompts;
+mod ratelimit;
+mod redact;
+mod rerank;
+mod resample;
+mod retry;
+mod similarity;
+mod stats;
+mod staging;
+mod streaming;
+mod summarize;
+mod tokens;
+mod tools;
+mod transcription;
+mod upload;
+mod vectors;
+mod voice;
+
+use options::Opts;
+use postprocess::PostProcessor;
+
 // Define the resource struct that will be accessible from Elixir
 pub struct OpenAIClientResource {
     client: Arc<Mutex<OpenAIClient<OpenAIConfig>>>,
+    // Post-processors applied, in order, to completion content before it is returned
+    post_processors: Mutex<Vec<PostProcessor>>,
+    // Shares the client's connection pool for requests whose headers are needed
+    transport: Mutex<http::Transport>,
+    // Backoff before a stream's first chunk; requests use the transport's policy
+    stream_retry: Mutex<retry::RetryPolicy>,
+    // Timeouts and retry caps for requests sent through the transport
+    limits: Mutex<limits::EndpointLimits>,
+    // Prompts calls refer to with `system_prompt`, by id
+    system_prompts: Mutex<prompts::SystemPrompts>,
+    // Shared with the transport, which counts the requests it sends
+    stats: Arc<stats::ClientStats>,
+    // Marker labelling generated text and audio, if the client was given a template
+    attribution: Mutex<Option<attribution::Attribution>>,
+    // How streams are kept alive through proxies, if the client was given `keep_warm`
+    keep_warm: Option<keepwarm::KeepWarm>,
+    // Failures injected into requests and streams, if the client was given `faults`
+    faults: Mutex<Option<faults::Faults>>,
+    // Rate requests and streams are held to, if the client was given `rate_limit`
+    rate_limit: Mutex<Option<ratelimit::RateLimit>>,
 }
 
-// Register the resource type with Rustler at the top level - Reverted, moved back to on_load
-// rustler::resource!(OpenAIClientResource, env);
+impl rustler::Resource for OpenAIClientResource {}
+
+// A function call the model asked for. Assistant messages send them back so the
+// `tool` messages answering them follow the call in the conversation.
+#[derive(Debug, Clone, NifMap, Serialize, Deserialize)]
+struct ToolCall {
+    id: String,
+    name: String,
+    // JSON-encoded, as generated by the model; not guaranteed to be valid
+    arguments: String,
+}
+
+impl From<&ChatCompletionMessageToolCall> for ToolCall {
+    fn from(call: &ChatCompletionMessageToolCall) -> Self {
+        ToolCall { id: call.id.clone(), name: call.function.name.clone(), arguments: call.function.arguments.clone() }
+    }
+}
 
 #[derive(Debug, NifStruct, Serialize, Deserialize)]
 #[module = "Alchemind.OpenAI.Message"]
 struct Message {
     role: String,
     content: String,
+    // Participant name, or the function name of a `function` message
+    name: Option<String>,
+    // The call a `tool` message answers
+    tool_call_id: Option<String>,
+    // Calls made by an `assistant` message
+    tool_calls: Option<Vec<ToolCall>>,
+    // Text and image parts, for content given as a list; `content` then holds the text
+    parts: Option<Vec<ContentPart>>,
+}
+
+impl Message {
+    fn new(role: &str, content: String) -> Self {
+        Message { role: role.to_string(), content, name: None, tool_call_id: None, tool_calls: None, parts: None }
+    }
+}
+
+// One part of a message's content: `text`, an `image_url` or raw `image` data, the
+// latter two with their `detail`
+#[derive(Debug, Clone, NifMap, Serialize, Deserialize)]
+struct ContentPart {
+    kind: String,
+    text: Option<String>,
+    url: Option<String>,
+    data: Option<ImageData>,
+    detail: Option<String>,
+}
+
+// Image bytes passed as a binary. They are encoded into a data URL here, so callers
+// don't copy the larger base64 text into the NIF.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ImageData(Vec<u8>);
+
+impl<'a> rustler::Decoder<'a> for ImageData {
+    fn decode(term: Term<'a>) -> NifResult<Self> {
+        Ok(ImageData(term.decode::<rustler::Binary>()?.as_slice().to_vec()))
+    }
+}
+
+impl Encoder for ImageData {
+    fn encode<'a>(&self, env: Env<'a>) -> Term<'a> {
+        let mut binary = rustler::NewBinary::new(env, self.0.len());
+        binary.as_mut_slice().copy_from_slice(&self.0);
+        rustler::Binary::from(binary).encode(env)
+    }
+}
+
+impl ContentPart {
+    fn is_image(&self) -> bool {
+        matches!(self.kind.as_str(), "image_url" | "image")
+    }
+
+    // `path` locates the part in errors, e.g. `messages[1].content[0]`
+    fn to_request_part(&self, path: &str) -> Result<ChatCompletionRequestMessageContentPart, String> {
+        match self.kind.as_str() {
+            "text" => Ok(ChatCompletionRequestMessageContentPart::Text(ChatCompletionRequestMessageContentPartText {
+                r#type: "text".to_string(),
+                text: self.text.clone().ok_or_else(|| format!("{}: text parts require text", path))?,
+            })),
+            "image_url" | "image" => {
+                let url = if self.kind == "image" {
+                    let data = self.data.as_ref().ok_or_else(|| format!("{}: image parts require data", path))?;
+                    ocr::data_url(&data.0).map_err(|e| format!("{}: {}", path, e))?
+                } else {
+                    self.url.clone().ok_or_else(|| format!("{}: image_url parts require a url", path))?
+                };
+                let detail = match self.detail.as_deref() {
+                    None | Some("auto") => ImageUrlDetail::Auto,
+                    Some("low") => ImageUrlDetail::Low,
+                    Some("high") => ImageUrlDetail::High,
+                    Some(other) => return Err(format!("{}: detail must be \"auto\", \"low\" or \"high\", got {:?}", path, other)),
+                };
+                Ok(ChatCompletionRequestMessageContentPart::Image(ChatCompletionRequestMessageContentPartImage {
+                    r#type: "image_url".to_string(),
+                    image_url: ImageUrl { url, detail },
+                }))
+            },
+            other => Err(format!("{}: unknown content part type {:?}", path, other)),
+        }
+    }
+}
+
+#[derive(NifMap)]
+struct Usage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    total_tokens: u64,
+    // Hidden tokens a reasoning model spent thinking, included in completion_tokens;
+    // nil for other models
+    reasoning_tokens: Option<u64>,
+    // How many tokens of a `prediction` the output used, and how many it didn't, which
+    // are still billed; nil without a prediction
+    accepted_prediction_tokens: Option<u64>,
+    rejected_prediction_tokens: Option<u64>,
+}
+
+#[derive(NifMap)]
+struct TopLogprob {
+    token: String,
+    logprob: f64,
+    // UTF-8 bytes of the token, for tokens that split a character; nil when the API has none
+    bytes: Option<Vec<u8>>,
+}
+
+#[derive(NifMap)]
+struct TokenLogprob {
+    token: String,
+    logprob: f64,
+    bytes: Option<Vec<u8>>,
+    // The most likely tokens at this position, up to `top_logprobs` of them
+    top_logprobs: Vec<TopLogprob>,
+}
+
+// What `dry_run: true` returns instead of sending the request
+#[derive(NifMap)]
+struct DryRun {
+    method: String,
+    url: String,
+    // The JSON body exactly as it would be sent
+    body: String,
+}
+
+// A web page a search-preview model cited, and the span of the content citing it
+#[derive(Clone, NifMap)]
+struct Citation {
+    url: String,
+    title: Option<String>,
+    // Character offsets into the content
+    start_index: u64,
+    end_index: u64,
+}
+
+impl Citation {
+    // The url_citation annotations of the raw response's choice `index`; async-openai's
+    // message type predates them
+    fn from_raw(raw: &serde_json::Value, index: usize) -> Vec<Citation> {
+        raw["choices"][index]["message"]["annotations"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+            .iter()
+            .filter(|annotation| annotation["type"] == "url_citation")
+            .filter_map(|annotation| {
+                let citation = &annotation["url_citation"];
+                Some(Citation {
+                    url: citation["url"].as_str()?.to_string(),
+                    title: citation["title"].as_str().map(str::to_string),
+                    start_index: citation["start_index"].as_u64().unwrap_or(0),
+                    end_index: citation["end_index"].as_u64().unwrap_or(0),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(NifMap)]
+struct Choice<'a> {
+    index: u32,
+    content: Term<'a>,
+    finish_reason: Option<String>,
+    // One entry per generated token with `logprobs: true`, nil otherwise
+    logprobs: Option<Vec<TokenLogprob>>,
+    // The model's explanation when it declines a structured output request
+    refusal: Option<String>,
+    tool_calls: Vec<ToolCall>,
+    // Sources cited with `web_search_options`; empty otherwise
+    citations: Vec<Citation>,
+}
+
+// A completion as returned by complete_chat. Content is a string, or the decoded
+// JSON with `json: :extract`, or {:refusal, text} when the model declined.
+// `content` and `finish_reason` are the first choice's; `choices` has all `n` of them.
+#[derive(NifStruct)]
+#[module = "Alchemind.OpenAI.Completion"]
+struct Completion<'a> {
+    id: String,
+    model: String,
+    created: u32,
+    content: Term<'a>,
+    finish_reason: Option<String>,
+    refusal: Option<String>,
+    tool_calls: Vec<ToolCall>,
+    citations: Vec<Citation>,
+    choices: Vec<Choice<'a>>,
+    usage: Option<Usage>,
+    // Backend configuration the completion was generated with, for comparing seeded runs
+    system_fingerprint: Option<String>,
+    // Tier that actually processed the request, which can differ from the one asked for
+    service_tier: Option<String>,
+    // The client's attribution marker, whether or not it was appended to the content
+    attribution: Option<String>,
+}
+
+fn token_logprobs(choice: &ChatChoice) -> Option<Vec<TokenLogprob>> {
+    let tokens = choice.logprobs.as_ref()?.content.as_ref()?;
+    Some(
+        tokens
+            .iter()
+            .map(|token| TokenLogprob {
+                token: token.token.clone(),
+                logprob: token.logprob as f64,
+                bytes: token.bytes.clone(),
+                top_logprobs: token
+                    .top_logprobs
+                    .iter()
+                    .map(|top| TopLogprob { token: top.token.clone(), logprob: top.logprob as f64, bytes: top.bytes.clone() })
+                    .collect(),
+            })
+            .collect(),
+    )
+}
+
+// Spelled as the API does, e.g. "content_filter"
+fn finish_reason(choice: &ChatChoice) -> Option<String> {
+    choice
+        .finish_reason
+        .and_then(|reason| serde_json::to_value(reason).ok())
+        .and_then(|reason| reason.as_str().map(str::to_string))
+}
+
+impl OpenAIClientResource {
+    // Clone the underlying client so requests don't hold the lock while in flight
+    fn client(&self) -> NifResult<OpenAIClient<OpenAIConfig>> {
+        match self.client.lock() {
+            Ok(client) => Ok(client.clone()),
+            Err(e) => Err(Error::Term(Box::new(format!("Failed to lock client: {}", e)))),
+        }
+    }
+
+    fn transport(&self) -> NifResult<http::Transport> {
+        match self.transport.lock() {
+            Ok(transport) => Ok(transport.clone().with_faults(self.faults()?).with_rate_limit(self.rate_limit()?)),
+            Err(e) => Err(Error::Term(Box::new(format!("Failed to lock transport: {}", e)))),
+        }
+    }
+
+    // The transport with the client's limits for `endpoint`, overridden by the call's
+    // `timeout_ms` and `max_retries`
+    fn transport_for(&self, endpoint: limits::Endpoint, opts: &Opts) -> NifResult<http::Transport> {
+        let defaults = match self.limits.lock() {
+            Ok(limits) => limits.get(endpoint),
+            Err(e) => return Err(Error::Term(Box::new(format!("Failed to lock endpoint limits: {}", e)))),
+        };
+        Ok(self.transport()?.with_limits(limits::Limits::decode(opts)?.or(defaults)))
+    }
+
+    fn stream_retry(&self) -> NifResult<retry::RetryPolicy> {
+        match self.stream_retry.lock() {
+            Ok(policy) => Ok(*policy),
+            Err(e) => Err(Error::Term(Box::new(format!("Failed to lock stream retry policy: {}", e)))),
+        }
+    }
+}
+
+// Longest participant or function name the API accepts
+const MAX_NAME_LENGTH: usize = 64;
+
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    (1..=MAX_NAME_LENGTH).contains(&name.len())
+        && name.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-')
+}
+
+// Convert NIF messages into request messages. `developer` messages become system
+// messages here, since the request types predate the role; `restore_developer` gives
+// them their own role back in the body that is sent, streamed or not. Tool
+// messages must answer a call made by an earlier assistant message, which the API
+// would otherwise reject without saying which message is wrong, as it does names
+// it doesn't accept.
+fn to_request_messages(messages: Vec<Message>) -> Result<Vec<ChatCompletionRequestMessage>, String> {
+    let mut tool_call_ids = std::collections::HashSet::new();
+    messages
+        .into_iter()
+        .enumerate()
+        .map(|(index, msg)| {
+            if let Some(name) = msg.name.as_deref().filter(|name| !is_valid_name(name)) {
+                return Err(format!(
+                    "messages[{}]: name {:?} must be 1 to {} letters, digits, underscores or hyphens",
+                    index, name, MAX_NAME_LENGTH
+                ));
+            }
+            // Other roles are sent the parts' text, which `content` already holds
+            if msg.role != "user" && msg.parts.iter().flatten().any(ContentPart::is_image) {
+                return Err(format!("messages[{}]: only user messages can contain images", index));
+            }
+            let message = match msg.role.as_str() {
+                "system" | "developer" => ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                    content: msg.content,
+                    role: Role::System,
+                    name: msg.name,
+                }),
+                "user" => ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content: match &msg.parts {
+                        Some(parts) => ChatCompletionRequestUserMessageContent::Array(
+                            parts
+                                .iter()
+                                .enumerate()
+                                .map(|(i, part)| part.to_request_part(&format!("messages[{}].content[{}]", index, i)))
+                                .collect::<Result<_, _>>()?,
+                        ),
+                        None => ChatCompletionRequestUserMessageContent::Text(msg.content),
+                    },
+                    role: Role::User,
+                    name: msg.name,
+                }),
+                "assistant" => ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+                    // Messages that only call tools have no content
+                    content: Some(msg.content).filter(|content| !content.is_empty() || msg.tool_calls.is_none()),
+                    name: msg.name,
+                    tool_calls: msg.tool_calls.map(|calls| {
+                        calls
+                            .into_iter()
+                            .inspect(|call| {
+                                tool_call_ids.insert(call.id.clone());
+                            })
+                            .map(|call| ChatCompletionMessageToolCall {
+                                id: call.id,
+                                r#type: ChatCompletionToolType::Function,
+                                function: FunctionCall { name: call.name, arguments: call.arguments },
+                            })
+                            .collect()
+                    }),
+                    ..Default::default()
+                }),
+                "tool" => {
+                    let tool_call_id = msg
+                        .tool_call_id
+                        .ok_or_else(|| format!("messages[{}]: tool messages require a tool_call_id", index))?;
+                    if !tool_call_ids.contains(&tool_call_id) {
+                        return Err(format!(
+                            "messages[{}]: tool_call_id {:?} doesn't match a call made by an earlier assistant message",
+                            index, tool_call_id
+                        ));
+                    }
+                    ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage {
+                        role: Role::Tool,
+                        content: msg.content,
+                        tool_call_id,
+                    })
+                },
+                // Legacy function calling
+                "function" => ChatCompletionRequestMessage::Function(ChatCompletionRequestFunctionMessage {
+                    role: Role::Function,
+                    content: Some(msg.content),
+                    name: msg.name.ok_or_else(|| format!("messages[{}]: function messages require a name", index))?,
+                }),
+                other => {
+                    return Err(format!(
+                        "messages[{}]: role {:?} must be system, developer, user, assistant, tool or function",
+                        index, other
+                    ))
+                },
+            };
+            Ok(message)
+        })
+        .collect()
+}
+
+// The body of a non-streaming chat request. async-openai's request type is built first,
+// then given what it can't express: options it has no field for, legacy function
+// calling and the developer role of the messages at `developer`.
+fn chat_body(model: &str, messages: Vec<ChatCompletionRequestMessage>, developer: &[usize], n: Option<u8>, params: &params::ChatParams, sampling: &resample::Sampling) -> Result<serde_json::Value, String> {
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(model).messages(messages);
+    if let Some(n) = n {
+        args.n(n);
+    }
+    params.apply(&mut args);
+    sampling.apply(&mut args);
+    let request = args.build().map_err(|e| format!("Failed to build request: {}", e))?;
+    let mut body = serde_json::to_value(&request).map_err(|e| format!("Failed to encode request: {}", e))?;
+    params.patch(&mut body);
+    if params.legacy_functions() {
+        legacy::downgrade(&mut body)?;
+    }
+    restore_developer(&mut body, developer);
+    Ok(body)
+}
+
+// Positions of the developer messages, which `to_request_messages` turns into system ones
+fn developer_indices(messages: &[Message]) -> Vec<usize> {
+    messages
+        .iter()
+        .enumerate()
+        .filter(|(_, msg)| msg.role == "developer")
+        .map(|(index, _)| index)
+        .collect()
+}
+
+// Sends the messages at `developer` in a serialized request with their own role again
+fn restore_developer(body: &mut serde_json::Value, developer: &[usize]) {
+    for &index in developer {
+        body["messages"][index]["role"] = serde_json::Value::from("developer");
+    }
+}
+
+// Send a non-streaming chat request and return the first choice's content
+async fn request_content(transport: &http::Transport, request: CreateChatCompletionRequest) -> Result<String, String> {
+    let completion: CreateChatCompletionResponse = transport
+        .post_typed("/chat/completions", &request)
+        .await
+        .map_err(|e| format!("API request failed: {}", e.message))?;
+    
+    match completion.choices.into_iter().next() {
+        Some(choice) => Ok(choice.message.content.unwrap_or_default()),
+        None => Err("No completion choices returned".to_string()),
+    }
 }
 
 #[rustler::nif]
-fn create_client(api_key: &str, base_url: &str) -> NifResult<ResourceArc<OpenAIClientResource>> {
+fn create_client(api_key: &str, base_url: &str, keep_warm: Opts) -> NifResult<ResourceArc<OpenAIClientResource>> {
     let config = OpenAIConfig::new()
         .with_api_key(api_key)
         .with_api_base(base_url);
     
-    let client = OpenAIClient::with_config(config);
+    let keep_warm = keepwarm::KeepWarm::decode(&keep_warm)?;
+    let http_client = keepwarm::KeepWarm::http_client(keep_warm)?;
+    let client = OpenAIClient::with_config(config.clone()).with_http_client(http_client.clone());
+    let stats = Arc::new(stats::ClientStats::new(base_url));
     
     Ok(ResourceArc::new(OpenAIClientResource {
         client: Arc::new(Mutex::new(client)),
+        post_processors: Mutex::new(Vec::new()),
+        transport: Mutex::new(http::Transport::new(http_client, config, stats.clone())),
+        stream_retry: Mutex::new(retry::RetryPolicy::streams()),
+        limits: Mutex::new(limits::EndpointLimits::default()),
+        system_prompts: Mutex::new(prompts::SystemPrompts::new()),
+        stats,
+        attribution: Mutex::new(None),
+        keep_warm,
+        faults: Mutex::new(None),
+        rate_limit: Mutex::new(None),
     }))
 }
 
-#[rustler::nif]
-fn complete_chat(client_resource: ResourceArc<OpenAIClientResource>, messages: Vec<Message>, model: &str) -> NifResult<String> {
+#[rustler::nif(schedule = "DirtyIo")]
+fn complete_chat<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, messages: Vec<Message>, model: &str, opts: Opts<'a>) -> NifResult<Term<'a>> {
     let runtime = match tokio::runtime::Runtime::new() {
         Ok(rt) => rt,
         Err(_) => return Err(Error::Term(Box::new("Failed to create Tokio runtime"))),
     };
     
-    // Access the client field correctly through the ResourceArc
-    let client = client_resource.client.lock().unwrap();
+    // Requests go through the transport so response headers can be returned
+    let transport = client_resource
+        .transport_for(limits::Endpoint::Chat, &opts)?
+        .with_deadline(deadline::Deadline::decode(&opts)?);
+    let header_selection = http::decode_header_selection(&opts)?;
     
-    // Convert messages to OpenAI format
-    let mut chat_messages = Vec::new();
-    
-    for msg in messages {
-        match msg.role.as_str() {
-            "system" => {
-                let message = ChatCompletionRequestSystemMessageArgs::default()
-                    .content(msg.content)
-                    .build()
-                    .map_err(|e| Error::Term(Box::new(format!("Failed to build system message: {}", e))))?;
-                chat_messages.push(message.into());
-            },
-            "assistant" => {
-                let message = async_openai::types::ChatCompletionRequestAssistantMessageArgs::default()
-                    .content(msg.content)
-                    .build()
-                    .map_err(|e| Error::Term(Box::new(format!("Failed to build assistant message: {}", e))))?;
-                chat_messages.push(message.into());
-            },
-            _ => { // default to user message
-                let message = ChatCompletionRequestUserMessageArgs::default()
-                    .content(msg.content)
-                    .build()
-                    .map_err(|e| Error::Term(Box::new(format!("Failed to build user message: {}", e))))?;
-                chat_messages.push(message.into());
-            }
-        }
+    let extract_json = match options::get_atom(&opts, "json")?.as_deref() {
+        Some("extract") => true,
+        Some(other) => return Err(options::invalid(&options::path("json"), format!("must be :extract, got :{}", other))),
+        None => false,
+    };
+    let json_numbers = json::Numbers::decode(&opts)?;
+    let attribution = client_resource.attribution()?;
+    let dry_run = options::get_bool(&opts, "dry_run")?.unwrap_or(false);
+    // The response body exactly as received, for fields the structs don't cover yet
+    let raw_json = match options::get_atom(&opts, "return")?.as_deref() {
+        Some("raw_json") => true,
+        Some(other) => return Err(options::invalid(&options::path("return"), format!("must be :raw_json, got :{}", other))),
+        None => false,
+    };
+    
+    let schedule = resample::decode_schedule(&opts)?;
+    let params = params::ChatParams::decode(&opts)?;
+    // Only here: a stream would interleave the choices' deltas
+    let n = match options::get_usize(&opts, "n")? {
+        Some(n @ 1..=128) => Some(n as u8),
+        Some(n) => return Err(options::invalid(&options::path("n"), format!("must be between 1 and 128, got {}", n))),
+        None => None,
+    };
+    let max_continuations = continuation::decode(&opts)?;
+    if max_continuations > 0 && n.is_some_and(|n| n > 1) {
+        return Err(options::invalid(&options::path("max_continuations"), "can't be combined with n"));
+    }
+    if max_continuations > 0 && raw_json {
+        return Err(options::invalid(&options::path("max_continuations"), "can't be combined with return: :raw_json"));
     }
     
-    // Create the completion request
-    let request = CreateChatCompletionRequestArgs::default()
-        .model(model)
-        .messages(chat_messages)
-        .build()
-        .map_err(|e| Error::Term(Box::new(format!("Failed to build request: {}", e))))?;
+    let messages = prompts::splice(&client_resource, &opts, messages)?;
     
-    // Send the request and get the response
-    let response = runtime.block_on(async {
-        client.chat().create(request).await
-    });
+    // The request types predate the developer role, so it is restored in the body
+    let developer_indices = developer_indices(&messages);
+
+    // Convert messages to OpenAI format
+    let chat_messages = to_request_messages(messages).map_err(|e| Error::Term(Box::new(e)))?;
     
-    match response {
-        Ok(completion) => {
-            // Get the assistant's message
-            if let Some(choice) = completion.choices.first() {
-                if let Some(content) = &choice.message.content {
-                    Ok(content.clone())
-                } else {
-                    Ok(String::new())
-                }
+    for (attempt, sampling) in schedule.iter().enumerate() {
+        let is_last = attempt + 1 == schedule.len();
+        
+        let body = chat_body(model, chat_messages.clone(), &developer_indices, n, &params, sampling)
+            .map_err(|e| Error::Term(Box::new(e)))?;
+        
+        // Everything above is validated by now; with a retry schedule this is the first attempt
+        if dry_run {
+            let (url, body) = transport.preview_json("/chat/completions", &body).map_err(|e| e.context("Failed to build request"))?;
+            return Ok((atoms::dry_run(), DryRun { method: "POST".to_string(), url, body }).encode(env));
+        }
+        
+        // Send the request and get the response
+        let mut response = runtime
+            .block_on(transport.post_json("/chat/completions", &body))
+            .map_err(|e| e.context("API request failed"))?;
+        if raw_json {
+            if let Ok(raw) = response.json::<serde_json::Value>() {
+                client_resource.stats.record_response(&raw);
+            }
+            let body = String::from_utf8_lossy(&response.body).into_owned();
+            return Ok(response.attach(env, (atoms::raw_json(), body).encode(env), &header_selection));
+        }
+        // The raw response also has fields async-openai's types predate, such as refusals.
+        // Output cut off by the token limit is continued and joined here; headers are
+        // returned from the last request.
+        let mut raw: serde_json::Value = response.json().map_err(|e| Error::Term(Box::new(e)))?;
+        for _ in 0..max_continuations {
+            let Some(next) = continuation::request(&body, &raw) else {
+                break;
+            };
+            response = runtime
+                .block_on(transport.post_json("/chat/completions", &next))
+                .map_err(|e| e.context("API request failed"))?;
+            continuation::merge(&mut raw, &response.json().map_err(|e| Error::Term(Box::new(e)))?);
+        }
+        // Responses a retry schedule rejects were still billed
+        client_resource.stats.record_response(&raw);
+        // The envelope's ids and counts are held to the same rule as decoded content
+        json_numbers.check(&raw).map_err(|e| Error::Term(Box::new(e)))?;
+        let completion: CreateChatCompletionResponse = serde_json::from_value(raw.clone())
+            .map_err(|e| Error::Term(Box::new(format!("Failed to decode response: {}", e))))?;
+        
+        if completion.choices.is_empty() {
+            return Err(Error::Term(Box::new("No completion choices returned")));
+        }
+        
+        // Why the model declined a structured output request
+        let refusals: Vec<Option<String>> = (0..completion.choices.len())
+            .map(|i| raw["choices"][i]["message"]["refusal"].as_str().map(str::to_string))
+            .collect();
+        let tool_calls: Vec<Vec<ToolCall>> = completion
+            .choices
+            .iter()
+            .enumerate()
+            .map(|(i, choice)| match &choice.message.tool_calls {
+                Some(calls) if !calls.is_empty() => calls.iter().map(ToolCall::from).collect(),
+                _ => legacy::call(&raw, i).into_iter().collect(),
+            })
+            .collect();
+        
+        // Post-process every choice; with `json: :extract` each is parsed separately
+        let processors = client_resource.post_processors.lock()
+            .map_err(|e| Error::Term(Box::new(format!("Failed to lock post-processors: {}", e))))?;
+        let contents: Vec<String> = completion
+            .choices
+            .iter()
+            .map(|choice| choice.message.content.as_deref().map_or_else(String::new, |content| postprocess::apply(&processors, content)))
+            .collect();
+        drop(processors);
+        let marker = attribution.as_ref().map(|attribution| attribution.marker(&completion.model));
+        
+        // Empty and refused responses are only rejected when a retry schedule is set,
+        // and only when no choice is usable
+        if schedule.len() > 1 {
+            let refused = |(choice, refusal): (&ChatChoice, &Option<String>)| {
+                refusal.is_some() || matches!(choice.finish_reason, Some(FinishReason::ContentFilter))
+            };
+            // Choices that only call tools have no content but are still usable
+            let unusable = |(calls, content): (&Vec<ToolCall>, &String)| content.trim().is_empty() && calls.is_empty();
+            let rejection = if tool_calls.iter().zip(&contents).all(unusable) {
+                Some("was empty")
+            } else if completion
+                .choices
+                .iter()
+                .zip(&refusals)
+                .zip(tool_calls.iter().zip(&contents))
+                .all(|((choice, refusal), choice_content)| refused((choice, refusal)) || unusable(choice_content))
+            {
+                Some("was refused")
             } else {
-                Err(Error::Term(Box::new("No completion choices returned")))
+                None
+            };
+            match rejection {
+                Some(_) if !is_last => continue,
+                Some(reason) => return Err(Error::Term(Box::new(format!("Completion {} after {} attempts", reason, schedule.len())))),
+                None => {},
             }
-        },
-        Err(e) => Err(Error::Term(Box::new(format!("API request failed: {}", e)))),
+        }
+        
+        // Return the first JSON object/array in each output as a decoded term. Structured
+        // outputs are JSON throughout, unless the model refused. Choices that call tools
+        // need no JSON, and keep any text the model wrote alongside the calls.
+        let structured = params.structured();
+        let values: Vec<Term<'a>> = if extract_json || structured {
+            let parsed: Vec<Option<serde_json::Value>> = contents
+                .iter()
+                .map(|content| {
+                    structured
+                        .then(|| serde_json::from_str(content).ok())
+                        .flatten()
+                        .or_else(|| postprocess::parse_first_json(content))
+                })
+                .collect();
+            if parsed.iter().all(Option::is_none) && refusals.iter().all(Option::is_none) && tool_calls.iter().all(Vec::is_empty) {
+                if !is_last {
+                    continue;
+                }
+                return Err(Error::Term(Box::new("No JSON object or array found in completion")));
+            }
+            for value in parsed.iter().flatten() {
+                json_numbers.check(value).map_err(|e| Error::Term(Box::new(e)))?;
+            }
+            parsed
+                .iter()
+                .zip(contents.iter().zip(&tool_calls))
+                .map(|(value, (content, calls))| match value {
+                    Some(value) => json::to_term(env, value),
+                    None if !calls.is_empty() && !content.trim().is_empty() => content.encode(env),
+                    None => rustler::types::atom::nil().encode(env),
+                })
+                .collect()
+        } else {
+            // Only text gets the marker appended, since it would break JSON
+            contents
+                .iter()
+                .map(|content| {
+                    let suffix = attribution.as_ref().zip(marker.as_deref()).and_then(|(attribution, marker)| attribution.text_suffix(content, marker));
+                    match suffix {
+                        Some(suffix) => format!("{}{}", content, suffix).encode(env),
+                        None => content.encode(env),
+                    }
+                })
+                .collect()
+        };
+        // A refused choice's content is {:refusal, text}, so it can't pass for an empty response
+        let values: Vec<Term<'a>> = values
+            .into_iter()
+            .zip(&refusals)
+            .map(|(value, refusal)| match refusal {
+                Some(text) => (atoms::refusal(), text).encode(env),
+                None => value,
+            })
+            .collect();
+        
+        let choices: Vec<Choice<'a>> = completion
+            .choices
+            .iter()
+            .zip(values)
+            .zip(refusals)
+            .zip(tool_calls)
+            .map(|(((choice, content), refusal), tool_calls)| Choice {
+                index: choice.index,
+                content,
+                finish_reason: finish_reason(choice),
+                logprobs: token_logprobs(choice),
+                refusal,
+                tool_calls,
+                citations: Citation::from_raw(&raw, choice.index as usize),
+            })
+            .collect();
+        // Token breakdowns async-openai's usage type predates
+        let details = |key: &str| raw["usage"]["completion_tokens_details"][key].as_u64();
+        let result = Completion {
+            id: completion.id.clone(),
+            model: completion.model.clone(),
+            created: completion.created,
+            content: choices[0].content,
+            finish_reason: choices[0].finish_reason.clone(),
+            refusal: choices[0].refusal.clone(),
+            tool_calls: choices[0].tool_calls.clone(),
+            citations: choices[0].citations.clone(),
+            choices,
+            usage: completion.usage.as_ref().map(|usage| Usage {
+                prompt_tokens: usage.prompt_tokens.into(),
+                completion_tokens: usage.completion_tokens.into(),
+                total_tokens: usage.total_tokens.into(),
+                reasoning_tokens: details("reasoning_tokens"),
+                accepted_prediction_tokens: details("accepted_prediction_tokens"),
+                rejected_prediction_tokens: details("rejected_prediction_tokens"),
+            }),
+            system_fingerprint: completion.system_fingerprint.clone(),
+            service_tier: raw["service_tier"].as_str().map(str::to_string),
+            attribution: marker,
+        };
+        
+        return Ok(response.attach(env, result.encode(env), &header_selection));
     }
+    
+    Err(Error::Term(Box::new("No completion choices returned")))
+}
+
+// Sends a request body as-is, such as one captured with `dry_run: true` and edited,
+// to reproduce a problem with its exact payload. The body only has to be JSON; the
+// response's JSON is returned decoded, without the processing `complete` does.
+#[rustler::nif(schedule = "DirtyIo")]
+fn send_request_body<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, body: String, endpoint: String, opts: Opts<'a>) -> NifResult<Term<'a>> {
+    serde_json::from_str::<serde_json::Value>(&body).map_err(|e| Error::Term(Box::new(format!("Invalid request JSON: {}", e))))?;
+    let path = format!("/{}", endpoint.trim_start_matches('/'));
+    let limits = match path.as_str() {
+        "/chat/completions" => limits::Endpoint::Chat,
+        path if path.starts_with("/audio/") => limits::Endpoint::Audio,
+        _ => limits::Endpoint::Other,
+    };
+    let transport = client_resource
+        .transport_for(limits, &opts)?
+        .with_deadline(deadline::Deadline::decode(&opts)?);
+    let header_selection = http::decode_header_selection(&opts)?;
+    let json_numbers = json::Numbers::decode(&opts)?;
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let response = runtime
+        .block_on(transport.post_raw_json(&path, &body))
+        .map_err(|e| e.context("API request failed"))?;
+    let value: serde_json::Value = response.json().map_err(|e| Error::Term(Box::new(e)))?;
+    json_numbers.check(&value).map_err(|e| Error::Term(Box::new(e)))?;
+    Ok(response.attach(env, json::to_term(env, &value), &header_selection))
 }
 
-// Instead of trying to implement the streaming in Rust, which is complex due to thread safety,
-// let's use a more pragmatic approach: we'll create a function that processes a small chunk
-// of the streaming response and call this function multiple times from Elixir to simulate streaming.
+// How a stream's thread stopped reading it
+enum StreamEnd {
+    // The server finished, or the filter stopped it with the given halt
+    Finished(Option<streaming::Halt>),
+    Cancelled,
+    // The consumer exited, so nobody is left to tell
+    Abandoned,
+    // The stream went quiet and was most likely closed by a proxy, with the error
+    ProxyIdle(String),
+}
 
+// Starts a chat completion stream on a thread of its own, which reads it to the end and
+// sends each message of the protocol in streaming.rs to `stream_pid` as it arrives.
+// Options are checked before the thread starts, so mistakes are returned from here.
 #[rustler::nif]
-fn process_completion_chunk(env: Env, client_resource: ResourceArc<OpenAIClientResource>, messages: Vec<Message>, model: &str, stream_pid: rustler::LocalPid, ref_term: Term) -> NifResult<rustler::Atom> {
-    // We'll use a simpler approach - just initiating the request and letting Elixir handle the streaming
-    let runtime = tokio::runtime::Runtime::new().map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
-    
-    // Access the client field correctly through the ResourceArc
-    let client = match client_resource.client.lock() {
-        Ok(client) => client.clone(),
-        Err(e) => return Err(Error::Term(Box::new(format!("Failed to lock client: {}", e)))),
-    };
+fn start_completion_stream(client_resource: ResourceArc<OpenAIClientResource>, messages: Vec<Message>, model: String, opts: Opts, stream_pid: rustler::LocalPid, ref_term: Term) -> NifResult<rustler::Atom> {
+    spawn_chat_stream(client_resource, messages, model, &opts, streaming::Consumer::process(stream_pid, ref_term), None)?;
+    Ok(atoms::ok())
+}
+
+// Starts a chat completion stream like `start_completion_stream`, but returns a handle
+// its messages are pulled from with `next_chunk`, so the consumer sets the pace.
+// The model is the `model` option.
+#[rustler::nif]
+fn start_chat_stream(client_resource: ResourceArc<OpenAIClientResource>, messages: Vec<Message>, opts: Opts) -> NifResult<ResourceArc<streaming::ChatStream>> {
+    let model = options::get_string(&opts, "model")?.ok_or_else(|| options::invalid(&options::path("model"), "is required"))?;
+    let (consumer, stream) = streaming::Consumer::queue();
+    spawn_chat_stream(client_resource, messages, model, &opts, consumer, Some(stream.stop.clone()))?;
+    Ok(stream)
+}
+
+// Checks the options, then starts the thread that reads the stream to the end and
+// hands each message to `consumer`. `stop` cancels it like the `cancel` option.
+fn spawn_chat_stream(client_resource: ResourceArc<OpenAIClientResource>, messages: Vec<Message>, model: String, opts: &Opts, consumer: streaming::Consumer, stop: Option<ResourceArc<cancel::CancelToken>>) -> NifResult<()> {
+    let mut filter = streaming::StreamFilter::decode(opts, &model)?;
+    let deadline = deadline::Deadline::decode(opts)?;
+    let segmenter = streaming::Segmenter::decode(opts, streaming::Segmentation::Delta)?;
+    let params = params::ChatParams::decode(opts)?;
+    params.check_streamable()?;
+    // Streams aren't retried, so only the top-level sampling applies
+    let sampling = resample::decode_schedule(opts)?[0];
+    let cancel = cancel::CancelToken::decode(opts)?;
+    let include_usage = options::get_bool(opts, "include_usage")?.unwrap_or(false);
+    let stream_retry = client_resource.stream_retry()?;
+    let faults = client_resource.faults()?;
+    let attribution = client_resource.attribution()?;
     
     // Convert messages to OpenAI format
-    let mut chat_messages = Vec::new();
-    
-    for msg in messages {
-        match msg.role.as_str() {
-            "system" => {
-                let message = ChatCompletionRequestSystemMessageArgs::default()
-                    .content(msg.content)
-                    .build()
-                    .map_err(|e| Error::Term(Box::new(format!("Failed to build system message: {}", e))))?;
-                chat_messages.push(message.into());
-            },
-            "assistant" => {
-                let message = async_openai::types::ChatCompletionRequestAssistantMessageArgs::default()
-                    .content(msg.content)
-                    .build()
-                    .map_err(|e| Error::Term(Box::new(format!("Failed to build assistant message: {}", e))))?;
-                chat_messages.push(message.into());
-            },
-            _ => { // default to user message
-                let message = ChatCompletionRequestUserMessageArgs::default()
-                    .content(msg.content)
-                    .build()
-                    .map_err(|e| Error::Term(Box::new(format!("Failed to build user message: {}", e))))?;
-                chat_messages.push(message.into());
-            }
-        }
-    }
+    let messages = prompts::splice(&client_resource, opts, messages)?;
+    let opener = chunks::Opener::new(&client_resource, &messages, &params, include_usage, deadline)?;
+    let prompt: Vec<String> = messages.iter().map(|msg| msg.content.clone()).collect();
+    let chat_messages = to_request_messages(messages).map_err(|e| Error::Term(Box::new(e)))?;
     
     // Create the completion request with streaming enabled
-    let request = CreateChatCompletionRequestArgs::default()
-        .model(model)
-        .messages(chat_messages)
-        .stream(true)
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(model.as_str()).messages(chat_messages).stream(true);
+    params.apply(&mut args);
+    sampling.apply(&mut args);
+    let request = args
         .build()
         .map_err(|e| Error::Term(Box::new(format!("Failed to build request: {}", e))))?;
     
-    // Process the request in the runtime but in a blocking way
-    let result = runtime.block_on(async {
-        // Create the stream
-        let mut stream = match client.chat().create_stream(request).await {
-            Ok(s) => s,
-            Err(e) => return Err(format!("Failed to create stream: {}", e)),
-        };
-        
-        // Process up to 10 chunks to keep it responsive
-        let mut chunks = Vec::new();
-        let mut is_done = false;
-        
-        for _ in 0..10 {
-            match stream.next().await {
-                Some(Ok(response)) => {
-                    for choice in response.choices {
-                        if let Some(content) = &choice.delta.content {
-                            chunks.push(content.clone());
+    let mut sender = streaming::StreamSender::new(consumer, segmenter);
+    // The thread drives one stream, so it needs no worker threads of its own
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+    
+    let spawned = std::thread::Builder::new().name("alchemind_openai_stream".to_string()).spawn(move || {
+        let _stream = client_resource.stats.stream();
+        // Reported by the server with `include_usage`, on its last chunks
+        let mut server_finish_reason = None;
+        let mut server_usage = None;
+        // Errors say whether starting over could succeed
+        let result = runtime.block_on(async {
+            if deadline.passed() {
+                return Err(("Deadline exceeded".to_string(), false));
+            }
+            // Cancelling while the model thinks, before the first token, stops it too
+            let opened = retry::open_chat_stream(&opener, request, stream_retry, deadline, client_resource.keep_warm, faults.as_ref());
+            let mut stream = match cancel::run_either(cancel.as_deref(), stop.as_deref(), opened).await {
+                Some(stream) => stream.map_err(|e| (e, false))?,
+                None => return Ok(StreamEnd::Cancelled),
+            };
+            
+            let mut started = false;
+            loop {
+                let next = match cancel::run_either(cancel.as_deref(), stop.as_deref(), deadline.run(stream.next())).await {
+                    Some(Some(next)) => next,
+                    Some(None) => return Err(("Deadline exceeded".to_string(), false)),
+                    None => return Ok(StreamEnd::Cancelled),
+                };
+                let response = match next {
+                    Some(Ok(chunk)) => {
+                        if chunk.usage.is_some() {
+                            server_usage = chunk.usage;
                         }
-                        if choice.finish_reason.is_some() {
-                            is_done = true;
+                        chunk.response
+                    },
+                    Some(Err(e)) if keepwarm::is_proxy_idle(&e) => return Ok(StreamEnd::ProxyIdle(format!("Stream error: {}", e))),
+                    Some(Err(e)) => return Err((format!("Stream error: {}", e), retry::retryable(&e))),
+                    None => return Ok(StreamEnd::Finished(None)),
+                };
+                
+                let mut delivered = true;
+                if !started {
+                    started = true;
+                    let started = streaming::StreamStarted { id: response.id.clone(), model: response.model.clone() };
+                    delivered = sender.send((atoms::stream_started(), started));
+                }
+                for choice in response.choices {
+                    if choice.index == 0 {
+                        server_finish_reason = choice.finish_reason.or(server_finish_reason);
+                    }
+                    for call in choice.delta.tool_calls.iter().flatten() {
+                        let function = call.function.as_ref();
+                        let delta = streaming::ToolCallDelta {
+                            index: call.index,
+                            id: call.id.clone(),
+                            name: function.and_then(|function| function.name.clone()),
+                            arguments: function.and_then(|function| function.arguments.clone()).unwrap_or_default(),
+                        };
+                        delivered &= sender.send((atoms::stream_tool_call_delta(), delta));
+                    }
+                    if let Some(content) = &choice.delta.content {
+                        let (text, halted) = filter.push(content);
+                        delivered &= sender.push(&text);
+                        // Dropping the stream closes the connection so no more tokens are generated
+                        if halted.is_some() {
+                            return Ok(StreamEnd::Finished(halted));
                         }
                     }
-                },
-                Some(Err(e)) => return Err(format!("Stream error: {}", e)),
-                None => {
-                    is_done = true;
-                    break;
+                }
+                if !delivered {
+                    return Ok(StreamEnd::Abandoned);
                 }
             }
-        }
+        });
         
-        Ok((chunks, is_done))
-    });
-    
-    match result {
-        Ok((chunks, is_done)) => {
-            // Send the chunks to the Elixir process
-            for chunk in chunks {
-                let _ = env.send(&stream_pid, (atoms::stream_chunk(), chunk, ref_term.clone()));
-            }
-            
-            // If we're done, send the done message
-            if is_done {
-                let _ = env.send(&stream_pid, (atoms::stream_done(), ref_term.clone()));
+        let halt = match result {
+            Ok(StreamEnd::Finished(Some(halt))) => Some(halt),
+            Ok(StreamEnd::Finished(None)) => {
+                let (text, halted) = filter.flush();
+                sender.push(&text);
+                halted
+            },
+            Ok(StreamEnd::Cancelled) => {
+                sender.send((atoms::stream_cancelled(),));
+                return;
+            },
+            Ok(StreamEnd::Abandoned) => return,
+            Ok(StreamEnd::ProxyIdle(message)) => {
+                let error = streaming::StreamError::proxy_idle_timeout(message, sender.received.clone(), sender.chunks);
+                sender.send((atoms::stream_error(), error));
+                return;
+            },
+            Err((message, retryable)) => {
+                let error = streaming::StreamError::new(message, retryable, sender.received.clone(), sender.chunks);
+                sender.send((atoms::stream_error(), error));
+                return;
+            },
+        };
+        sender.flush();
+        
+        if let Some(streaming::Halt::MaxSizeExceeded) = halt {
+            let error = streaming::StreamError::max_size_exceeded(sender.received.clone(), sender.chunks);
+            sender.send((atoms::stream_error(), error));
+            return;
+        }
+        // Servers that ignore `include_usage` leave it to be counted here
+        let usage = match server_usage {
+            Some(usage) => streaming::StreamUsage::from(usage),
+            None => tokens::with_bpe(&model, |bpe| {
+                let prompt_tokens = prompt.iter().map(|content| tokens::count(bpe, content)).sum();
+                let completion_tokens = tokens::count(bpe, &sender.received);
+                streaming::StreamUsage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }
+            }),
+        };
+        // The marker is the last chunk, and isn't counted as generated
+        if let Some(attribution) = &attribution {
+            if let Some(suffix) = attribution.text_suffix(&sender.received, &attribution.marker(&model)) {
+                sender.push(&suffix);
+                sender.flush();
             }
-            
-            Ok(atoms::ok())
-        },
-        Err(error_msg) => {
-            // Send the error to the Elixir process
-            let _ = env.send(&stream_pid, (atoms::stream_error(), error_msg, ref_term.clone()));
-            Ok(atoms::ok())
         }
-    }
+        client_resource.stats.record_usage(usage.prompt_tokens as u64, usage.completion_tokens as u64);
+        sender.send((atoms::stream_usage(), usage.clone()));
+        if include_usage {
+            // A local stop sequence ends the stream before the server says why it finished
+            let finish_reason = match halt {
+                Some(streaming::Halt::LimitReached) => "limit_reached".to_string(),
+                Some(_) => "stop".to_string(),
+                None => server_finish_reason
+                    .and_then(|reason| serde_json::to_value(reason).ok())
+                    .and_then(|reason| reason.as_str().map(str::to_string))
+                    .unwrap_or_else(|| "stop".to_string()),
+            };
+            sender.send((atoms::stream_done(), streaming::StreamDone { finish_reason, usage }));
+        } else if let Some(streaming::Halt::LimitReached) = halt {
+            sender.send((atoms::stream_done(), atoms::limit_reached()));
+        } else {
+            sender.send((atoms::stream_done(),));
+        }
+    });
+    spawned.map_err(|e| Error::Term(Box::new(format!("Failed to start stream thread: {}", e))))?;
+    
+    Ok(())
 }
 
-#[rustler::nif]
-fn transcribe_audio(client_resource: ResourceArc<OpenAIClientResource>, audio_binary: Vec<u8>, opts: HashMap<String, Term>) -> NifResult<String> {
+#[rustler::nif(schedule = "DirtyIo")]
+fn transcribe_audio<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, audio: Term<'a>, opts: HashMap<String, Term<'a>>) -> NifResult<Term<'a>> {
     let runtime = match tokio::runtime::Runtime::new() {
         Ok(rt) => rt,
         Err(_) => return Err(Error::Term(Box::new("Failed to create Tokio runtime"))),
     };
     
-    // Access the client field correctly through the ResourceArc
-    let client = match client_resource.client.lock() {
-        Ok(client) => client,
-        Err(e) => return Err(Error::Term(Box::new(format!("Failed to lock client: {}", e))))
-    };
+    // Requests go through the transport so response headers can be returned
+    let transport = client_resource
+        .transport_for(limits::Endpoint::Audio, &opts)?
+        .with_deadline(deadline::Deadline::decode(&opts)?);
+    
+    // A binary, named for its format, or a staged file uploaded under the name it was staged with
+    let stem = format!("audio-{}", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs());
+    let audio_format = transcription::decode_audio_format(&opts)?;
+    let (audio, file_name) = staging::decode_audio(audio, |data| transcription::upload_name(&stem, data, audio_format.as_deref()))?;
     
-    let debug_info = format!("Audio binary length: {}, Opts: {:?}", audio_binary.len(), opts.keys().collect::<Vec<_>>());
+    let debug_info = format!("Audio binary length: {}, Opts: {:?}", audio.len(), opts.keys().collect::<Vec<_>>());
     
     // Audio binary should have a minimum length
-    if audio_binary.len() < 10 {
+    if audio.len() < 10 {
         return Err(Error::Term(Box::new(format!("Audio binary too small. {}", debug_info))));
     }
     
     // Extract options with defaults
-    let model = if let Some(term) = opts.get("model") {
-        if term.is_atom() {
-            "whisper-1".to_string()
-        } else {
-            match term.decode::<String>() {
-                Ok(s) => s,
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode model: {:?}", e))))
-            }
-        }
-    } else {
-        "whisper-1".to_string()
-    };
+    let model = options::get_string(&opts, "model")?.unwrap_or_else(|| "whisper-1".to_string());
+    let language = options::get_string(&opts, "language")?;
+    let prompt = options::get_string(&opts, "prompt")?;
+    // Speaker labels need verbose_json's timed segments
+    let diarizer = diarize::Diarizer::decode(&opts)?;
+    let default_format = if diarizer.is_some() { "verbose_json" } else { "text" };
+    let response_format = options::get_string(&opts, "response_format")?.unwrap_or_else(|| default_format.to_string());
+    let temperature = options::get_f32(&opts, "temperature")?;
     
-    let language = if let Some(term) = opts.get("language") {
-        if term.is_atom() {
-            None
-        } else {
-            match term.decode::<String>() {
-                Ok(s) => Some(s),
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode language: {:?}", e))))
-            }
-        }
-    } else {
-        None
-    };
-    
-    let prompt = if let Some(term) = opts.get("prompt") {
-        if term.is_atom() {
-            None
-        } else {
-            match term.decode::<String>() {
-                Ok(s) => Some(s),
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode prompt: {:?}", e))))
-            }
-        }
-    } else {
-        None
-    };
+    let header_selection = http::decode_header_selection(&opts)?;
+    let redactor = redact::Redactor::decode(&opts)?;
+    let return_duration = options::get_bool(&opts, "return_duration")?.unwrap_or(false);
     
-    let response_format = if let Some(term) = opts.get("response_format") {
-        if term.is_atom() {
-            "text".to_string()
-        } else {
-            match term.decode::<String>() {
-                Ok(s) => s,
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode response_format: {:?}", e))))
-            }
-        }
+    let response_format = if transcription::TRANSCRIPTION_FORMATS.contains(&response_format.as_str()) {
+        response_format
     } else {
         "text".to_string()
     };
-    
-    let temperature = if let Some(term) = opts.get("temperature") {
-        if term.is_atom() {
-            None
-        } else {
-            match term.decode::<f32>() {
-                Ok(t) => Some(t),
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode temperature: {:?}", e))))
-            }
-        }
-    } else {
-        None
-    };
-    
-    // Create the audio input from binary data
-    let file_name = format!("audio-{}.webm", std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs());
-    let audio_input = AudioInput::from_vec_u8(file_name, audio_binary);
-    
-    // Create the transcription request using the correct builder pattern with a binding
-    let mut args = CreateTranscriptionRequestArgs::default();
-    let mut request = args
-        .file(audio_input)
-        .model(&model);
-    
-    if let Some(lang) = language {
-        request = request.language(&lang);
-    }
-    
-    if let Some(p) = prompt {
-        request = request.prompt(&p);
+    if diarizer.is_some() && response_format != "verbose_json" {
+        return Err(options::invalid(&options::path("diarize"), "requires response_format: \"verbose_json\""));
     }
     
-    // Set response format using the correct enum
-    let response_format_enum = match response_format.as_str() {
-        "json" => AudioResponseFormat::Json,
-        "srt" => AudioResponseFormat::Srt,
-        "verbose_json" => AudioResponseFormat::VerboseJson,
-        "vtt" => AudioResponseFormat::Vtt,
-        _ => AudioResponseFormat::Text,
-    };
+    let audio = runtime.block_on(audio.load()).map_err(|e| Error::Term(Box::new(e)))?;
+    let mut upload = upload::UploadProgress::decode(env, &opts)?;
+    let counter = upload.counter(0, audio.len() as u64);
     
-    request = request.response_format(response_format_enum);
-    
-    if let Some(temp) = temperature {
-        request = request.temperature(temp);
-    }
-    
-    // Build the final request
-    let request = match request.build() {
-        Ok(req) => req,
-        Err(e) => return Err(Error::Term(Box::new(format!("Failed to build request: {:?}", e))))
+    // Create the multipart form, rebuilt for each attempt when rate limited. Every
+    // attempt shares the one copy of the audio.
+    let make_form = || {
+        let file = counter.part(&audio).file_name(file_name.clone());
+        let mut form = Form::new()
+            .part("file", file)
+            .text("model", model.clone())
+            .text("response_format", response_format.clone());
+        if let Some(lang) = &language {
+            form = form.text("language", lang.clone());
+        }
+        if let Some(p) = &prompt {
+            form = form.text("prompt", p.clone());
+        }
+        if let Some(temp) = temperature {
+            form = form.text("temperature", temp.to_string());
+        }
+        Ok(form)
     };
     
     // Send the request and get the response
-    let response = runtime.block_on(async {
-        client.audio().transcribe(request).await
-    });
+    let response = runtime
+        .block_on(upload.track(transport.post_form("/audio/transcriptions", make_form)))
+        .map_err(|e| e.context("API transcription request failed"))?;
     
-    match response {
-        Ok(transcription) => {
-            Ok(transcription.text)
+    // JSON formats carry the transcript in `text`, and verbose_json the audio's
+    // duration; the others are returned as-is
+    let body = match response_format.as_str() {
+        "json" | "verbose_json" => Some(response.json::<serde_json::Value>().map_err(|e| Error::Term(Box::new(e)))?),
+        _ => None,
+    };
+    let (text, reported_duration) = match &body {
+        Some(body) => {
+            let text = body.get("text").and_then(|text| text.as_str()).unwrap_or_default().to_string();
+            (text, body.get("duration").and_then(|duration| duration.as_f64()))
         },
-        Err(e) => Err(Error::Term(Box::new(format!("API transcription request failed: {}", e)))),
-    }
+        None => (String::from_utf8_lossy(&response.body).into_owned(), None),
+    };
+    let text = redactor.apply(&text);
+    
+    let value = if let (Some(diarizer), Some(body)) = (&diarizer, &body) {
+        let segments = diarizer.label(body, &audio, |text| redactor.apply(text));
+        let audio_duration_s = audio::probe_duration(&audio).or(reported_duration);
+        diarize::DiarizedTranscript { text, segments, audio_duration_s }.encode(env)
+    } else if return_duration {
+        let audio_duration_s = audio::probe_duration(&audio).or(reported_duration);
+        transcription::Transcription { text, audio_duration_s }.encode(env)
+    } else {
+        text.encode(env)
+    };
+    Ok(response.attach(env, value, &header_selection))
 }
 
-#[rustler::nif]
-fn text_to_speech(client_resource: ResourceArc<OpenAIClientResource>, input: String, opts: HashMap<String, Term>) -> NifResult<Vec<u8>> {
+#[rustler::nif(schedule = "DirtyIo")]
+fn text_to_speech<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, input: String, opts: HashMap<String, Term<'a>>) -> NifResult<Term<'a>> {
     let runtime = match tokio::runtime::Runtime::new() {
         Ok(rt) => rt,
         Err(_) => return Err(Error::Term(Box::new("Failed to create Tokio runtime"))),
     };
     
-    // Access the client field correctly through the ResourceArc
-    let client = match client_resource.client.lock() {
-        Ok(client) => client,
-        Err(e) => return Err(Error::Term(Box::new(format!("Failed to lock client: {}", e))))
-    };
+    // Requests go through the transport so response headers can be returned
+    let transport = client_resource
+        .transport_for(limits::Endpoint::Audio, &opts)?
+        .with_deadline(deadline::Deadline::decode(&opts)?);
+    let header_selection = http::decode_header_selection(&opts)?;
     
     let debug_info = format!("Input text length: {}, Opts: {:?}", input.len(), opts.keys().collect::<Vec<_>>());
     
-    // Extract options with defaults
-    let model_str = if let Some(term) = opts.get("model") {
-        if term.is_atom() {
-            "tts-1".to_string()
-        } else {
-            match term.decode::<String>() {
-                Ok(s) => s,
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode model: {:?}. {}", e, debug_info))))
-            }
-        }
-    } else {
-        "tts-1".to_string()
-    };
-    
-    let model = match model_str.as_str() {
-        "tts-1-hd" => SpeechModel::Tts1Hd,
-        _ => SpeechModel::Tts1,  // Default to tts-1
-    };
-    
-    let voice_str = if let Some(term) = opts.get("voice") {
-        if term.is_atom() {
-            "alloy".to_string()
-        } else {
-            match term.decode::<String>() {
-                Ok(s) => s,
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode voice: {:?}. {}", e, debug_info))))
-            }
-        }
-    } else {
-        "alloy".to_string()
-    };
+    // Unknown models and voices pass through as for speech_many; unknown formats are rejected
+    let speech = voice::decode_speech(&opts, "model", "response_format")?;
+    let model_str = options::get_string(&opts, "model")?.unwrap_or_else(|| "tts-1".to_string());
+    let extension = voice::extension(speech.response_format.unwrap_or(SpeechResponseFormat::Mp3));
     
-    let voice = match voice_str.as_str() {
-        "echo" => Voice::Echo,
-        "fable" => Voice::Fable,
-        "onyx" => Voice::Onyx,
-        "nova" => Voice::Nova,
-        "shimmer" => Voice::Shimmer,
-        _ => Voice::Alloy,  // Default to alloy
-    };
-    
-    let format_str = if let Some(term) = opts.get("response_format") {
-        if term.is_atom() {
-            "mp3".to_string()
-        } else {
-            match term.decode::<String>() {
-                Ok(s) => s,
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode response_format: {:?}. {}", e, debug_info))))
-            }
-        }
-    } else {
-        "mp3".to_string()
-    };
-    
-    let response_format = match format_str.as_str() {
-        "opus" => async_openai::types::SpeechResponseFormat::Opus,
-        "aac" => async_openai::types::SpeechResponseFormat::Aac,
-        "flac" => async_openai::types::SpeechResponseFormat::Flac,
-        _ => async_openai::types::SpeechResponseFormat::Mp3,
-    };
-    
-    let speed = if let Some(term) = opts.get("speed") {
-        if term.is_atom() {
-            None
-        } else {
-            match term.decode::<f32>() {
-                Ok(s) => Some(s),
-                Err(e) => return Err(Error::Term(Box::new(format!("Failed to decode speed: {:?}. {}", e, debug_info))))
-            }
-        }
-    } else {
-        None
-    };
-    
-    // Create the speech request with a binding to avoid temporary value issue
-    let mut args = CreateSpeechRequestArgs::default();
-    let mut request = args
-        .input(&input)
-        .model(model)
-        .voice(voice)
-        .response_format(response_format);
-    
-    if let Some(spd) = speed {
-        request = request.speed(spd);
+    let output = output::Output::decode(&opts)?;
+    let stage = options::get_bool(&opts, "stage")?.unwrap_or(false);
+    if stage && output != output::Output::Binary {
+        return Err(options::invalid(&options::path("stage"), "can't be combined with output"));
     }
     
-    let request = match request.build() {
-        Ok(req) => req,
-        Err(e) => return Err(Error::Term(Box::new(format!("Failed to build speech request: {:?}. {}", e, debug_info))))
-    };
+    let request = CreateSpeechRequest { input, ..speech };
     
     // Send the request and get the response
-    let response = runtime.block_on(async {
-        client.audio().speech(request).await
-    });
+    let mut response = runtime
+        .block_on(transport.post_json("/audio/speech", &request))
+        .map_err(|mut e| {
+            e.message = format!("API speech request failed: {}. {}", e.message, debug_info);
+            e
+        })?;
+    response.body = voice::tag(&client_resource.attribution()?, std::mem::take(&mut response.body), extension, &model_str);
     
-    match response {
-        Ok(bytes) => {
-            match bytes.bytes.to_vec() {
-                bytes => Ok(bytes),
-                //Err(e) => Err(Error::Term(Box::new(format!("Failed to convert bytes: {:?}. {}", e, debug_info))))
-            }
-        },
-        Err(e) => Err(Error::Term(Box::new(format!("API speech request failed: {}. {}", e, debug_info)))),
+    // With `stage: true` the audio is kept in a temp file instead of copied into a binary
+    if stage {
+        let file = staging::stage(&response.body, format!("speech.{}", extension))
+            .map_err(|e| Error::Term(Box::new(e)))?;
+        return Ok(response.attach(env, file.encode(env), &header_selection));
     }
+    
+    let audio = output
+        .encode(env, &response.body, extension)
+        .map_err(|e| Error::Term(Box::new(e)))?;
+    
+    Ok(response.attach(env, audio, &header_selection))
 }
 
+// Identifies this load of the library. A client created before the module was
+// reloaded (module purge + load) belongs to the old load's resource type and can't be
+// used with this one, so Elixir records the id a client was created under and
+// compares it before each call to fail with a clear error instead.
+static LOAD_ID: AtomicU64 = AtomicU64::new(0);
+
 // Load function to register the resource type
 fn on_load(env: Env, _info: Term) -> bool {
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    LOAD_ID.store(now.as_nanos() as u64 ^ std::process::id() as u64, Ordering::Relaxed);
+
     // Register the resource type with Rustler
-    rustler::resource!(OpenAIClientResource, env);
-    true
+    env.register::<OpenAIClientResource>().is_ok()
+        && env.register::<staging::StagedFile>().is_ok()
+        && env.register::<cancel::CancelToken>().is_ok()
+        && env.register::<streaming::ChatStream>().is_ok()
+}
+
+#[rustler::nif]
+fn nif_load_id() -> u64 {
+    LOAD_ID.load(Ordering::Relaxed)
 }
 
 // Define our atoms
@@ -491,7 +1225,38 @@ mod atoms {
         error,
         stream_chunk,
         stream_error,
-        stream_done
+        stream_done,
+        regex_replace,
+        locale_format,
+        summarize_progress,
+        map,
+        reduce,
+        eval_progress,
+        equal,
+        insert,
+        delete,
+        limit_reached,
+        stream_audio,
+        transcribe_progress,
+        rust_client,
+        image_partial,
+        image_done,
+        cancelled,
+        upload_progress,
+        dry_run,
+        refusal,
+        stream_started,
+        stream_tool_call_delta,
+        stream_usage,
+        stream_cancelled,
+        raw_json,
+        max_size_exceeded,
+        proxy_idle_timeout,
+        minute,
+        hour,
+        rate_capacity_available,
+        timeout,
+        path
     }
 }
 
@@ -500,7 +1265,7 @@ rustler::init!(
     // [ // Deprecated argument, remove the list of functions - Kept from previous edit
     //     create_client,
     //     complete_chat,
-    //     process_completion_chunk,
+    //     start_completion_stream,
     //     transcribe_audio,
     //     text_to_speech
     // ],
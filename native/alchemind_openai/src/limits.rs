@@ -0,0 +1,78 @@
+use std::time::Duration;
+
+use rustler::{Error, NifResult, ResourceArc};
+
+use crate::options::{self, Opts};
+use crate::{atoms, OpenAIClientResource};
+
+// Kinds of requests with their own limits. An hour of audio legitimately takes
+// minutes to transcribe, while a chat request that slow should fail fast.
+#[derive(Clone, Copy, Debug)]
+pub enum Endpoint {
+    Chat,
+    Audio,
+    Other,
+}
+
+// Per-attempt timeout and retry cap. Unset fields fall back to the client's default
+// limits, and unset defaults mean no timeout and retries bounded only by the backoff.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Limits {
+    pub timeout: Option<Duration>,
+    pub max_retries: Option<usize>,
+    // Whether timed out attempts are sent again. Off unless set, since a request may
+    // have been carried out even though its response never arrived.
+    pub retry_timeouts: Option<bool>,
+}
+
+impl Limits {
+    pub fn decode(opts: &Opts) -> NifResult<Self> {
+        Ok(Limits {
+            timeout: options::get_usize(opts, "timeout_ms")?.map(|ms| Duration::from_millis(ms as u64)),
+            max_retries: options::get_usize(opts, "max_retries")?,
+            retry_timeouts: options::get_bool(opts, "retry_timeouts")?,
+        })
+    }
+
+    // Fields set here take precedence over `fallback`'s
+    pub fn or(self, fallback: Limits) -> Limits {
+        Limits {
+            timeout: self.timeout.or(fallback.timeout),
+            max_retries: self.max_retries.or(fallback.max_retries),
+            retry_timeouts: self.retry_timeouts.or(fallback.retry_timeouts),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EndpointLimits {
+    default: Limits,
+    chat: Limits,
+    audio: Limits,
+}
+
+impl EndpointLimits {
+    pub fn get(&self, endpoint: Endpoint) -> Limits {
+        match endpoint {
+            Endpoint::Chat => self.chat.or(self.default),
+            Endpoint::Audio => self.audio.or(self.default),
+            Endpoint::Other => self.default,
+        }
+    }
+}
+
+// Replaces the client's limits. Limits left out of every map are unbounded.
+#[rustler::nif]
+fn set_endpoint_limits(client_resource: ResourceArc<OpenAIClientResource>, default_opts: Opts, chat_opts: Opts, audio_opts: Opts) -> NifResult<rustler::Atom> {
+    let limits = EndpointLimits {
+        default: Limits::decode(&default_opts)?,
+        chat: Limits::decode(&chat_opts)?,
+        audio: Limits::decode(&audio_opts)?,
+    };
+
+    let mut current = client_resource.limits.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock endpoint limits: {}", e))))?;
+    *current = limits;
+
+    Ok(atoms::ok())
+}
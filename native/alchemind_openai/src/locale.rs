@@ -0,0 +1,232 @@
+use std::sync::OnceLock;
+
+use regex::{Captures, Regex};
+
+// How dates are spelled out, with the month's name and the year last
+#[derive(Clone, Copy)]
+enum DateOrder {
+    // March 5, 2024
+    MonthDay,
+    // 5 March 2024
+    DayMonth,
+    // 5. März 2024
+    DayDotMonth,
+    // 5 de marzo de 2024
+    DayDeMonth,
+}
+
+// Number and date conventions of a locale, following CLDR
+pub struct Locale {
+    decimal: &'static str,
+    group: &'static str,
+    months: [&'static str; 12],
+    dates: DateOrder,
+}
+
+const ENGLISH_MONTHS: [&str; 12] = [
+    "January", "February", "March", "April", "May", "June", "July", "August", "September", "October", "November", "December",
+];
+
+// Tags are matched case-insensitively on their language and region, e.g. "de-DE"
+// or "de_de", falling back to the language alone
+const LOCALES: &[(&str, Locale)] = &[
+    ("en-us", Locale { decimal: ".", group: ",", months: ENGLISH_MONTHS, dates: DateOrder::MonthDay }),
+    ("en-gb", Locale { decimal: ".", group: ",", months: ENGLISH_MONTHS, dates: DateOrder::DayMonth }),
+    ("en", Locale { decimal: ".", group: ",", months: ENGLISH_MONTHS, dates: DateOrder::MonthDay }),
+    (
+        "de",
+        Locale {
+            decimal: ",",
+            group: ".",
+            months: ["Januar", "Februar", "März", "April", "Mai", "Juni", "Juli", "August", "September", "Oktober", "November", "Dezember"],
+            dates: DateOrder::DayDotMonth,
+        },
+    ),
+    (
+        // CLDR groups French digits with a narrow no-break space
+        "fr",
+        Locale {
+            decimal: ",",
+            group: "\u{202f}",
+            months: ["janvier", "février", "mars", "avril", "mai", "juin", "juillet", "août", "septembre", "octobre", "novembre", "décembre"],
+            dates: DateOrder::DayMonth,
+        },
+    ),
+    (
+        "es",
+        Locale {
+            decimal: ",",
+            group: ".",
+            months: ["enero", "febrero", "marzo", "abril", "mayo", "junio", "julio", "agosto", "septiembre", "octubre", "noviembre", "diciembre"],
+            dates: DateOrder::DayDeMonth,
+        },
+    ),
+    (
+        "it",
+        Locale {
+            decimal: ",",
+            group: ".",
+            months: ["gennaio", "febbraio", "marzo", "aprile", "maggio", "giugno", "luglio", "agosto", "settembre", "ottobre", "novembre", "dicembre"],
+            dates: DateOrder::DayMonth,
+        },
+    ),
+    (
+        "pt",
+        Locale {
+            decimal: ",",
+            group: ".",
+            months: ["janeiro", "fevereiro", "março", "abril", "maio", "junho", "julho", "agosto", "setembro", "outubro", "novembro", "dezembro"],
+            dates: DateOrder::DayDeMonth,
+        },
+    ),
+    (
+        "nl",
+        Locale {
+            decimal: ",",
+            group: ".",
+            months: ["januari", "februari", "maart", "april", "mei", "juni", "juli", "augustus", "september", "oktober", "november", "december"],
+            dates: DateOrder::DayMonth,
+        },
+    ),
+];
+
+// ISO dates, and numbers as models write them: digits with an optional decimal point,
+// grouped with commas or not
+fn pattern() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| {
+        Regex::new(r"\b(?P<year>\d{4})-(?P<month>\d{2})-(?P<day>\d{2})\b|(?P<int>\d{1,3}(?:,\d{3})+|\d+)(?:\.(?P<frac>\d+))?").unwrap()
+    })
+}
+
+impl Locale {
+    pub fn find(tag: &str) -> Result<&'static Locale, String> {
+        let tag = tag.trim().replace('_', "-").to_lowercase();
+        let language = tag.split('-').next().unwrap_or_default();
+        LOCALES
+            .iter()
+            .find(|(name, _)| *name == tag)
+            .or_else(|| LOCALES.iter().find(|(name, _)| *name == language))
+            .map(|(_, locale)| locale)
+            .ok_or_else(|| {
+                let names: Vec<&str> = LOCALES.iter().map(|(name, _)| *name).collect();
+                format!("must be a known locale, one of {}, got {:?}", names.join(", "), tag)
+            })
+    }
+
+    // Rewrites ISO dates and numbers in `content` the way the locale writes them, so
+    // text-to-speech reads them naturally. Numbers that are part of something else,
+    // such as versions, identifiers or times, are left alone.
+    pub fn format(&self, content: &str) -> String {
+        pattern()
+            .replace_all(content, |captures: &Captures| {
+                let matched = captures.get(0).map_or("", |m| m.as_str());
+                let formatted = if captures.name("year").is_some() {
+                    self.format_date(captures)
+                } else if standalone(content, captures) {
+                    self.format_number(captures)
+                } else {
+                    None
+                };
+                formatted.unwrap_or_else(|| matched.to_string())
+            })
+            .into_owned()
+    }
+
+    fn format_date(&self, captures: &Captures) -> Option<String> {
+        let part = |name: &str| captures[name].parse::<usize>().ok();
+        let (year, month, day) = (&captures["year"], part("month")?, part("day")?);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+            return None;
+        }
+        let month = self.months[month - 1];
+        Some(match self.dates {
+            DateOrder::MonthDay => format!("{} {}, {}", month, day, year),
+            DateOrder::DayMonth => format!("{} {} {}", day, month, year),
+            DateOrder::DayDotMonth => format!("{}. {} {}", day, month, year),
+            DateOrder::DayDeMonth => format!("{} de {} de {}", day, month, year),
+        })
+    }
+
+    // Whole parts of five or more digits are grouped. Four-digit numbers are usually
+    // years, so they stay ungrouped in every locale, as CLDR already has it for Spanish.
+    fn format_number(&self, captures: &Captures) -> Option<String> {
+        let digits = captures["int"].replace(',', "");
+        // Codes such as zip codes keep their leading zeros as written
+        if digits.len() > 1 && digits.starts_with('0') {
+            return None;
+        }
+        let mut number = if digits.len() >= 5 {
+            let first = digits.len() % 3;
+            let mut groups: Vec<&str> = if first > 0 { vec![&digits[..first]] } else { Vec::new() };
+            groups.extend((first..digits.len()).step_by(3).map(|start| &digits[start..start + 3]));
+            groups.join(self.group)
+        } else {
+            digits
+        };
+        if let Some(fraction) = captures.name("frac") {
+            number.push_str(self.decimal);
+            number.push_str(fraction.as_str());
+        }
+        Some(number)
+    }
+}
+
+// Whether a number stands on its own, rather than being part of a word, a version
+// such as 1.2.3, a list such as 1,2,3 or a number already in another format
+fn standalone(content: &str, captures: &Captures) -> bool {
+    let Some(matched) = captures.get(0) else {
+        return false;
+    };
+    let before = content[..matched.start()].chars().next_back();
+    let mut after = content[matched.end()..].chars();
+    let (next, following) = (after.next(), after.next());
+    let is_word = |c: char| c.is_alphanumeric() || c == '_';
+    let attached = before.is_some_and(|c| is_word(c) || c == '.' || c == ',')
+        || next.is_some_and(is_word)
+        || (matches!(next, Some('.' | ',')) && following.is_some_and(|c| c.is_ascii_digit()));
+    !attached
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(tag: &str, content: &str) -> String {
+        Locale::find(tag).unwrap().format(content)
+    }
+
+    #[test]
+    fn groups_numbers_of_five_or_more_digits() {
+        assert_eq!(format("en", "It cost 1234567.5 dollars"), "It cost 1,234,567.5 dollars");
+        assert_eq!(format("de", "It cost 1,234,567.89 euros"), "It cost 1.234.567,89 euros");
+        assert_eq!(format("fr", "12345 habitants"), "12\u{202f}345 habitants");
+        assert_eq!(format("de", "In 2024 it was 1234.5"), "In 2024 it was 1234,5");
+    }
+
+    #[test]
+    fn keeps_leading_zeros() {
+        assert_eq!(format("de", "Zip 02139, room 0"), "Zip 02139, room 0");
+    }
+
+    #[test]
+    fn leaves_versions_lists_and_words_alone() {
+        assert_eq!(format("de", "Version 1.2.3 of v2"), "Version 1.2.3 of v2");
+        assert_eq!(format("de", "Pick 1,2,3 or 10:30"), "Pick 1,2,3 or 10:30");
+    }
+
+    #[test]
+    fn spells_out_iso_dates() {
+        assert_eq!(format("en-US", "Due 2024-03-05."), "Due March 5, 2024.");
+        assert_eq!(format("en_GB", "Due 2024-03-05."), "Due 5 March 2024.");
+        assert_eq!(format("de-AT", "Am 2024-03-05"), "Am 5. März 2024");
+        assert_eq!(format("es", "El 2024-03-05"), "El 5 de marzo de 2024");
+        assert_eq!(format("en", "Not 2024-13-05"), "Not 2024-13-05");
+    }
+
+    #[test]
+    fn rejects_unknown_locales() {
+        let Err(message) = Locale::find("xx-YY") else { panic!("found a locale for xx-YY") };
+        assert!(message.contains("got \"xx-yy\""));
+    }
+}
@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+
+use futures_util::StreamExt;
+use rustler::{Error, NifMap, NifResult, ResourceArc};
+use serde_json::{json, Value};
+
+use crate::deadline::Deadline;
+use crate::http::{ApiError, Transport};
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::params::ChatParams;
+use crate::{chat_body, developer_indices, postprocess, prompts, resample, to_request_messages, Message, OpenAIClientResource};
+
+const DEFAULT_MODEL: &str = "omni-moderation-latest";
+
+// The moderation endpoint accepts at most 32 inputs per request
+const MAX_BATCH_SIZE: usize = 32;
+
+#[derive(NifMap)]
+struct Moderation {
+    flagged: bool,
+    // Category name, e.g. "harassment/threatening", to whether it was flagged
+    categories: HashMap<String, bool>,
+    category_scores: HashMap<String, f64>,
+}
+
+#[derive(NifMap)]
+struct ModerationResult {
+    // Position in the input list
+    index: usize,
+    result: Option<Moderation>,
+    // Set instead of `result` when the input's batch failed
+    error: Option<ApiError>,
+}
+
+fn decode_result(result: &Value) -> Moderation {
+    let categories = result
+        .get("categories")
+        .and_then(Value::as_object)
+        .map(|categories| categories.iter().map(|(name, flagged)| (name.clone(), flagged.as_bool().unwrap_or(false))).collect())
+        .unwrap_or_default();
+    let category_scores = result
+        .get("category_scores")
+        .and_then(Value::as_object)
+        .map(|scores| scores.iter().map(|(name, score)| (name.clone(), score.as_f64().unwrap_or(0.0))).collect())
+        .unwrap_or_default();
+
+    Moderation {
+        flagged: result.get("flagged").and_then(Value::as_bool).unwrap_or(false),
+        categories,
+        category_scores,
+    }
+}
+
+async fn moderate_batch(transport: &Transport, model: &str, inputs: Vec<String>) -> Result<Vec<Moderation>, ApiError> {
+    let count = inputs.len();
+    let response: Value = transport
+        .post_json("/moderations", &json!({"model": model, "input": inputs}))
+        .await
+        .map_err(|e| e.context("API moderation request failed"))?
+        .json()
+        .map_err(ApiError::local)?;
+
+    let results: Vec<Moderation> = response
+        .get("results")
+        .and_then(Value::as_array)
+        .map(|results| results.iter().map(decode_result).collect())
+        .unwrap_or_default();
+    if results.len() != count {
+        return Err(ApiError::local(format!("Expected {} moderation results, got {}", count, results.len())));
+    }
+    Ok(results)
+}
+
+// Moderates any number of inputs, split into API-sized batches that run with
+// bounded concurrency. Results are aligned with the inputs; a failed batch
+// doesn't stop the others, and its inputs get its error in place of a result.
+#[rustler::nif(schedule = "DirtyIo")]
+fn moderate_many(client_resource: ResourceArc<OpenAIClientResource>, inputs: Vec<String>, opts: Opts) -> NifResult<Vec<ModerationResult>> {
+    let model = options::get_string(&opts, "model")?.unwrap_or_else(|| DEFAULT_MODEL.to_string());
+    let batch_size = options::get_usize(&opts, "batch_size")?.unwrap_or(MAX_BATCH_SIZE).clamp(1, MAX_BATCH_SIZE);
+    let concurrency = options::get_usize(&opts, "concurrency")?.unwrap_or(4).max(1);
+    let transport = client_resource.transport_for(Endpoint::Other, &opts)?.with_deadline(Deadline::decode(&opts)?);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let batches: Vec<(usize, Vec<String>)> = inputs
+        .chunks(batch_size)
+        .enumerate()
+        .map(|(batch, inputs)| (batch * batch_size, inputs.to_vec()))
+        .collect();
+
+    let results = runtime.block_on(async {
+        futures_util::stream::iter(batches)
+            .map(|(start, inputs)| {
+                let transport = &transport;
+                let model = &model;
+                async move {
+                    let count = inputs.len();
+                    (start, count, moderate_batch(transport, model, inputs).await)
+                }
+            })
+            .buffered(concurrency)
+            .collect::<Vec<_>>()
+            .await
+    });
+
+    let mut aligned = Vec::with_capacity(inputs.len());
+    for (start, count, batch) in results {
+        match batch {
+            Ok(moderations) => aligned.extend(
+                moderations
+                    .into_iter()
+                    .enumerate()
+                    .map(|(offset, moderation)| ModerationResult { index: start + offset, result: Some(moderation), error: None }),
+            ),
+            Err(error) => aligned.extend((start..start + count).map(|index| ModerationResult {
+                index,
+                result: None,
+                error: Some(error.clone()),
+            })),
+        }
+    }
+    Ok(aligned)
+}
+
+#[derive(NifMap)]
+struct ModeratedCompletion {
+    // The first choice's text, after the client's post-processors and with its
+    // attribution marker
+    content: String,
+    // Why the model declined, in which case there is nothing to moderate
+    refusal: Option<String>,
+    moderation: Option<Moderation>,
+}
+
+// Options of `complete_chat` that need more than one plain-text choice, more than one
+// request, or a result other than text
+const UNSUPPORTED_OPTIONS: &[&str] = &["n", "tools", "tool_choice", "json", "return", "dry_run", "max_continuations", "retry_schedule"];
+
+// Completes a conversation and moderates the output in one call, so nothing the
+// moderation model flags is returned as if it were fine. Only the first choice's
+// text is completed and moderated; options are those of `complete_chat` that shape a
+// plain-text completion, with the moderation model as `moderation_model`.
+#[rustler::nif(schedule = "DirtyIo")]
+fn complete_and_moderate(client_resource: ResourceArc<OpenAIClientResource>, messages: Vec<Message>, model: &str, opts: Opts) -> NifResult<ModeratedCompletion> {
+    if let Some(key) = UNSUPPORTED_OPTIONS.iter().find(|key| options::is_set(&opts, key)) {
+        return Err(options::invalid(&options::path(key), "isn't supported by complete_and_moderate"));
+    }
+    let moderation_model = options::get_string(&opts, "moderation_model")?.unwrap_or_else(|| DEFAULT_MODEL.to_string());
+    let deadline = Deadline::decode(&opts)?;
+    let chat = client_resource.transport_for(Endpoint::Chat, &opts)?.with_deadline(deadline);
+    let moderations = client_resource.transport_for(Endpoint::Other, &opts)?.with_deadline(deadline);
+    let params = ChatParams::decode(&opts)?;
+    if params.structured() {
+        return Err(options::invalid(&options::path("response_format"), "can't be a JSON schema with complete_and_moderate"));
+    }
+    let sampling = resample::decode_schedule(&opts)?[0];
+    let attribution = client_resource.attribution()?;
+
+    let messages = prompts::splice(&client_resource, &opts, messages)?;
+    let developer_indices = developer_indices(&messages);
+    let chat_messages = to_request_messages(messages).map_err(|e| Error::Term(Box::new(e)))?;
+    let body = chat_body(model, chat_messages, &developer_indices, None, &params, &sampling).map_err(|e| Error::Term(Box::new(e)))?;
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+    let raw: Value = runtime
+        .block_on(chat.post_json("/chat/completions", &body))
+        .map_err(|e| e.context("API request failed"))?
+        .json()
+        .map_err(|e| Error::Term(Box::new(e)))?;
+    client_resource.stats.record_response(&raw);
+    if raw["choices"].as_array().is_none_or(Vec::is_empty) {
+        return Err(Error::Term(Box::new("No completion choices returned")));
+    }
+    let message = &raw["choices"][0]["message"];
+    if let Some(refusal) = message["refusal"].as_str() {
+        return Ok(ModeratedCompletion { content: String::new(), refusal: Some(refusal.to_string()), moderation: None });
+    }
+    let content = message["content"].as_str().unwrap_or_default();
+    let processors = client_resource.post_processors.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock post-processors: {}", e))))?;
+    let content = postprocess::apply(&processors, content);
+    drop(processors);
+
+    let moderation = runtime
+        .block_on(moderate_batch(&moderations, &moderation_model, vec![content.clone()]))?
+        .pop()
+        .ok_or_else(|| Error::Term(Box::new("No moderation result returned")))?;
+    // The marker is added after moderation, which only judges what the model wrote
+    let model = raw["model"].as_str().unwrap_or(model);
+    let suffix = attribution.as_ref().and_then(|attribution| attribution.text_suffix(&content, &attribution.marker(model)));
+    let content = match suffix {
+        Some(suffix) => format!("{}{}", content, suffix),
+        None => content,
+    };
+    Ok(ModeratedCompletion { content, refusal: None, moderation: Some(moderation) })
+}
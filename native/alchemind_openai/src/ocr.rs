@@ -0,0 +1,154 @@
+use async_openai::types::{
+    ChatCompletionRequestMessageContentPartImageArgs, ChatCompletionRequestMessageContentPartTextArgs,
+    ChatCompletionRequestSystemMessageArgs, ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+    CreateChatCompletionResponse, ImageUrlArgs, ImageUrlDetail,
+};
+use base64::Engine;
+use rustler::{Binary, Env, Error, NifMap, NifResult, ResourceArc, Term};
+use std::io::Cursor;
+
+use crate::deadline::Deadline;
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::{json, postprocess, OpenAIClientResource};
+
+const OCR_PROMPT: &str = "You are an OCR engine. Transcribe all text visible in the image exactly as written, \
+preserving line breaks, reading order, punctuation and capitalization. Do not describe the image, \
+summarize, translate or correct spelling. If no text is visible, reply with an empty message.";
+
+const LAYOUT_PROMPT: &str = "Reply only with a JSON object of the form \
+{\"text\": \"<full transcription>\", \"blocks\": [{\"type\": \"heading|paragraph|list|table|caption|other\", \"text\": \"<block text>\"}]} \
+where blocks follow the reading order of the page.";
+
+#[derive(NifMap)]
+struct OcrResult<'a> {
+    text: String,
+    layout: Option<Term<'a>>,
+}
+
+// Grayscale and contrast-stretch the image, re-encoding it as PNG
+fn preprocess(image_binary: &[u8], contrast: f32) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(image_binary)
+        .map_err(|e| format!("Failed to decode image: {}", e))?;
+
+    let processed = image.grayscale().adjust_contrast(contrast);
+
+    let mut encoded = Cursor::new(Vec::new());
+    processed
+        .write_to(&mut encoded, image::ImageOutputFormat::Png)
+        .map_err(|e| format!("Failed to encode image: {}", e))?;
+
+    Ok(encoded.into_inner())
+}
+
+pub fn data_url(image_binary: &[u8]) -> Result<String, String> {
+    let mime = match image::guess_format(image_binary) {
+        Ok(image::ImageFormat::Png) => "image/png",
+        Ok(image::ImageFormat::Jpeg) => "image/jpeg",
+        Ok(image::ImageFormat::WebP) => "image/webp",
+        Ok(image::ImageFormat::Gif) => "image/gif",
+        _ => return Err("Unsupported image format, expected PNG, JPEG, WebP or GIF".to_string()),
+    };
+
+    Ok(format!(
+        "data:{};base64,{}",
+        mime,
+        base64::engine::general_purpose::STANDARD.encode(image_binary)
+    ))
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn ocr_image<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, image_binary: Binary, opts: Opts<'a>) -> NifResult<OcrResult<'a>> {
+    let model = options::get_string(&opts, "model")?.unwrap_or_else(|| "gpt-4o".to_string());
+    let with_layout = options::get_bool(&opts, "layout")?.unwrap_or(false);
+    let preprocess_image = options::get_bool(&opts, "preprocess")?.unwrap_or(true);
+    let contrast = options::get_f32(&opts, "contrast")?.unwrap_or(20.0);
+    let language = options::get_string(&opts, "language")?;
+    let detail = match options::get_string(&opts, "detail")?.as_deref() {
+        Some("low") => ImageUrlDetail::Low,
+        Some("auto") => ImageUrlDetail::Auto,
+        _ => ImageUrlDetail::High,
+    };
+
+    let image_binary = if preprocess_image {
+        preprocess(&image_binary, contrast).map_err(|e| Error::Term(Box::new(e)))?
+    } else {
+        image_binary.to_vec()
+    };
+    let url = data_url(&image_binary).map_err(|e| Error::Term(Box::new(e)))?;
+
+    let mut system_prompt = OCR_PROMPT.to_string();
+    if let Some(lang) = language {
+        system_prompt.push_str(&format!(" The text is expected to be in {}.", lang));
+    }
+    if with_layout {
+        system_prompt.push(' ');
+        system_prompt.push_str(LAYOUT_PROMPT);
+    }
+
+    let system_message = ChatCompletionRequestSystemMessageArgs::default()
+        .content(system_prompt)
+        .build()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to build system message: {}", e))))?;
+
+    let text_part = ChatCompletionRequestMessageContentPartTextArgs::default()
+        .text("Transcribe the text in this image.")
+        .build()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to build text part: {}", e))))?;
+
+    let image_part = ChatCompletionRequestMessageContentPartImageArgs::default()
+        .image_url(
+            ImageUrlArgs::default()
+                .url(url)
+                .detail(detail)
+                .build()
+                .map_err(|e| Error::Term(Box::new(format!("Failed to build image url: {}", e))))?,
+        )
+        .build()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to build image part: {}", e))))?;
+
+    let user_message = ChatCompletionRequestUserMessageArgs::default()
+        .content(vec![text_part.into(), image_part.into()])
+        .build()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to build user message: {}", e))))?;
+
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .messages(vec![system_message.into(), user_message.into()])
+        .temperature(0.0)
+        .build()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to build request: {}", e))))?;
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let transport = client_resource.transport_for(Endpoint::Chat, &opts)?.with_deadline(Deadline::decode(&opts)?);
+
+    let completion: CreateChatCompletionResponse = runtime
+        .block_on(transport.post_typed("/chat/completions", &request))
+        .map_err(|e| Error::Term(Box::new(format!("API request failed: {}", e.message))))?;
+
+    let content = completion
+        .choices
+        .first()
+        .and_then(|choice| choice.message.content.clone())
+        .unwrap_or_default();
+
+    if !with_layout {
+        return Ok(OcrResult { text: content, layout: None });
+    }
+
+    // Fall back to the raw content when the model ignores the JSON instructions
+    match postprocess::parse_first_json(&content) {
+        Some(value) => {
+            let text = value
+                .get("text")
+                .and_then(|t| t.as_str())
+                .map(str::to_string)
+                .unwrap_or_else(|| content.clone());
+            let layout = value.get("blocks").map(|blocks| json::to_term(env, blocks));
+            Ok(OcrResult { text, layout })
+        },
+        None => Ok(OcrResult { text: content, layout: None }),
+    }
+}
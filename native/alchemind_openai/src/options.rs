@@ -0,0 +1,164 @@
+use rustler::types::map::MapIterator;
+use rustler::{Decoder, Error, LocalPid, NifResult, Term, TermType};
+use std::collections::HashMap;
+use std::fmt::Display;
+
+// Options are passed from Elixir as a map with string keys; nil values fall back to the default
+pub type Opts<'a> = HashMap<String, Term<'a>>;
+
+// Longest string shown in a validation error before it is cut off
+const MAX_SHOWN_STRING: usize = 32;
+
+fn present<'a>(opts: &Opts<'a>, key: &str) -> Option<Term<'a>> {
+    opts.get(key).copied().filter(|term| !is_nil(*term))
+}
+
+fn is_nil(term: Term) -> bool {
+    term.is_atom() && term.atom_to_string().map(|a| a == "nil").unwrap_or(false)
+}
+
+// Full path of a top-level option, as shown in validation errors
+// Whether `key` is given and not nil
+pub fn is_set(opts: &Opts, key: &str) -> bool {
+    present(opts, key).is_some()
+}
+
+pub fn path(key: &str) -> String {
+    format!("opts.{}", key)
+}
+
+// Describes a term by its type, and its value when short, e.g. `atom :json`
+pub fn describe(term: Term) -> String {
+    match term.get_type() {
+        TermType::Atom => term.atom_to_string().map(|atom| format!("atom :{}", atom)).unwrap_or_else(|_| "atom".to_string()),
+        TermType::Binary => match term.decode::<String>() {
+            Ok(string) if string.chars().count() > MAX_SHOWN_STRING => {
+                format!("string {:?}...", string.chars().take(MAX_SHOWN_STRING).collect::<String>())
+            },
+            Ok(string) => format!("string {:?}", string),
+            Err(_) => "binary".to_string(),
+        },
+        TermType::Integer => term.decode::<i64>().map(|n| format!("integer {}", n)).unwrap_or_else(|_| "integer".to_string()),
+        TermType::Float => term.decode::<f64>().map(|n| format!("float {}", n)).unwrap_or_else(|_| "float".to_string()),
+        TermType::List => "list".to_string(),
+        TermType::Map => "map".to_string(),
+        TermType::Tuple => "tuple".to_string(),
+        TermType::Pid => "pid".to_string(),
+        TermType::Ref => "reference".to_string(),
+        TermType::Fun => "function".to_string(),
+        TermType::Port => "port".to_string(),
+        _ => "unknown term".to_string(),
+    }
+}
+
+// Every option that has the wrong type fails with this error, e.g.
+// `opts.response_format expected string, got atom :json`
+pub fn type_error(path: &str, expected: &str, term: Term) -> Error {
+    Error::Term(Box::new(format!("{} expected {}, got {}", path, expected, describe(term))))
+}
+
+// An option of the right type with a value that isn't allowed, e.g.
+// `opts.top_logprobs must be at most 20, got 25`
+pub fn invalid(path: &str, message: impl Display) -> Error {
+    Error::Term(Box::new(format!("{} {}", path, message)))
+}
+
+pub fn decode_at<'a, T: Decoder<'a>>(term: Term<'a>, path: &str, expected: &str) -> NifResult<T> {
+    term.decode().map_err(|_| type_error(path, expected, term))
+}
+
+// Looks up `key` in options nested at `parent`, such as a retry_schedule step
+pub fn get_in<'a, T: Decoder<'a>>(opts: &Opts<'a>, parent: &str, key: &str, expected: &str) -> NifResult<Option<T>> {
+    present(opts, key)
+        .map(|term| decode_at(term, &format!("{}.{}", parent, key), expected))
+        .transpose()
+}
+
+fn get<'a, T: Decoder<'a>>(opts: &Opts<'a>, key: &str, expected: &str) -> NifResult<Option<T>> {
+    get_in(opts, "opts", key, expected)
+}
+
+pub fn get_atom(opts: &Opts, key: &str) -> NifResult<Option<String>> {
+    match present(opts, key) {
+        Some(term) if term.is_atom() => term.atom_to_string().map(Some),
+        Some(term) => Err(type_error(&path(key), "atom", term)),
+        None => Ok(None),
+    }
+}
+
+pub fn get_string(opts: &Opts, key: &str) -> NifResult<Option<String>> {
+    get(opts, key, "string")
+}
+
+pub fn get_bool(opts: &Opts, key: &str) -> NifResult<Option<bool>> {
+    get(opts, key, "boolean")
+}
+
+pub fn get_usize(opts: &Opts, key: &str) -> NifResult<Option<usize>> {
+    get(opts, key, "non-negative integer")
+}
+
+// Integers are accepted wherever a float is, since Elixir callers often write `1` for `1.0`
+pub fn decode_number(term: Term, path: &str) -> NifResult<f64> {
+    match term.get_type() {
+        TermType::Integer => term.decode::<i64>().map(|n| n as f64).map_err(|_| type_error(path, "number", term)),
+        _ => decode_at(term, path, "number"),
+    }
+}
+
+pub fn get_f32_in(opts: &Opts, parent: &str, key: &str) -> NifResult<Option<f32>> {
+    present(opts, key)
+        .map(|term| decode_number(term, &format!("{}.{}", parent, key)).map(|n| n as f32))
+        .transpose()
+}
+
+pub fn get_f32(opts: &Opts, key: &str) -> NifResult<Option<f32>> {
+    get_f32_in(opts, "opts", key)
+}
+
+pub fn get_f64(opts: &Opts, key: &str) -> NifResult<Option<f64>> {
+    present(opts, key).map(|term| decode_number(term, &path(key))).transpose()
+}
+
+pub fn get_pid(opts: &Opts, key: &str) -> NifResult<Option<LocalPid>> {
+    get(opts, key, "pid")
+}
+
+pub fn get_strings(opts: &Opts, key: &str) -> NifResult<Option<Vec<String>>> {
+    get(opts, key, "list of strings")
+}
+
+pub fn get_i64(opts: &Opts, key: &str) -> NifResult<Option<i64>> {
+    get(opts, key, "integer")
+}
+
+pub fn get_map<'a, K, V>(opts: &Opts<'a>, key: &str) -> NifResult<Option<HashMap<K, V>>>
+where
+    K: Decoder<'a> + Eq + std::hash::Hash,
+    V: Decoder<'a>,
+{
+    get(opts, key, "map")
+}
+
+pub fn get_list<'a, T: Decoder<'a>>(opts: &Opts<'a>, key: &str) -> NifResult<Option<Vec<T>>> {
+    get(opts, key, "list")
+}
+
+// Entries of a map or keyword list, keyed by atom or string
+pub fn entries<'a>(term: Term<'a>, path: &str) -> NifResult<Vec<(String, Term<'a>)>> {
+    let pairs: Vec<(Term, Term)> = match term.get_type() {
+        TermType::Map => term.decode::<MapIterator>().map_err(|_| type_error(path, "map or keyword list", term))?.collect(),
+        _ => decode_at(term, path, "map or keyword list")?,
+    };
+    pairs
+        .into_iter()
+        .map(|(key, value)| {
+            let key = match key.get_type() {
+                TermType::Atom => key.atom_to_string().ok(),
+                _ => key.decode::<String>().ok(),
+            }
+            .ok_or_else(|| type_error(&format!("{} key", path), "atom or string", key))?;
+            Ok((key, value))
+        })
+        .collect()
+}
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use base64::Engine;
+use rustler::{Encoder, Env, NifResult, OwnedBinary, Term};
+
+use crate::options::{self, Opts};
+use crate::staging;
+
+// How audio and image payloads are returned. A binary is copied whole whenever it
+// crosses to another node, so `output: :base64` returns it as text instead, and
+// `output: :file` writes it to a temp file and returns the path.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Output {
+    Binary,
+    Base64,
+    File,
+}
+
+impl Output {
+    pub fn decode(opts: &Opts) -> NifResult<Self> {
+        match options::get_atom(opts, "output")?.as_deref() {
+            None | Some("binary") => Ok(Output::Binary),
+            Some("base64") => Ok(Output::Base64),
+            Some("file") => Ok(Output::File),
+            Some(other) => Err(options::invalid(&options::path("output"), format!("must be :binary, :base64 or :file, got :{}", other))),
+        }
+    }
+
+    // `extension` ends the file name with `output: :file`, e.g. "mp3"
+    pub fn encode<'a>(self, env: Env<'a>, data: &[u8], extension: &str) -> Result<Term<'a>, String> {
+        match self {
+            Output::Binary => {
+                let mut binary = OwnedBinary::new(data.len()).ok_or("Failed to allocate binary")?;
+                binary.as_mut_slice().copy_from_slice(data);
+                Ok(binary.release(env).encode(env))
+            },
+            Output::Base64 => Ok(base64::engine::general_purpose::STANDARD.encode(data).encode(env)),
+            Output::File => write_file(data, extension).map(|path| path.display().to_string().encode(env)),
+        }
+    }
+}
+
+// Files get random names so they never clash, even across restarts. Unlike staged
+// files they belong to the caller, who removes them when done. They share the
+// process's private temp dir and, like staged files, only its user can read them.
+fn write_file(data: &[u8], extension: &str) -> Result<PathBuf, String> {
+    let dir = staging::private_dir()?.join("output");
+    staging::create_private_dir(&dir)?;
+    let path = dir.join(format!("{}.{}", staging::random_hex(16)?, extension));
+    staging::write_private(&path, data)?;
+    Ok(path)
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+
+use async_openai::types::{
+    ChatCompletionNamedToolChoice, ChatCompletionResponseFormat, ChatCompletionResponseFormatType, ChatCompletionTool,
+    ChatCompletionToolChoiceOption, ChatCompletionToolType, CreateChatCompletionRequestArgs, FunctionName, FunctionObject, Stop,
+};
+use rustler::types::tuple::get_tuple;
+use rustler::{NifResult, Term};
+use serde_json::{json, Map, Value};
+
+use crate::json;
+use crate::options::{self, Opts};
+
+// Generation options shared by every chat request, streaming or not. Sampling
+// (temperature/top_p) lives in `resample` since it can change between attempts.
+pub struct ChatParams {
+    max_tokens: Option<u16>,
+    // Reasoning models reject max_tokens and take this instead, which also caps their
+    // hidden reasoning tokens. Set on the serialized request, like `json_schema`.
+    max_completion_tokens: Option<u32>,
+    // "low", "medium" or "high", for reasoning models. Also set on the serialized request.
+    reasoning_effort: Option<String>,
+    stop: Option<Vec<String>>,
+    // Token id to bias, sent with the ids as strings as the API expects
+    logit_bias: Option<HashMap<String, serde_json::Value>>,
+    // Best-effort determinism; the response's system_fingerprint tells whether the backend changed
+    seed: Option<i64>,
+    logprobs: Option<bool>,
+    top_logprobs: Option<u8>,
+    // End user the request is made for, for OpenAI's abuse monitoring
+    user: Option<String>,
+    response_format: Option<ChatCompletionResponseFormatType>,
+    // Structured output format. async-openai's request type can't express it, so it
+    // is set on the serialized request instead.
+    json_schema: Option<Value>,
+    tools: Option<Vec<ChatCompletionTool>>,
+    tool_choice: Option<ToolChoice>,
+    // false makes the model call at most one tool per turn, for sequential executors.
+    // Set on the serialized request, like `json_schema`.
+    parallel_tool_calls: Option<bool>,
+    // Text most of the output is expected to repeat, e.g. a file being edited, which
+    // speeds up generation. Set on the serialized request, like `json_schema`.
+    prediction: Option<String>,
+    // Keeps the completion for the dashboard and evals, tagged with `metadata`, both
+    // set on the serialized request
+    store: Option<bool>,
+    metadata: Option<serde_json::Map<String, Value>>,
+    // Processing tier trading cost against latency. Set on the serialized request.
+    service_tier: Option<String>,
+    // Search settings for the search-preview models, which look up the web before
+    // answering. Set on the serialized request.
+    web_search_options: Option<Value>,
+    // Sends tools with the deprecated function calling API, for older gateways. The
+    // serialized request is rewritten by `legacy::downgrade`.
+    legacy_functions: bool,
+}
+
+enum ToolChoice {
+    Typed(ChatCompletionToolChoiceOption),
+    // Not in async-openai's enum, so set on the serialized request
+    Required,
+}
+
+// The API accepts at most four stop sequences
+const MAX_STOP_SEQUENCES: usize = 4;
+
+// and at most 20 alternatives per token position
+const MAX_TOP_LOGPROBS: usize = 20;
+
+const REASONING_EFFORTS: &[&str] = &["low", "medium", "high"];
+
+const SERVICE_TIERS: &[&str] = &["auto", "default", "flex", "priority"];
+
+const SEARCH_CONTEXT_SIZES: &[&str] = &["low", "medium", "high"];
+
+// Fields of an approximate user location
+const LOCATION_FIELDS: &[&str] = &["country", "region", "city", "timezone"];
+
+// Limits the API puts on stored completions' metadata
+const MAX_METADATA_PAIRS: usize = 16;
+const MAX_METADATA_KEY_LENGTH: usize = 64;
+const MAX_METADATA_VALUE_LENGTH: usize = 512;
+
+impl ChatParams {
+    pub fn decode(opts: &Opts) -> NifResult<Self> {
+        let max_tokens = match options::get_usize(opts, "max_tokens")? {
+            Some(max) => Some(
+                u16::try_from(max)
+                    .map_err(|_| options::invalid(&options::path("max_tokens"), format!("must be at most {}, got {}", u16::MAX, max)))?,
+            ),
+            None => None,
+        };
+
+        let max_completion_tokens = match options::get_usize(opts, "max_completion_tokens")? {
+            Some(_) if max_tokens.is_some() => {
+                return Err(options::invalid(&options::path("max_completion_tokens"), "can't be combined with max_tokens"));
+            },
+            Some(max) => Some(u32::try_from(max).map_err(|_| {
+                options::invalid(&options::path("max_completion_tokens"), format!("must be at most {}, got {}", u32::MAX, max))
+            })?),
+            None => None,
+        };
+
+        // An atom or string
+        let reasoning_effort = match opts.get("reasoning_effort") {
+            Some(term) if term.is_atom() => options::get_atom(opts, "reasoning_effort")?,
+            _ => options::get_string(opts, "reasoning_effort")?,
+        };
+        if let Some(effort) = &reasoning_effort {
+            if !REASONING_EFFORTS.contains(&effort.as_str()) {
+                return Err(options::invalid(
+                    &options::path("reasoning_effort"),
+                    format!("must be :low, :medium or :high, got {}", effort),
+                ));
+            }
+        }
+
+        // A single string or a list of them
+        let stop = match opts.get("stop") {
+            Some(term) if term.is_binary() => options::get_string(opts, "stop")?.map(|stop| vec![stop]),
+            _ => options::get_strings(opts, "stop")?,
+        };
+        if let Some(stop) = &stop {
+            if stop.is_empty() || stop.len() > MAX_STOP_SEQUENCES {
+                return Err(options::invalid(
+                    &options::path("stop"),
+                    format!("must have between 1 and {} sequences, got {}", MAX_STOP_SEQUENCES, stop.len()),
+                ));
+            }
+            if stop.iter().any(String::is_empty) {
+                return Err(options::invalid(&options::path("stop"), "can't contain empty sequences"));
+            }
+        }
+
+        let logit_bias = match options::get_map::<u32, Term>(opts, "logit_bias")? {
+            Some(biases) => {
+                let biases = biases
+                    .into_iter()
+                    .map(|(token, bias)| Ok((token, options::decode_number(bias, &format!("opts.logit_bias.{}", token))?)))
+                    .collect::<NifResult<HashMap<u32, f64>>>()?;
+                if let Some((token, bias)) = biases.iter().find(|(_, bias)| !(-100.0..=100.0).contains(*bias)) {
+                    return Err(options::invalid(
+                        &format!("opts.logit_bias.{}", token),
+                        format!("must be between -100 and 100, got {}", bias),
+                    ));
+                }
+                Some(biases.into_iter().map(|(token, bias)| (token.to_string(), serde_json::json!(bias))).collect())
+            },
+            None => None,
+        };
+
+        let seed = options::get_i64(opts, "seed")?;
+
+        let logprobs = options::get_bool(opts, "logprobs")?;
+        let top_logprobs = match options::get_usize(opts, "top_logprobs")? {
+            Some(count) if count > MAX_TOP_LOGPROBS => {
+                return Err(options::invalid(
+                    &options::path("top_logprobs"),
+                    format!("must be at most {}, got {}", MAX_TOP_LOGPROBS, count),
+                ));
+            },
+            Some(_) if logprobs != Some(true) => {
+                return Err(options::invalid(&options::path("top_logprobs"), "requires logprobs: true"));
+            },
+            count => count.map(|count| count as u8),
+        };
+
+        let user = options::get_string(opts, "user")?;
+
+        // `:json_object` or `:text`, also accepted as strings, or a `{:json_schema, ...}` tuple
+        let (response_format, json_schema) = match opts.get("response_format") {
+            Some(term) if term.is_tuple() => (None, Some(decode_json_schema(*term)?)),
+            Some(term) if term.is_atom() => (options::get_atom(opts, "response_format")?, None),
+            _ => (options::get_string(opts, "response_format")?, None),
+        };
+        let response_format = match response_format.as_deref() {
+            None => None,
+            Some("json_object") => Some(ChatCompletionResponseFormatType::JsonObject),
+            Some("text") => Some(ChatCompletionResponseFormatType::Text),
+            Some(other) => {
+                return Err(options::invalid(
+                    &options::path("response_format"),
+                    format!("must be :json_object, :text or {{:json_schema, ...}}, got {}", other),
+                ))
+            },
+        };
+
+        let tools = match options::get_list::<Term>(opts, "tools")? {
+            Some(tools) => Some(
+                tools
+                    .into_iter()
+                    .enumerate()
+                    .map(|(index, tool)| decode_tool(tool, &format!("opts.tools[{}]", index)))
+                    .collect::<NifResult<Vec<_>>>()?,
+            ),
+            None => None,
+        };
+
+        // `:none`, `:auto`, `:required`, or the name of a function to call
+        let tool_choice = match opts.get("tool_choice") {
+            Some(term) if term.is_atom() => match options::get_atom(opts, "tool_choice")?.as_deref() {
+                None => None,
+                Some("none") => Some(ToolChoice::Typed(ChatCompletionToolChoiceOption::None)),
+                Some("auto") => Some(ToolChoice::Typed(ChatCompletionToolChoiceOption::Auto)),
+                Some("required") => Some(ToolChoice::Required),
+                Some(other) => {
+                    return Err(options::invalid(
+                        &options::path("tool_choice"),
+                        format!("must be :none, :auto, :required or a function name, got :{}", other),
+                    ))
+                },
+            },
+            _ => options::get_string(opts, "tool_choice")?.map(|name| {
+                ToolChoice::Typed(ChatCompletionToolChoiceOption::Named(ChatCompletionNamedToolChoice {
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionName { name },
+                }))
+            }),
+        };
+
+        let parallel_tool_calls = options::get_bool(opts, "parallel_tool_calls")?;
+        if parallel_tool_calls.is_some() && tools.is_none() {
+            return Err(options::invalid(&options::path("parallel_tool_calls"), "requires tools"));
+        }
+
+        let prediction = options::get_string(opts, "prediction")?;
+
+        let store = options::get_bool(opts, "store")?;
+        let metadata = match options::get_map::<Term, Term>(opts, "metadata")? {
+            Some(pairs) => Some(decode_metadata(pairs)?),
+            None => None,
+        };
+
+        // An atom or string
+        let service_tier = match opts.get("service_tier") {
+            Some(term) if term.is_atom() => options::get_atom(opts, "service_tier")?,
+            _ => options::get_string(opts, "service_tier")?,
+        };
+        if let Some(tier) = &service_tier {
+            if !SERVICE_TIERS.contains(&tier.as_str()) {
+                return Err(options::invalid(
+                    &options::path("service_tier"),
+                    format!("must be :auto, :default, :flex or :priority, got {}", tier),
+                ));
+            }
+        }
+
+        let web_search_options = match opts.get("web_search_options") {
+            Some(term) if term.atom_to_string().ok().as_deref() != Some("nil") => Some(decode_web_search_options(*term)?),
+            _ => None,
+        };
+
+        let legacy_functions = options::get_bool(opts, "legacy_functions")?.unwrap_or(false);
+        if legacy_functions {
+            if let Some(ToolChoice::Required) = tool_choice {
+                return Err(options::invalid(&options::path("tool_choice"), ":required isn't supported with legacy_functions"));
+            }
+            if parallel_tool_calls.is_some() {
+                return Err(options::invalid(&options::path("parallel_tool_calls"), "isn't supported with legacy_functions"));
+            }
+        }
+
+        // The API rejects a tool_choice without tools, and a named function that isn't one of them
+        match (&tool_choice, &tools) {
+            (Some(_), None) => return Err(options::invalid(&options::path("tool_choice"), "requires tools")),
+            (Some(ToolChoice::Typed(ChatCompletionToolChoiceOption::Named(named))), Some(tools))
+                if !tools.iter().any(|tool| tool.function.name == named.function.name) =>
+            {
+                return Err(options::invalid(
+                    &options::path("tool_choice"),
+                    format!("names {:?}, which isn't one of the tools", named.function.name),
+                ));
+            },
+            _ => {},
+        }
+
+        Ok(ChatParams {
+            max_tokens,
+            max_completion_tokens,
+            reasoning_effort,
+            stop,
+            logit_bias,
+            seed,
+            logprobs,
+            top_logprobs,
+            user,
+            response_format,
+            json_schema,
+            tools,
+            tool_choice,
+            parallel_tool_calls,
+            prediction,
+            store,
+            metadata,
+            service_tier,
+            web_search_options,
+            legacy_functions,
+        })
+    }
+
+    // Whether the content is structured output that should be decoded as JSON
+    pub fn structured(&self) -> bool {
+        self.json_schema.is_some()
+    }
+
+    pub fn legacy_functions(&self) -> bool {
+        self.legacy_functions
+    }
+
+    // Sets the options async-openai's request type can't express on the serialized request
+    pub fn patch(&self, body: &mut Value) {
+        if let Some(max) = self.max_completion_tokens {
+            body["max_completion_tokens"] = json!(max);
+        }
+        if let Some(effort) = &self.reasoning_effort {
+            body["reasoning_effort"] = json!(effort);
+        }
+        if let Some(format) = &self.json_schema {
+            body["response_format"] = format.clone();
+        }
+        for (key, value) in self.stream_fields() {
+            body[key] = value;
+        }
+        if let Some(prediction) = &self.prediction {
+            body["prediction"] = json!({"type": "content", "content": prediction});
+        }
+        if let Some(store) = self.store {
+            body["store"] = json!(store);
+        }
+        if let Some(metadata) = &self.metadata {
+            body["metadata"] = Value::Object(metadata.clone());
+        }
+        if let Some(tier) = &self.service_tier {
+            body["service_tier"] = json!(tier);
+        }
+        if let Some(search) = &self.web_search_options {
+            body["web_search_options"] = search.clone();
+        }
+    }
+
+    // The part of `patch` streams support, set on a streamed request's body
+    pub fn stream_fields(&self) -> Map<String, Value> {
+        let mut fields = Map::new();
+        if let Some(ToolChoice::Required) = self.tool_choice {
+            fields.insert("tool_choice".to_string(), json!("required"));
+        }
+        if let Some(parallel) = self.parallel_tool_calls {
+            fields.insert("parallel_tool_calls".to_string(), json!(parallel));
+        }
+        fields
+    }
+
+    // Streams deliver text and tool call deltas, from the typed request and `stream_fields`
+    pub fn check_streamable(&self) -> NifResult<()> {
+        if self.json_schema.is_some() {
+            return Err(options::invalid(&options::path("response_format"), "{:json_schema, ...} is not supported when streaming"));
+        }
+        let set = [
+            ("legacy_functions", self.legacy_functions),
+            ("max_completion_tokens", self.max_completion_tokens.is_some()),
+            ("reasoning_effort", self.reasoning_effort.is_some()),
+            ("prediction", self.prediction.is_some()),
+            ("store", self.store.is_some()),
+            ("metadata", self.metadata.is_some()),
+            ("service_tier", self.service_tier.is_some()),
+            ("web_search_options", self.web_search_options.is_some()),
+        ];
+        match set.into_iter().find(|(_, set)| *set) {
+            Some((key, _)) => Err(options::invalid(&options::path(key), "is not supported when streaming")),
+            None => Ok(()),
+        }
+    }
+
+    pub fn apply(&self, args: &mut CreateChatCompletionRequestArgs) {
+        if let Some(max_tokens) = self.max_tokens {
+            args.max_tokens(max_tokens);
+        }
+        if let Some(stop) = &self.stop {
+            args.stop(Stop::StringArray(stop.clone()));
+        }
+        if let Some(logit_bias) = &self.logit_bias {
+            args.logit_bias(logit_bias.clone());
+        }
+        if let Some(seed) = self.seed {
+            args.seed(seed);
+        }
+        if let Some(logprobs) = self.logprobs {
+            args.logprobs(logprobs);
+        }
+        if let Some(top_logprobs) = self.top_logprobs {
+            args.top_logprobs(top_logprobs);
+        }
+        if let Some(user) = &self.user {
+            args.user(user);
+        }
+        if let Some(format) = &self.response_format {
+            args.response_format(ChatCompletionResponseFormat { r#type: format.clone() });
+        }
+        if let Some(tools) = &self.tools {
+            args.tools(tools.clone());
+        }
+        if let Some(ToolChoice::Typed(choice)) = &self.tool_choice {
+            args.tool_choice(choice.clone());
+        }
+    }
+}
+
+// `{:json_schema, schema}` or `{:json_schema, schema, opts}`, where `schema` is a map
+// and `opts` may set the schema's `:name`, `:description` and `:strict` (default: true)
+fn decode_json_schema(term: Term) -> NifResult<Value> {
+    let path = options::path("response_format");
+    let invalid = || options::type_error(&path, "{:json_schema, schema} or {:json_schema, schema, opts}", term);
+    let elements = get_tuple(term).map_err(|_| invalid())?;
+    let (tag, schema, opts) = match elements.as_slice() {
+        [tag, schema] => (*tag, *schema, Vec::new()),
+        [tag, schema, opts] => (*tag, *schema, options::decode_at::<Vec<(Term, Term)>>(*opts, &format!("{}.opts", path), "keyword list")?),
+        _ => return Err(invalid()),
+    };
+    if tag.atom_to_string().ok().as_deref() != Some("json_schema") {
+        return Err(invalid());
+    }
+    if !schema.is_map() {
+        return Err(options::type_error(&format!("{}.schema", path), "map", schema));
+    }
+
+    let mut name = "response".to_string();
+    let mut description = None;
+    let mut strict = true;
+    for (key, value) in opts {
+        let key = key
+            .atom_to_string()
+            .map_err(|_| options::type_error(&format!("{}.opts", path), "keyword list", key))?;
+        let key_path = format!("{}.{}", path, key);
+        match key.as_str() {
+            "name" => name = options::decode_at(value, &key_path, "string")?,
+            "description" => description = Some(options::decode_at::<String>(value, &key_path, "string")?),
+            "strict" => strict = options::decode_at(value, &key_path, "boolean")?,
+            other => return Err(options::invalid(&path, format!("has unknown json_schema option :{}", other))),
+        }
+    }
+
+    let schema = json::from_term(schema).map_err(|e| options::invalid(&format!("{}.schema", path), format!("is not valid JSON: {}", e)))?;
+    let mut format = json!({"name": name, "schema": schema, "strict": strict});
+    if let Some(description) = description {
+        format["description"] = json!(description);
+    }
+    Ok(json!({"type": "json_schema", "json_schema": format}))
+}
+
+// String values by atom or string keys, within the API's limits
+fn decode_metadata(pairs: HashMap<Term, Term>) -> NifResult<serde_json::Map<String, Value>> {
+    let path = options::path("metadata");
+    if pairs.len() > MAX_METADATA_PAIRS {
+        return Err(options::invalid(&path, format!("can have at most {} pairs, got {}", MAX_METADATA_PAIRS, pairs.len())));
+    }
+    pairs
+        .into_iter()
+        .map(|(key, value)| {
+            let key = match key.atom_to_string() {
+                Ok(key) => key,
+                Err(_) => options::decode_at::<String>(key, &format!("{} key", path), "atom or string")?,
+            };
+            let key_path = format!("{}.{}", path, key);
+            if key.chars().count() > MAX_METADATA_KEY_LENGTH {
+                return Err(options::invalid(&key_path, format!("key must be at most {} characters", MAX_METADATA_KEY_LENGTH)));
+            }
+            let value: String = options::decode_at(value, &key_path, "string")?;
+            if value.chars().count() > MAX_METADATA_VALUE_LENGTH {
+                return Err(options::invalid(&key_path, format!("must be at most {} characters", MAX_METADATA_VALUE_LENGTH)));
+            }
+            Ok((key, Value::String(value)))
+        })
+        .collect()
+}
+
+// A map or keyword list with an optional `search_context_size` and `user_location`,
+// the latter with any of a `country` (a two-letter ISO code), `region`, `city` and
+// IANA `timezone`. An empty one searches with the API's defaults.
+fn decode_web_search_options(term: Term) -> NifResult<Value> {
+    let path = options::path("web_search_options");
+    let mut search = json!({});
+    for (key, value) in options::entries(term, &path)? {
+        let key_path = format!("{}.{}", path, key);
+        match key.as_str() {
+            "search_context_size" => {
+                let size = match value.atom_to_string() {
+                    Ok(size) => size,
+                    Err(_) => options::decode_at::<String>(value, &key_path, "atom or string")?,
+                };
+                if !SEARCH_CONTEXT_SIZES.contains(&size.as_str()) {
+                    return Err(options::invalid(&key_path, format!("must be :low, :medium or :high, got {}", size)));
+                }
+                search["search_context_size"] = json!(size);
+            },
+            "user_location" => {
+                let mut location = serde_json::Map::new();
+                for (field, value) in options::entries(value, &key_path)? {
+                    let field_path = format!("{}.{}", key_path, field);
+                    if !LOCATION_FIELDS.contains(&field.as_str()) {
+                        return Err(options::invalid(&key_path, format!("has unknown key :{}", field)));
+                    }
+                    location.insert(field, json!(options::decode_at::<String>(value, &field_path, "string")?));
+                }
+                search["user_location"] = json!({"type": "approximate", "approximate": location});
+            },
+            other => return Err(options::invalid(&path, format!("has unknown key :{}", other))),
+        }
+    }
+    Ok(search)
+}
+
+// A map with the function's `name`, and optionally a `description` and its
+// `parameters` as a JSON Schema map
+fn decode_tool(term: Term, path: &str) -> NifResult<ChatCompletionTool> {
+    if !term.is_map() {
+        return Err(options::type_error(path, "map", term));
+    }
+    let tool = json::from_term(term).map_err(|e| options::invalid(path, format!("is not valid JSON: {}", e)))?;
+    let name = tool
+        .get("name")
+        .and_then(Value::as_str)
+        .ok_or_else(|| options::invalid(path, "needs a string name"))?;
+
+    Ok(ChatCompletionTool {
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionObject {
+            name: name.to_string(),
+            description: tool.get("description").and_then(Value::as_str).map(str::to_string),
+            parameters: tool.get("parameters").cloned(),
+        },
+    })
+}
@@ -0,0 +1,119 @@
+use regex::Regex;
+use rustler::{Error, NifResult, ResourceArc, Term};
+
+use crate::locale::Locale;
+use crate::options;
+use crate::{atoms, OpenAIClientResource};
+
+// A single step of the completion post-processing pipeline
+pub enum PostProcessor {
+    Trim,
+    StripMarkdownFences,
+    ExtractFirstJson,
+    RegexReplace { pattern: Regex, replacement: String },
+    LocaleFormat(&'static Locale),
+}
+
+impl PostProcessor {
+    fn run(&self, content: String) -> String {
+        match self {
+            PostProcessor::Trim => content.trim().to_string(),
+            PostProcessor::StripMarkdownFences => strip_markdown_fences(&content),
+            PostProcessor::ExtractFirstJson => match extract_first_json(&content) {
+                Some(json) => json.to_string(),
+                None => content,
+            },
+            PostProcessor::RegexReplace { pattern, replacement } => {
+                pattern.replace_all(&content, replacement.as_str()).into_owned()
+            }
+            PostProcessor::LocaleFormat(locale) => locale.format(&content),
+        }
+    }
+}
+
+// Run every processor over the content, in registration order
+pub fn apply(processors: &[PostProcessor], content: &str) -> String {
+    processors
+        .iter()
+        .fold(content.to_string(), |acc, processor| processor.run(acc))
+}
+
+// Remove the ``` / ```lang marker lines while keeping the fenced contents
+pub fn strip_markdown_fences(content: &str) -> String {
+    content
+        .lines()
+        .filter(|line| !line.trim_start().starts_with("```"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Find the first substring that parses as a complete JSON object or array.
+// Code fences and surrounding prose are skipped over naturally since parsing
+// only starts at an opening brace or bracket.
+fn find_first_json(content: &str) -> Option<(&str, serde_json::Value)> {
+    for (start, c) in content.char_indices() {
+        if c != '{' && c != '[' {
+            continue;
+        }
+
+        let mut values = serde_json::Deserializer::from_str(&content[start..])
+            .into_iter::<serde_json::Value>();
+
+        if let Some(Ok(value)) = values.next() {
+            return Some((&content[start..start + values.byte_offset()], value));
+        }
+    }
+
+    None
+}
+
+pub fn extract_first_json(content: &str) -> Option<&str> {
+    find_first_json(content).map(|(raw, _)| raw)
+}
+
+pub fn parse_first_json(content: &str) -> Option<serde_json::Value> {
+    find_first_json(content).map(|(_, value)| value)
+}
+
+// Decode processors given as atoms (:trim, :strip_markdown_fences, :extract_json),
+// {:regex_replace, pattern, replacement} or {:locale_format, locale} tuples
+fn decode_post_processor(term: Term, path: &str) -> NifResult<PostProcessor> {
+    if term.is_atom() {
+        let name = term.atom_to_string()?;
+        return match name.as_str() {
+            "trim" => Ok(PostProcessor::Trim),
+            "strip_markdown_fences" => Ok(PostProcessor::StripMarkdownFences),
+            "extract_json" => Ok(PostProcessor::ExtractFirstJson),
+            other => Err(options::invalid(path, format!("must be :trim, :strip_markdown_fences, :extract_json or a tuple, got :{}", other))),
+        };
+    }
+
+    match term.decode::<(rustler::Atom, String, String)>() {
+        Ok((tag, pattern, replacement)) if tag == atoms::regex_replace() => {
+            let pattern = Regex::new(&pattern)
+                .map_err(|e| Error::Term(Box::new(format!("Invalid regex_replace pattern: {}", e))))?;
+            Ok(PostProcessor::RegexReplace { pattern, replacement })
+        },
+        _ => match term.decode::<(rustler::Atom, String)>() {
+            Ok((tag, locale)) if tag == atoms::locale_format() => {
+                Locale::find(&locale).map(PostProcessor::LocaleFormat).map_err(|e| options::invalid(path, e))
+            },
+            _ => Err(options::invalid(path, format!("must be an atom, {{:regex_replace, pattern, replacement}} or {{:locale_format, locale}}, got {:?}", term))),
+        },
+    }
+}
+
+#[rustler::nif]
+fn set_post_processors(client_resource: ResourceArc<OpenAIClientResource>, processors: Vec<Term>) -> NifResult<rustler::Atom> {
+    let decoded = processors
+        .into_iter()
+        .enumerate()
+        .map(|(index, term)| decode_post_processor(term, &format!("{}[{}]", options::path("post_processors"), index)))
+        .collect::<NifResult<Vec<_>>>()?;
+
+    let mut current = client_resource.post_processors.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock post-processors: {}", e))))?;
+    *current = decoded;
+
+    Ok(atoms::ok())
+}
@@ -0,0 +1,38 @@
+use std::collections::HashMap;
+
+use rustler::{Error, NifResult, ResourceArc};
+
+use crate::options::{self, Opts};
+use crate::{atoms, Message, OpenAIClientResource};
+
+// Long, static system prompts are registered with the client once and referenced
+// by id, so a multi-KB prompt isn't copied into and validated by every call
+pub type SystemPrompts = HashMap<String, String>;
+
+// Puts the prompt `system_prompt` names, an atom or string id, before `messages`
+pub fn splice(client_resource: &OpenAIClientResource, opts: &Opts, mut messages: Vec<Message>) -> NifResult<Vec<Message>> {
+    let id = match opts.get("system_prompt") {
+        Some(term) if term.is_atom() => options::get_atom(opts, "system_prompt")?,
+        _ => options::get_string(opts, "system_prompt")?,
+    };
+    let Some(id) = id else {
+        return Ok(messages);
+    };
+
+    let prompts = client_resource.system_prompts.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock system prompts: {}", e))))?;
+    let prompt = prompts
+        .get(&id)
+        .ok_or_else(|| options::invalid(&options::path("system_prompt"), format!("{:?} isn't registered with the client", id)))?;
+    messages.insert(0, Message::new("system", prompt.clone()));
+    Ok(messages)
+}
+
+#[rustler::nif]
+fn set_system_prompts(client_resource: ResourceArc<OpenAIClientResource>, prompts: Vec<(String, String)>) -> NifResult<rustler::Atom> {
+    let mut current = client_resource.system_prompts.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock system prompts: {}", e))))?;
+    *current = prompts.into_iter().collect();
+
+    Ok(atoms::ok())
+}
@@ -0,0 +1,157 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use rustler::env::{OwnedEnv, SavedTerm};
+use rustler::{Encoder, Error, LocalPid, NifResult, ResourceArc, Term};
+
+use crate::options::{self, Opts};
+use crate::{atoms, OpenAIClientResource};
+
+// Spaces a client's requests and streams out to a rate set below the API's limits,
+// with a token bucket: each attempt, retries included, takes a token, and attempts
+// that find none wait their turn. Clones share one bucket.
+#[derive(Clone)]
+pub struct RateLimit(Arc<Bucket>);
+
+struct Bucket {
+    // Tokens added per second, and most the bucket holds
+    rate: f64,
+    burst: f64,
+    state: Mutex<State>,
+    // Told when capacity is back after work was deferred
+    subscriber: Option<Subscriber>,
+}
+
+struct State {
+    // Negative while attempts wait for tokens they have already taken
+    tokens: f64,
+    refilled: Instant,
+    // When a token is next free for new work, if work was deferred and the
+    // subscriber is still to be told
+    available_at: Option<Instant>,
+    // Whether a thread is waiting to tell the subscriber
+    notifying: bool,
+}
+
+struct Subscriber {
+    pid: LocalPid,
+    // The ref sent with each notification, in an environment of its own
+    ref_term: Mutex<(OwnedEnv, SavedTerm)>,
+}
+
+impl RateLimit {
+    // None when no rate is given, which leaves the client unlimited
+    fn decode(opts: &Opts) -> NifResult<Option<Self>> {
+        let Some(rate) = options::get_f32(opts, "requests_per_second")? else {
+            return Ok(None);
+        };
+        if rate <= 0.0 {
+            return Err(options::invalid(&options::path("requests_per_second"), format!("must be positive, got {}", rate)));
+        }
+        let burst = match options::get_usize(opts, "burst")? {
+            Some(0) => return Err(options::invalid(&options::path("burst"), "must be positive")),
+            Some(burst) => burst as f64,
+            None => (rate as f64).ceil(),
+        };
+        let subscriber = match options::get_pid(opts, "notify")? {
+            Some(pid) => {
+                let term = options::get_in::<Term>(opts, "opts", "ref", "reference")?
+                    .ok_or_else(|| options::invalid(&options::path("notify"), "requires opts.ref"))?;
+                let owned = OwnedEnv::new();
+                let saved = owned.save(term);
+                Some(Subscriber { pid, ref_term: Mutex::new((owned, saved)) })
+            },
+            None => None,
+        };
+
+        Ok(Some(RateLimit(Arc::new(Bucket {
+            rate: rate as f64,
+            burst,
+            state: Mutex::new(State { tokens: burst, refilled: Instant::now(), available_at: None, notifying: false }),
+            subscriber,
+        }))))
+    }
+
+    // Takes a token, returning how long to wait for it, or None without taking it
+    // when `allows` rejects the wait, e.g. because it would pass a deadline
+    pub fn reserve(&self, allows: impl Fn(Duration) -> bool) -> Option<Duration> {
+        let bucket = &self.0;
+        let mut state = bucket.state.lock().unwrap_or_else(|e| e.into_inner());
+        let now = Instant::now();
+        let elapsed = now.duration_since(state.refilled).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * bucket.rate).min(bucket.burst);
+        state.refilled = now;
+
+        let wait = Duration::from_secs_f64((1.0 - state.tokens).max(0.0) / bucket.rate);
+        if !allows(wait) {
+            return None;
+        }
+        state.tokens -= 1.0;
+        if !wait.is_zero() && bucket.subscriber.is_some() {
+            // New work can start once every deferred attempt has had its token
+            state.available_at = Some(now + Duration::from_secs_f64((1.0 - state.tokens) / bucket.rate));
+            if !state.notifying {
+                state.notifying = true;
+                let limit = self.clone();
+                std::thread::spawn(move || limit.notify());
+            }
+        }
+        Some(wait)
+    }
+
+    // Waits for a token, however long it takes
+    pub async fn acquire(&self) {
+        if let Some(wait) = self.reserve(|_| true).filter(|wait| !wait.is_zero()) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // Sends `{:rate_capacity_available, ref}` to the subscriber once capacity is back,
+    // waiting longer whenever more work is deferred meanwhile
+    fn notify(&self) {
+        let bucket = &self.0;
+        loop {
+            let at = {
+                let mut state = bucket.state.lock().unwrap_or_else(|e| e.into_inner());
+                match state.available_at {
+                    Some(at) if at > Instant::now() => at,
+                    _ => {
+                        state.available_at = None;
+                        state.notifying = false;
+                        break;
+                    },
+                }
+            };
+            std::thread::sleep(at.saturating_duration_since(Instant::now()));
+        }
+
+        if let Some(subscriber) = &bucket.subscriber {
+            let ref_term = subscriber.ref_term.lock().unwrap_or_else(|e| e.into_inner());
+            let (ref_env, saved) = &*ref_term;
+            let _ = OwnedEnv::new().send_and_clear(&subscriber.pid, |env| {
+                let client_ref = ref_env.run(|owned| saved.load(owned).in_env(env));
+                (atoms::rate_capacity_available(), client_ref).encode(env)
+            });
+        }
+    }
+}
+
+impl OpenAIClientResource {
+    pub fn rate_limit(&self) -> NifResult<Option<RateLimit>> {
+        match self.rate_limit.lock() {
+            Ok(rate_limit) => Ok(rate_limit.clone()),
+            Err(e) => Err(Error::Term(Box::new(format!("Failed to lock rate limit: {}", e)))),
+        }
+    }
+}
+
+// Sets the rate the client's requests and streams are held to, or removes the limit
+// when no rate is given
+#[rustler::nif]
+fn set_rate_limit(client_resource: ResourceArc<OpenAIClientResource>, opts: Opts) -> NifResult<rustler::Atom> {
+    let decoded = RateLimit::decode(&opts)?;
+    let mut current = client_resource.rate_limit.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock rate limit: {}", e))))?;
+    *current = decoded;
+    Ok(atoms::ok())
+}
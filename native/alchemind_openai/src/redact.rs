@@ -0,0 +1,48 @@
+use regex::{Regex, RegexBuilder};
+use rustler::NifResult;
+
+use crate::options::{self, Opts};
+
+const DEFAULT_REPLACEMENT: &str = "[REDACTED]";
+
+// Replaces listed words and pattern matches in transcripts before they are returned
+pub struct Redactor {
+    patterns: Vec<Regex>,
+    replacement: String,
+}
+
+impl Redactor {
+    // `redact` is a list of words matched whole and case-insensitively, `redact_patterns`
+    // a list of regexes; matches of either are replaced with `redact_with`
+    pub fn decode(opts: &Opts) -> NifResult<Self> {
+        let mut patterns = Vec::new();
+
+        let words = options::get_strings(opts, "redact")?.unwrap_or_default();
+        let words: Vec<String> = words.iter().filter(|w| !w.is_empty()).map(|w| regex::escape(w)).collect();
+        if !words.is_empty() {
+            let pattern = RegexBuilder::new(&format!(r"\b(?:{})\b", words.join("|")))
+                .case_insensitive(true)
+                .build()
+                .map_err(|e| options::invalid(&options::path("redact"), format!("is not a valid word list: {}", e)))?;
+            patterns.push(pattern);
+        }
+
+        for (index, pattern) in options::get_strings(opts, "redact_patterns")?.unwrap_or_default().iter().enumerate() {
+            let regex = Regex::new(pattern)
+                .map_err(|e| options::invalid(&format!("opts.redact_patterns[{}]", index), format!("is not a valid regex: {}", e)))?;
+            patterns.push(regex);
+        }
+
+        Ok(Redactor {
+            patterns,
+            replacement: options::get_string(opts, "redact_with")?.unwrap_or_else(|| DEFAULT_REPLACEMENT.to_string()),
+        })
+    }
+
+    pub fn apply(&self, text: &str) -> String {
+        self.patterns.iter().fold(text.to_string(), |acc, pattern| {
+            // NoExpand keeps `$` in the replacement literal
+            pattern.replace_all(&acc, regex::NoExpand(&self.replacement)).into_owned()
+        })
+    }
+}
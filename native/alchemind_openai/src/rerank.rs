@@ -0,0 +1,109 @@
+use async_openai::types::CreateChatCompletionRequestArgs;
+use futures_util::StreamExt;
+use rustler::{Error, NifMap, NifResult, ResourceArc};
+
+use crate::deadline::Deadline;
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::{postprocess, request_content, to_request_messages, Message, OpenAIClientResource};
+
+const RERANK_PROMPT: &str = "You are a search relevance judge. Score how well each document answers \
+the query on a scale from 0 (irrelevant) to 10 (perfectly relevant). Judge every document independently. \
+Reply only with a JSON object of the form {\"scores\": [{\"id\": <document id>, \"score\": <number>}]} \
+containing one entry per document.";
+
+#[derive(NifMap)]
+struct RankedDocument {
+    index: usize,
+    // Relevance normalized to 0.0..=1.0
+    score: f64,
+}
+
+fn build_prompt(query: &str, batch: &[(usize, String)], max_chars: usize) -> String {
+    let mut prompt = format!("Query: {}\n\nDocuments:\n", query);
+    for (id, (_, document)) in batch.iter().enumerate() {
+        let document: String = document.chars().take(max_chars).collect();
+        prompt.push_str(&format!("\n[{}]\n{}\n", id, document));
+    }
+    prompt
+}
+
+// Map the scores in the model's JSON reply back to document indices. Documents the
+// model skipped score 0.
+fn parse_scores(content: &str, batch: &[(usize, String)]) -> Result<Vec<(usize, f64)>, String> {
+    let value = postprocess::parse_first_json(content)
+        .ok_or_else(|| format!("Rerank response did not contain JSON: {}", content))?;
+    let entries = value
+        .get("scores")
+        .and_then(|s| s.as_array())
+        .ok_or_else(|| "Rerank response is missing the scores array".to_string())?;
+
+    let mut scores = vec![0.0; batch.len()];
+    for entry in entries {
+        let id = entry.get("id").and_then(|id| id.as_u64()).map(|id| id as usize);
+        let score = entry.get("score").and_then(|s| s.as_f64());
+        if let (Some(id), Some(score)) = (id, score) {
+            if id < scores.len() {
+                scores[id] = (score / 10.0).clamp(0.0, 1.0);
+            }
+        }
+    }
+
+    Ok(batch.iter().map(|(index, _)| *index).zip(scores).collect())
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn rerank(client_resource: ResourceArc<OpenAIClientResource>, query: String, documents: Vec<String>, opts: Opts) -> NifResult<Vec<RankedDocument>> {
+    let model = options::get_string(&opts, "model")?.unwrap_or_else(|| "gpt-4o-mini".to_string());
+    let batch_size = options::get_usize(&opts, "batch_size")?.unwrap_or(10).max(1);
+    let concurrency = options::get_usize(&opts, "concurrency")?.unwrap_or(4).max(1);
+    let max_chars = options::get_usize(&opts, "max_document_chars")?.unwrap_or(4000);
+    let top_n = options::get_usize(&opts, "top_n")?;
+
+    let transport = client_resource.transport_for(Endpoint::Chat, &opts)?.with_deadline(Deadline::decode(&opts)?);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let indexed: Vec<(usize, String)> = documents.into_iter().enumerate().collect();
+    let batches: Vec<Vec<(usize, String)>> = indexed.chunks(batch_size).map(|b| b.to_vec()).collect();
+
+    let results = runtime.block_on(async {
+        futures_util::stream::iter(batches)
+            .map(|batch| {
+                let transport = transport.clone();
+                let model = model.clone();
+                let messages = vec![
+                    Message::new("system", RERANK_PROMPT.to_string()),
+                    Message::new("user", build_prompt(&query, &batch, max_chars)),
+                ];
+                async move {
+                    let request = CreateChatCompletionRequestArgs::default()
+                        .model(model)
+                        .messages(to_request_messages(messages)?)
+                        .temperature(0.0)
+                        .build()
+                        .map_err(|e| format!("Failed to build request: {}", e))?;
+                    let content = request_content(&transport, request).await?;
+                    parse_scores(&content, &batch)
+                }
+            })
+            .buffer_unordered(concurrency)
+            .collect::<Vec<_>>()
+            .await
+    });
+
+    let mut ranked = Vec::with_capacity(indexed.len());
+    for result in results {
+        let scores = result.map_err(|e| Error::Term(Box::new(e)))?;
+        ranked.extend(scores.into_iter().map(|(index, score)| RankedDocument { index, score }));
+    }
+
+    // Highest score first; ties keep the original document order
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then(a.index.cmp(&b.index)));
+    if let Some(n) = top_n {
+        ranked.truncate(n);
+    }
+
+    Ok(ranked)
+}
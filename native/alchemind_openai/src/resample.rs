@@ -0,0 +1,45 @@
+use async_openai::types::CreateChatCompletionRequestArgs;
+use rustler::NifResult;
+
+use crate::options::{self, Opts};
+
+// Sampling parameters used for one attempt of a completion
+#[derive(Clone, Copy)]
+pub struct Sampling {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+}
+
+impl Sampling {
+    pub fn apply(&self, args: &mut CreateChatCompletionRequestArgs) {
+        if let Some(temperature) = self.temperature {
+            args.temperature(temperature);
+        }
+        if let Some(top_p) = self.top_p {
+            args.top_p(top_p);
+        }
+    }
+}
+
+// The first attempt uses the top-level temperature/top_p. Each `retry_schedule` step
+// adds one retry, taken when the previous response was empty, refused or not valid
+// JSON; parameters a step leaves out are inherited from the first attempt.
+pub fn decode_schedule(opts: &Opts) -> NifResult<Vec<Sampling>> {
+    let base = Sampling {
+        temperature: options::get_f32(opts, "temperature")?,
+        top_p: options::get_f32(opts, "top_p")?,
+    };
+
+    let steps: Vec<Opts> = options::get_in(opts, "opts", "retry_schedule", "list of maps")?.unwrap_or_default();
+
+    let mut schedule = vec![base];
+    for (index, step) in steps.iter().enumerate() {
+        let path = format!("opts.retry_schedule[{}]", index);
+        schedule.push(Sampling {
+            temperature: options::get_f32_in(step, &path, "temperature")?.or(base.temperature),
+            top_p: options::get_f32_in(step, &path, "top_p")?.or(base.top_p),
+        });
+    }
+
+    Ok(schedule)
+}
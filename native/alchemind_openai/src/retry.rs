@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+use async_openai::error::OpenAIError;
+use async_openai::types::CreateChatCompletionRequest;
+use backoff::backoff::Backoff as _;
+use backoff::ExponentialBackoff;
+use futures_util::StreamExt;
+use rustler::{Error, NifResult, ResourceArc};
+
+use crate::chunks::{ChunkStream, Opener};
+use crate::deadline::Deadline;
+use crate::faults::Faults;
+use crate::keepwarm::KeepWarm;
+use crate::options::{self, Opts};
+use crate::{atoms, OpenAIClientResource};
+
+// Exponential backoff for one kind of request. Requests and streams are configured
+// separately: retrying a request is always safe, but a stream is only ever retried
+// before its first chunk, since reopening it later would repeat delivered tokens.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    initial_interval: Duration,
+    max_interval: Duration,
+    multiplier: f64,
+    // Retrying stops once this much time has passed since the first attempt
+    max_elapsed: Duration,
+}
+
+impl RetryPolicy {
+    // async-openai's default: rate limited requests are retried for up to 15 minutes
+    pub fn requests() -> Self {
+        let defaults = ExponentialBackoff::default();
+        RetryPolicy {
+            initial_interval: defaults.initial_interval,
+            max_interval: defaults.max_interval,
+            multiplier: defaults.multiplier,
+            max_elapsed: defaults.max_elapsed_time.unwrap_or(Duration::MAX),
+        }
+    }
+
+    // Streams fail straight away unless retries are configured
+    pub fn streams() -> Self {
+        RetryPolicy { max_elapsed: Duration::ZERO, ..Self::requests() }
+    }
+
+    // Options not given keep `default`'s values; `max_elapsed_ms: 0` disables retries
+    pub fn decode(opts: &Opts, default: Self) -> NifResult<Self> {
+        let millis = |key: &str| options::get_usize(opts, key).map(|ms| ms.map(|ms| Duration::from_millis(ms as u64)));
+        Ok(RetryPolicy {
+            initial_interval: millis("initial_interval_ms")?.unwrap_or(default.initial_interval),
+            max_interval: millis("max_interval_ms")?.unwrap_or(default.max_interval),
+            multiplier: options::get_f32(opts, "multiplier")?.map_or(default.multiplier, |m| m.max(1.0) as f64),
+            max_elapsed: millis("max_elapsed_ms")?.unwrap_or(default.max_elapsed),
+        })
+    }
+
+    // A fresh backoff, started now
+    pub fn backoff(&self) -> ExponentialBackoff {
+        ExponentialBackoff {
+            initial_interval: self.initial_interval,
+            current_interval: self.initial_interval,
+            max_interval: self.max_interval,
+            multiplier: self.multiplier,
+            max_elapsed_time: Some(self.max_elapsed),
+            ..ExponentialBackoff::default()
+        }
+    }
+}
+
+// Whether a stream error could clear up: transport failures and the
+// statuses `ApiError` treats as retryable. The stream only reports the status in
+// its error message.
+pub fn retryable(error: &OpenAIError) -> bool {
+    let message = match error {
+        OpenAIError::Reqwest(_) => return true,
+        OpenAIError::StreamError(message) => message,
+        _ => return false,
+    };
+
+    match message.strip_prefix("Invalid status code: ") {
+        Some(status) => matches!(status.get(..3).and_then(|code| code.parse::<u16>().ok()), Some(408 | 409 | 429 | 500..=599)),
+        None => !message.starts_with("Invalid header value"),
+    }
+}
+
+// Opens a chat completion stream, reopening it under `policy` while its first item
+// is a retryable error. The returned stream still yields that first item. With
+// `keep_warm`, the stream is watched from the start, so a proxy closing it during a
+// long pause before the first token is caught too. `faults` can fail an attempt in
+// place of opening it, or slow the stream that is opened.
+pub async fn open_chat_stream(
+    opener: &Opener,
+    request: CreateChatCompletionRequest,
+    policy: RetryPolicy,
+    deadline: Deadline,
+    keep_warm: Option<KeepWarm>,
+    faults: Option<&Faults>,
+) -> Result<ChunkStream, String> {
+    let mut backoff = policy.backoff();
+
+    loop {
+        if let Some(fault) = faults.and_then(Faults::draw) {
+            let error = fault.stream_error();
+            match backoff.next_backoff() {
+                Some(delay) if retryable(&error) && deadline.allows(delay) => {
+                    tokio::time::sleep(delay).await;
+                    continue;
+                },
+                _ => return Ok(futures_util::stream::iter([Err(error)]).boxed()),
+            }
+        }
+
+        let mut stream = match deadline.run(opener.open(&request)).await {
+            Some(Ok(stream)) => {
+                let stream = match faults {
+                    Some(faults) => faults.slow(stream),
+                    None => stream,
+                };
+                match keep_warm {
+                    Some(keep_warm) => keep_warm.watch(stream),
+                    None => stream,
+                }
+            },
+            Some(Err(e)) => return Err(format!("Failed to create stream: {}", e)),
+            None => return Err("Deadline exceeded".to_string()),
+        };
+
+        let first = match deadline.run(stream.next()).await {
+            Some(first) => first,
+            None => return Err("Deadline exceeded".to_string()),
+        };
+
+        if let Some(Err(error)) = &first {
+            match backoff.next_backoff() {
+                Some(delay) if retryable(error) && deadline.allows(delay) => {
+                    tokio::time::sleep(delay).await;
+                    continue;
+                },
+                _ => {},
+            }
+        }
+
+        return Ok(futures_util::stream::iter(first).chain(stream).boxed());
+    }
+}
+
+// Replaces the client's retry policies. Options left out of either map keep the
+// defaults, not the previously set values.
+#[rustler::nif]
+fn set_retry_policies(client_resource: ResourceArc<OpenAIClientResource>, request_opts: Opts, stream_opts: Opts) -> NifResult<rustler::Atom> {
+    let requests = RetryPolicy::decode(&request_opts, RetryPolicy::requests())?;
+    let streams = RetryPolicy::decode(&stream_opts, RetryPolicy::streams())?;
+
+    let mut client = client_resource.client.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock client: {}", e))))?;
+    *client = client.clone().with_backoff(requests.backoff());
+    drop(client);
+
+    let mut transport = client_resource.transport.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock transport: {}", e))))?;
+    *transport = transport.clone().with_retry(requests);
+    drop(transport);
+
+    let mut stream_retry = client_resource.stream_retry.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock stream retry policy: {}", e))))?;
+    *stream_retry = streams;
+
+    Ok(atoms::ok())
+}
@@ -0,0 +1,296 @@
+use regex::Regex;
+use rustler::{Atom, NifMap, NifResult};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::atoms;
+use crate::options::{self, Opts};
+
+#[derive(NifMap)]
+struct SimilarityScores {
+    exact_match: bool,
+    // Character edit distance and 1 - distance / longer length
+    levenshtein: usize,
+    char_similarity: f64,
+    // Smoothed sentence BLEU up to 4-grams
+    bleu: f64,
+    // ROUGE F1 scores over lowercased words
+    rouge_1: f64,
+    rouge_2: f64,
+    rouge_l: f64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Edit {
+    Equal,
+    Insert,
+    Delete,
+}
+
+fn word_pattern() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\w+").unwrap())
+}
+
+// Words, whitespace runs and punctuation, so the pieces rejoin into the original text
+fn diff_token_pattern() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"\s+|\w+|[^\w\s]").unwrap())
+}
+
+fn words(text: &str) -> Vec<String> {
+    word_pattern()
+        .find_iter(text)
+        .map(|m| m.as_str().to_lowercase())
+        .collect()
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    let mut current = vec![0; b.len() + 1];
+
+    for (i, ca) in a.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[b.len()]
+}
+
+// Shortest edit script between two sequences (Myers' O(ND) algorithm in linear space)
+fn diff<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+    let mut edits = Vec::with_capacity(a.len().max(b.len()));
+    diff_into(a, b, &mut edits);
+    edits
+}
+
+// Splits at the middle snake and recurses on either side, so memory stays O(N + M)
+fn diff_into<T: PartialEq>(a: &[T], b: &[T], edits: &mut Vec<Edit>) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = a.iter().rev().zip(b.iter().rev()).take_while(|(x, y)| x == y).count();
+    let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+
+    edits.extend(std::iter::repeat_n(Edit::Equal, prefix));
+    if a.is_empty() {
+        edits.extend(std::iter::repeat_n(Edit::Insert, b.len()));
+    } else if b.is_empty() {
+        edits.extend(std::iter::repeat_n(Edit::Delete, a.len()));
+    } else {
+        // Both ends differ, so there are edits on either side of the snake
+        let (x, y, u, v) = middle_snake(a, b);
+        diff_into(&a[..x], &b[..y], edits);
+        edits.extend(std::iter::repeat_n(Edit::Equal, u - x));
+        diff_into(&a[u..], &b[v..], edits);
+    }
+    edits.extend(std::iter::repeat_n(Edit::Equal, suffix));
+}
+
+// Start and end of a snake on some shortest path, found by searching from both corners
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T]) -> (usize, usize, usize, usize) {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let delta = n - m;
+    let max = (n + m + 1) / 2;
+    let offset = max + 1;
+    // Furthest x reached on each diagonal, forwards and over the reversed sequences
+    let mut forward = vec![0isize; (2 * offset + 1) as usize];
+    let mut backward = vec![0isize; (2 * offset + 1) as usize];
+
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let index = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && forward[index - 1] < forward[index + 1]) {
+                forward[index + 1]
+            } else {
+                forward[index - 1] + 1
+            };
+            let (start_x, start_y) = (x, x - k);
+            let mut y = start_y;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[index] = x;
+            let reversed = delta - k;
+            if delta % 2 != 0 && reversed.abs() < d && x + backward[(offset + reversed) as usize] >= n {
+                return (start_x as usize, start_y as usize, x as usize, y as usize);
+            }
+        }
+
+        for k in (-d..=d).step_by(2) {
+            let index = (offset + k) as usize;
+            let mut x = if k == -d || (k != d && backward[index - 1] < backward[index + 1]) {
+                backward[index + 1]
+            } else {
+                backward[index - 1] + 1
+            };
+            let (end_x, end_y) = (x, x - k);
+            let mut y = end_y;
+            while x < n && y < m && a[(n - 1 - x) as usize] == b[(m - 1 - y) as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[index] = x;
+            let forward_k = delta - k;
+            if delta % 2 == 0 && forward_k.abs() <= d && forward[(offset + forward_k) as usize] + x >= n {
+                return ((n - x) as usize, (m - y) as usize, (n - end_x) as usize, (m - end_y) as usize);
+            }
+        }
+    }
+
+    unreachable!("the searches meet within (n + m + 1) / 2 rounds")
+}
+
+fn lcs_length<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    diff(a, b).into_iter().filter(|edit| *edit == Edit::Equal).count()
+}
+
+fn ngrams(tokens: &[String], n: usize) -> HashMap<&[String], usize> {
+    let mut counts = HashMap::new();
+    for gram in tokens.windows(n) {
+        *counts.entry(gram).or_insert(0) += 1;
+    }
+    counts
+}
+
+// Matches between candidate and reference n-grams, with counts clipped to the reference
+fn overlap(candidate: &HashMap<&[String], usize>, reference: &HashMap<&[String], usize>) -> usize {
+    candidate
+        .iter()
+        .map(|(gram, count)| (*count).min(reference.get(gram).copied().unwrap_or(0)))
+        .sum()
+}
+
+fn f1(matches: usize, candidate_total: usize, reference_total: usize) -> f64 {
+    if matches == 0 || candidate_total == 0 || reference_total == 0 {
+        return 0.0;
+    }
+    let precision = matches as f64 / candidate_total as f64;
+    let recall = matches as f64 / reference_total as f64;
+    2.0 * precision * recall / (precision + recall)
+}
+
+fn rouge_n(candidate: &[String], reference: &[String], n: usize) -> f64 {
+    let matches = overlap(&ngrams(candidate, n), &ngrams(reference, n));
+    f1(
+        matches,
+        candidate.len().saturating_sub(n - 1),
+        reference.len().saturating_sub(n - 1),
+    )
+}
+
+// Add-one smoothing on higher-order precisions keeps short outputs from scoring zero
+fn bleu(candidate: &[String], reference: &[String]) -> f64 {
+    if candidate.is_empty() || reference.is_empty() {
+        return 0.0;
+    }
+
+    let max_n = 4.min(candidate.len());
+    let mut log_precision = 0.0;
+    for n in 1..=max_n {
+        let matches = overlap(&ngrams(candidate, n), &ngrams(reference, n)) as f64;
+        let total = (candidate.len() - n + 1) as f64;
+        let precision = if n == 1 { matches / total } else { (matches + 1.0) / (total + 1.0) };
+        if precision == 0.0 {
+            return 0.0;
+        }
+        log_precision += precision.ln() / max_n as f64;
+    }
+
+    let brevity_penalty = if candidate.len() >= reference.len() {
+        1.0
+    } else {
+        (1.0 - reference.len() as f64 / candidate.len() as f64).exp()
+    };
+
+    brevity_penalty * log_precision.exp()
+}
+
+fn score(candidate: &str, reference: &str) -> SimilarityScores {
+    let distance = levenshtein(candidate, reference);
+    let longest = candidate.chars().count().max(reference.chars().count());
+    let candidate_words = words(candidate);
+    let reference_words = words(reference);
+
+    SimilarityScores {
+        exact_match: candidate == reference,
+        levenshtein: distance,
+        char_similarity: if longest == 0 { 1.0 } else { 1.0 - distance as f64 / longest as f64 },
+        bleu: bleu(&candidate_words, &reference_words),
+        rouge_1: rouge_n(&candidate_words, &reference_words, 1),
+        rouge_2: rouge_n(&candidate_words, &reference_words, 2),
+        rouge_l: f1(
+            lcs_length(&candidate_words, &reference_words),
+            candidate_words.len(),
+            reference_words.len(),
+        ),
+    }
+}
+
+#[rustler::nif(schedule = "DirtyCpu")]
+fn similarity_scores(pairs: Vec<(String, String)>) -> Vec<SimilarityScores> {
+    pairs
+        .iter()
+        .map(|(candidate, reference)| score(candidate, reference))
+        .collect()
+}
+
+// Diff `old` against `new`, returning {:equal | :insert | :delete, text} runs
+#[rustler::nif(schedule = "DirtyCpu")]
+fn diff_text(old: String, new: String, opts: Opts) -> NifResult<Vec<(Atom, String)>> {
+    let tokenize = |text: &str| -> NifResult<Vec<String>> {
+        Ok(match options::get_atom(&opts, "granularity")?.as_deref() {
+            None | Some("word") => diff_token_pattern()
+                .find_iter(text)
+                .map(|m| m.as_str().to_string())
+                .collect(),
+            Some("char") => text.chars().map(String::from).collect(),
+            Some("line") => text.split_inclusive('\n').map(str::to_string).collect(),
+            Some(other) => return Err(options::invalid(&options::path("granularity"), format!("must be :word, :char or :line, got :{}", other))),
+        })
+    };
+    let old_tokens = tokenize(&old)?;
+    let new_tokens = tokenize(&new)?;
+
+    let mut runs: Vec<(Edit, String)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    for edit in diff(&old_tokens, &new_tokens) {
+        let token = match edit {
+            Edit::Equal => {
+                i += 1;
+                j += 1;
+                &old_tokens[i - 1]
+            },
+            Edit::Delete => {
+                i += 1;
+                &old_tokens[i - 1]
+            },
+            Edit::Insert => {
+                j += 1;
+                &new_tokens[j - 1]
+            },
+        };
+        match runs.last_mut() {
+            Some((last, text)) if *last == edit => text.push_str(token),
+            _ => runs.push((edit, token.clone())),
+        }
+    }
+
+    Ok(runs
+        .into_iter()
+        .map(|(edit, text)| {
+            let op = match edit {
+                Edit::Equal => atoms::equal(),
+                Edit::Insert => atoms::insert(),
+                Edit::Delete => atoms::delete(),
+            };
+            (op, text)
+        })
+        .collect())
+}
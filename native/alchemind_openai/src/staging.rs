@@ -0,0 +1,189 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use bytes::Bytes;
+use ring::rand::SecureRandom;
+use rustler::{Binary, Error, NifMap, NifResult, ResourceArc, Term};
+
+use crate::options::{self, Opts};
+use crate::transcription;
+
+// Temp files for large audio, staged once and passed to the NIFs by reference
+// instead of copying the binary into every call. Files are named by the SHA-256 of
+// their contents, so staging the same audio twice shares one file. A file is
+// removed once no resource refers to it any more. Each OS process stages into a
+// directory of its own, so nodes sharing a temp dir never see each other's files.
+pub struct StagedFile {
+    file: Arc<StagedData>,
+    // Name sent with uploads; the API detects the audio format from its extension
+    file_name: String,
+}
+
+impl rustler::Resource for StagedFile {}
+
+struct StagedData {
+    id: String,
+    path: PathBuf,
+    size: usize,
+}
+
+// Live files by id. Staging and removal both happen under this lock, so a file
+// being dropped is never deleted after the same contents were staged again.
+static STAGED: Mutex<Option<HashMap<String, Weak<StagedData>>>> = Mutex::new(None);
+
+impl Drop for StagedData {
+    fn drop(&mut self) {
+        let mut staged = STAGED.lock().unwrap_or_else(|e| e.into_inner());
+        let files = staged.get_or_insert_with(HashMap::new);
+        if files.get(&self.id).is_some_and(|file| file.upgrade().is_none()) {
+            files.remove(&self.id);
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[derive(NifMap)]
+struct StagedInfo {
+    id: String,
+    path: String,
+    size: usize,
+    file_name: String,
+}
+
+// This process's temp directory, created on first use. The pid and a random
+// suffix keep it apart from other nodes, and only the owner can open it.
+static PRIVATE_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub fn private_dir() -> Result<PathBuf, String> {
+    let mut dir = PRIVATE_DIR.lock().map_err(|e| format!("Failed to lock temp dir: {}", e))?;
+    if let Some(dir) = dir.as_ref() {
+        return Ok(dir.clone());
+    }
+
+    let path = std::env::temp_dir().join(format!("alchemind_openai-{}-{}", std::process::id(), random_hex(8)?));
+    create_private_dir(&path)?;
+    Ok(dir.insert(path).clone())
+}
+
+// Creates a directory only the owner can list or enter
+pub fn create_private_dir(path: &Path) -> Result<(), String> {
+    let mut builder = std::fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    match builder.create(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(format!("Failed to create {}: {}", path.display(), e)),
+        _ => Ok(()),
+    }
+}
+
+// Writes `data` to a new file only the owner can read, failing if `path` exists
+pub fn write_private(path: &Path, data: &[u8]) -> Result<(), String> {
+    let mut options = File::options();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(data))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+pub fn random_hex(len: usize) -> Result<String, String> {
+    let mut bytes = vec![0u8; len];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| "Failed to generate random bytes".to_string())?;
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+// Writes `data` to its content-addressed file unless it is already staged
+pub fn stage(data: &[u8], file_name: String) -> Result<ResourceArc<StagedFile>, String> {
+    let digest = ring::digest::digest(&ring::digest::SHA256, data);
+    let id: String = digest.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+
+    let mut staged = STAGED.lock().map_err(|e| format!("Failed to lock staged files: {}", e))?;
+    let files = staged.get_or_insert_with(HashMap::new);
+
+    let file = match files.get(&id).and_then(Weak::upgrade) {
+        Some(file) => file,
+        None => {
+            let path = private_dir()?.join(&id);
+            // Left behind if removing a dropped file failed; nothing else writes here
+            let _ = std::fs::remove_file(&path);
+            write_private(&path, data)?;
+
+            let file = Arc::new(StagedData { id: id.clone(), path, size: data.len() });
+            files.insert(id, Arc::downgrade(&file));
+            file
+        },
+    };
+
+    Ok(ResourceArc::new(StagedFile { file, file_name }))
+}
+
+impl StagedFile {
+    // Read once per call; uploads share the buffer across retries
+    pub async fn read(&self) -> Result<Bytes, String> {
+        tokio::fs::read(&self.file.path)
+            .await
+            .map(Bytes::from)
+            .map_err(|e| format!("Failed to read staged file {}: {}", self.file.id, e))
+    }
+}
+
+// Audio passed to a NIF as either a binary or a staged file, with the name to upload it under
+pub fn decode_audio(term: Term, default_name: impl FnOnce(&[u8]) -> String) -> NifResult<(AudioInput, String)> {
+    if let Ok(file) = term.decode::<ResourceArc<StagedFile>>() {
+        let file_name = file.file_name.clone();
+        return Ok((AudioInput::Staged(file), file_name));
+    }
+    let binary: Binary = term
+        .decode()
+        .map_err(|_| Error::Term(Box::new("Audio must be a binary or a staged file")))?;
+    Ok((AudioInput::Data(Bytes::copy_from_slice(binary.as_slice())), default_name(binary.as_slice())))
+}
+
+pub enum AudioInput {
+    Data(Bytes),
+    Staged(ResourceArc<StagedFile>),
+}
+
+impl AudioInput {
+    pub fn len(&self) -> usize {
+        match self {
+            AudioInput::Data(data) => data.len(),
+            AudioInput::Staged(file) => file.file.size,
+        }
+    }
+
+    pub async fn load(&self) -> Result<Bytes, String> {
+        match self {
+            AudioInput::Data(data) => Ok(data.clone()),
+            AudioInput::Staged(file) => file.read().await,
+        }
+    }
+}
+
+// Stages audio in a temp file. `file_name` sets the name it is uploaded under,
+// otherwise it is named for the format its bytes are sniffed as.
+#[rustler::nif(schedule = "DirtyIo")]
+fn stage_file(data: Binary, opts: Opts) -> NifResult<ResourceArc<StagedFile>> {
+    let file_name = match options::get_string(&opts, "file_name")? {
+        Some(file_name) => file_name,
+        None => transcription::upload_name("audio", data.as_slice(), transcription::decode_audio_format(&opts)?.as_deref()),
+    };
+    stage(data.as_slice(), file_name).map_err(|e| Error::Term(Box::new(e)))
+}
+
+#[rustler::nif]
+fn staged_file_info(file: ResourceArc<StagedFile>) -> StagedInfo {
+    StagedInfo {
+        id: file.file.id.clone(),
+        path: file.file.path.display().to_string(),
+        size: file.file.size,
+        file_name: file.file_name.clone(),
+    }
+}
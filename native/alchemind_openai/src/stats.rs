@@ -0,0 +1,219 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rustler::{Error, NifMap, NifResult, ResourceArc};
+
+use crate::options;
+use crate::{atoms, OpenAIClientResource};
+
+// Live request counts for a client, for saturation dashboards and autoscaling.
+// Shared by every clone of the client's transport and by its streams.
+pub struct ClientStats {
+    // Where the client sends its requests, e.g. `api.openai.com`
+    host: String,
+    in_flight: AtomicUsize,
+    // Requests waiting out a backoff, e.g. after a rate limit, before retrying
+    queued: AtomicUsize,
+    streams: AtomicUsize,
+    requests: AtomicU64,
+    usage: Mutex<UsageHistory>,
+}
+
+// Chat completion usage over the last hour by minute, and the last day by hour
+struct UsageHistory {
+    minutes: UsageRing,
+    hours: UsageRing,
+}
+
+impl Default for UsageHistory {
+    fn default() -> Self {
+        UsageHistory { minutes: UsageRing::new(60, 60), hours: UsageRing::new(3600, 24) }
+    }
+}
+
+// Chat completions finished in one period, and the tokens they used
+#[derive(Clone, Copy, Default, NifMap)]
+struct UsageBucket {
+    // Unix time in seconds the period starts at
+    start: u64,
+    completions: u64,
+    prompt_tokens: u64,
+    completion_tokens: u64,
+}
+
+// The most recent buckets of one width, oldest first. Periods nothing finished in
+// have no bucket until they are read.
+struct UsageRing {
+    width: u64,
+    capacity: usize,
+    buckets: VecDeque<UsageBucket>,
+}
+
+impl UsageRing {
+    fn new(width: u64, capacity: usize) -> Self {
+        UsageRing { width, capacity, buckets: VecDeque::with_capacity(capacity) }
+    }
+
+    fn record(&mut self, now: u64, prompt_tokens: u64, completion_tokens: u64) {
+        let start = now - now % self.width;
+        if self.buckets.back().is_none_or(|bucket| bucket.start != start) {
+            self.buckets.push_back(UsageBucket { start, ..UsageBucket::default() });
+            if self.buckets.len() > self.capacity {
+                self.buckets.pop_front();
+            }
+        }
+        if let Some(bucket) = self.buckets.back_mut() {
+            bucket.completions += 1;
+            bucket.prompt_tokens += prompt_tokens;
+            bucket.completion_tokens += completion_tokens;
+        }
+    }
+
+    // Every period of the window ending with the current one, idle ones included, so
+    // rates can be charted without filling gaps
+    fn series(&self, now: u64) -> Vec<UsageBucket> {
+        let current = now - now % self.width;
+        (0..self.capacity as u64)
+            .rev()
+            .map(|ago| current.saturating_sub(ago * self.width))
+            .map(|start| {
+                let recorded = self.buckets.iter().find(|bucket| bucket.start == start);
+                recorded.copied().unwrap_or(UsageBucket { start, ..UsageBucket::default() })
+            })
+            .collect()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+// Requests in flight to each host from every client, since clients sending to the
+// same host share its connections' load and its rate limits. Hosts with none are removed.
+fn hosts() -> &'static Mutex<HashMap<String, usize>> {
+    static HOSTS: OnceLock<Mutex<HashMap<String, usize>>> = OnceLock::new();
+    HOSTS.get_or_init(Mutex::default)
+}
+
+// Counts one thing for as long as it is held
+pub struct Gauge<'a> {
+    counter: &'a AtomicUsize,
+    // Counted against its host too, for requests in flight
+    host: Option<&'a str>,
+}
+
+impl<'a> Gauge<'a> {
+    fn enter(counter: &'a AtomicUsize, host: Option<&'a str>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let (Some(host), Ok(mut hosts)) = (host, hosts().lock()) {
+            *hosts.entry(host.to_string()).or_default() += 1;
+        }
+        Gauge { counter, host }
+    }
+}
+
+impl Drop for Gauge<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+        if let (Some(host), Ok(mut hosts)) = (self.host, hosts().lock()) {
+            if let Some(count) = hosts.get_mut(host) {
+                *count -= 1;
+                if *count == 0 {
+                    hosts.remove(host);
+                }
+            }
+        }
+    }
+}
+
+impl ClientStats {
+    pub fn new(base_url: &str) -> Self {
+        // Keeps an explicit port, so local servers on different ports are told apart
+        let host = reqwest::Url::parse(base_url)
+            .ok()
+            .and_then(|url| Some(format!("{}{}", url.host_str()?, url.port().map(|port| format!(":{}", port)).unwrap_or_default())))
+            .unwrap_or_else(|| base_url.to_string());
+        ClientStats {
+            host,
+            in_flight: AtomicUsize::default(),
+            queued: AtomicUsize::default(),
+            streams: AtomicUsize::default(),
+            requests: AtomicU64::default(),
+            usage: Mutex::default(),
+        }
+    }
+
+    // One attempt at a request, until its response arrives
+    pub fn request(&self) -> Gauge<'_> {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        Gauge::enter(&self.in_flight, Some(&self.host))
+    }
+
+    // Reading the body of a response whose attempt was already counted
+    pub fn reading(&self) -> Gauge<'_> {
+        Gauge::enter(&self.in_flight, Some(&self.host))
+    }
+
+    pub fn queued(&self) -> Gauge<'_> {
+        Gauge::enter(&self.queued, None)
+    }
+
+    pub fn stream(&self) -> Gauge<'_> {
+        Gauge::enter(&self.streams, None)
+    }
+
+    // Records a finished chat completion's tokens in the usage history
+    pub fn record_usage(&self, prompt_tokens: u64, completion_tokens: u64) {
+        let now = unix_now();
+        if let Ok(mut usage) = self.usage.lock() {
+            usage.minutes.record(now, prompt_tokens, completion_tokens);
+            usage.hours.record(now, prompt_tokens, completion_tokens);
+        }
+    }
+
+    // Records a chat completion response by its reported `usage`
+    pub fn record_response(&self, raw: &serde_json::Value) {
+        let tokens = |key: &str| raw["usage"][key].as_u64().unwrap_or(0);
+        self.record_usage(tokens("prompt_tokens"), tokens("completion_tokens"));
+    }
+}
+
+#[derive(NifMap)]
+struct Snapshot {
+    in_flight: usize,
+    queued: usize,
+    streams: usize,
+    // Attempts sent since the client was created, retries included
+    requests: u64,
+    // Requests in flight to each host, from every client
+    hosts: HashMap<String, usize>,
+}
+
+#[rustler::nif]
+fn read_client_stats(client_resource: ResourceArc<OpenAIClientResource>) -> Snapshot {
+    let stats = &client_resource.stats;
+    Snapshot {
+        in_flight: stats.in_flight.load(Ordering::Relaxed),
+        queued: stats.queued.load(Ordering::Relaxed),
+        streams: stats.streams.load(Ordering::Relaxed),
+        requests: stats.requests.load(Ordering::Relaxed),
+        hosts: hosts().lock().map(|hosts| hosts.clone()).unwrap_or_default(),
+    }
+}
+
+// Chat completion usage by minute over the last hour, or by hour over the last day,
+// oldest first. Kept in memory only, so it starts over with the client.
+#[rustler::nif]
+fn read_usage_history(client_resource: ResourceArc<OpenAIClientResource>, resolution: rustler::Atom) -> NifResult<Vec<UsageBucket>> {
+    let usage = client_resource.stats.usage.lock()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to lock usage history: {}", e))))?;
+    if resolution == atoms::minute() {
+        Ok(usage.minutes.series(unix_now()))
+    } else if resolution == atoms::hour() {
+        Ok(usage.hours.series(unix_now()))
+    } else {
+        Err(options::invalid("resolution", "must be :minute or :hour"))
+    }
+}
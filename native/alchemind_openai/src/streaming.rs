@@ -0,0 +1,519 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_openai::types::CompletionUsage;
+use rustler::env::{OwnedEnv, SavedTerm};
+use rustler::sys::{enif_monotonic_time, ErlNifTimeUnit};
+use rustler::types::tuple;
+use rustler::{Atom, Encoder, Env, Error, LocalPid, NifMap, NifResult, ResourceArc, Term};
+
+use crate::cancel::CancelToken;
+use crate::options::{self, Opts};
+use crate::{atoms, tokens};
+
+// Hard cap on a stream's accumulated text, so a runaway generation can't exhaust
+// memory where the final message is assembled
+const DEFAULT_MAX_STREAM_BYTES: usize = 8 * 1024 * 1024;
+
+// Local budget on streamed output, enforced even when the server ignores max_tokens
+struct StreamLimits {
+    model: String,
+    max_chars: Option<usize>,
+    max_tokens: Option<usize>,
+    max_bytes: usize,
+    chars: usize,
+    tokens: usize,
+    bytes: usize,
+}
+
+impl StreamLimits {
+    pub fn decode(opts: &Opts, model: &str) -> NifResult<Self> {
+        Ok(StreamLimits {
+            model: model.to_string(),
+            max_chars: options::get_usize(opts, "max_stream_chars")?,
+            max_tokens: options::get_usize(opts, "max_stream_tokens")?,
+            max_bytes: options::get_usize(opts, "max_stream_bytes")?.unwrap_or(DEFAULT_MAX_STREAM_BYTES),
+            chars: 0,
+            tokens: 0,
+            bytes: 0,
+        })
+    }
+
+    // Admit as much of `delta` as fits in the budget. Returns the admitted text and
+    // why the stream should stop here, if it should: the delta had to be cut, or
+    // would take the stream past `max_stream_bytes`, in which case none of it is.
+    pub fn admit(&mut self, delta: &str) -> (String, Option<Halt>) {
+        if self.bytes + delta.len() > self.max_bytes {
+            return (String::new(), Some(Halt::MaxSizeExceeded));
+        }
+        self.bytes += delta.len();
+        if self.max_chars.is_none() && self.max_tokens.is_none() {
+            return (delta.to_string(), None);
+        }
+
+        let mut admitted: String = match self.max_chars {
+            Some(max) => delta.chars().take(max.saturating_sub(self.chars)).collect(),
+            None => delta.to_string(),
+        };
+
+        if let Some(max) = self.max_tokens {
+            let remaining = max.saturating_sub(self.tokens);
+            let (text, count) = tokens::with_bpe(&self.model, |bpe| {
+                let encoded = bpe.encode_ordinary(&admitted);
+                if encoded.len() <= remaining {
+                    (admitted.clone(), encoded.len())
+                } else {
+                    (bpe.decode(encoded[..remaining].to_vec()).unwrap_or_default(), remaining)
+                }
+            });
+            admitted = text;
+            self.tokens += count;
+        }
+
+        self.chars += admitted.chars().count();
+        let cut = admitted.len() < delta.len();
+        (admitted, cut.then_some(Halt::LimitReached))
+    }
+}
+
+// Why a filtered stream stopped before the server finished it
+pub enum Halt {
+    StopSequence,
+    LimitReached,
+    // Not a clean stop: the stream is abandoned with a `max_size_exceeded` error
+    MaxSizeExceeded,
+}
+
+// Applies local stop sequences and limits to streamed deltas
+pub struct StreamFilter {
+    stops: Vec<String>,
+    // Text held back because it could be the start of a stop sequence
+    pending: String,
+    limits: StreamLimits,
+}
+
+impl StreamFilter {
+    pub fn decode(opts: &Opts, model: &str) -> NifResult<Self> {
+        Ok(StreamFilter {
+            stops: options::get_strings(opts, "local_stop")?
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|stop| !stop.is_empty())
+                .collect(),
+            pending: String::new(),
+            limits: StreamLimits::decode(opts, model)?,
+        })
+    }
+
+    // Returns the text that can be emitted now and whether the stream should stop
+    pub fn push(&mut self, delta: &str) -> (String, Option<Halt>) {
+        let (text, stopped) = self.scan(delta);
+        match self.limits.admit(&text) {
+            (admitted, None) if stopped => (admitted, Some(Halt::StopSequence)),
+            (admitted, halt) => (admitted, halt),
+        }
+    }
+
+    // Release any held back text once no more deltas are coming
+    pub fn flush(&mut self) -> (String, Option<Halt>) {
+        let pending = std::mem::take(&mut self.pending);
+        self.limits.admit(&pending)
+    }
+
+    fn scan(&mut self, delta: &str) -> (String, bool) {
+        if self.stops.is_empty() {
+            return (delta.to_string(), false);
+        }
+
+        self.pending.push_str(delta);
+
+        // Cut at the earliest stop sequence, dropping it and everything after it
+        if let Some(position) = self.stops.iter().filter_map(|stop| self.pending.find(stop.as_str())).min() {
+            let text = self.pending[..position].to_string();
+            self.pending.clear();
+            return (text, true);
+        }
+
+        // Hold back the longest tail that a later delta could complete into a stop sequence
+        let held = self
+            .stops
+            .iter()
+            .filter_map(|stop| {
+                (1..stop.len())
+                    .rev()
+                    .find(|&len| stop.is_char_boundary(len) && self.pending.ends_with(&stop[..len]))
+            })
+            .max()
+            .unwrap_or(0);
+
+        let split = self.pending.len() - held;
+        let text = self.pending[..split].to_string();
+        self.pending.drain(..split);
+        (text, false)
+    }
+}
+
+// Words that end in a period without ending the sentence
+const ABBREVIATIONS: &[&str] = &["mr", "mrs", "ms", "dr", "prof", "sr", "jr", "st", "vs", "etc", "e.g", "i.e"];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Segmentation {
+    Delta,
+    Sentence,
+    Clause,
+}
+
+// Regroups streamed text into whole sentences or clauses, so consumers like
+// TTS never receive a cut-off word. Segments keep their trailing whitespace,
+// so joining them reproduces the streamed text.
+pub struct Segmenter {
+    mode: Segmentation,
+    buffer: String,
+}
+
+impl Segmenter {
+    pub fn decode(opts: &Opts, default: Segmentation) -> NifResult<Self> {
+        let mode = match options::get_atom(opts, "stream_mode")?.as_deref() {
+            None => default,
+            Some("delta") => Segmentation::Delta,
+            Some("sentence") => Segmentation::Sentence,
+            Some("clause") => Segmentation::Clause,
+            Some(other) => {
+                return Err(options::invalid(
+                    &options::path("stream_mode"),
+                    format!("must be :delta, :sentence or :clause, got :{}", other),
+                ))
+            },
+        };
+        Ok(Segmenter { mode, buffer: String::new() })
+    }
+
+    // Returns the segments completed by `text`
+    pub fn push(&mut self, text: &str) -> Vec<String> {
+        if self.mode == Segmentation::Delta {
+            return vec![text.to_string()];
+        }
+
+        self.buffer.push_str(text);
+        let mut segments = Vec::new();
+        while let Some(end) = self.boundary() {
+            segments.push(self.buffer.drain(..end).collect());
+        }
+        segments
+    }
+
+    // Release the unfinished segment once no more text is coming
+    pub fn flush(&mut self) -> Option<String> {
+        (!self.buffer.is_empty()).then(|| std::mem::take(&mut self.buffer))
+    }
+
+    // Byte offset just past the first complete segment, including its trailing whitespace
+    fn boundary(&self) -> Option<usize> {
+        let chars: Vec<(usize, char)> = self.buffer.char_indices().collect();
+
+        for (i, &(_, c)) in chars.iter().enumerate() {
+            let ends = match c {
+                '\n' | '。' | '！' | '？' => true,
+                '.' => !self.is_abbreviation(i, &chars),
+                '!' | '?' | '…' => true,
+                ',' | ';' | ':' => self.mode == Segmentation::Clause,
+                _ => false,
+            };
+            if !ends {
+                continue;
+            }
+
+            // Closing quotes and brackets belong to the segment they close
+            let mut j = i + 1;
+            while j < chars.len() && matches!(chars[j].1, '"' | '\'' | '”' | '’' | ')' | ']') {
+                j += 1;
+            }
+
+            // Latin punctuation only ends a segment before whitespace, so "3.14" and
+            // "a.m." stay whole; wait for the next delta when the buffer ends here
+            let needs_space = !matches!(c, '\n' | '。' | '！' | '？');
+            match chars.get(j) {
+                Some((_, next)) if next.is_whitespace() => {},
+                Some(_) if !needs_space => {},
+                None if !needs_space => return Some(self.buffer.len()),
+                _ => continue,
+            }
+
+            while j < chars.len() && chars[j].1.is_whitespace() {
+                j += 1;
+            }
+            return Some(chars.get(j).map_or(self.buffer.len(), |&(offset, _)| offset));
+        }
+
+        None
+    }
+
+    fn is_abbreviation(&self, period: usize, chars: &[(usize, char)]) -> bool {
+        let start = chars[..period]
+            .iter()
+            .rposition(|(_, c)| c.is_whitespace())
+            .map_or(0, |space| space + 1);
+        let word: String = chars[start..period].iter().map(|(_, c)| c.to_ascii_lowercase()).collect();
+        // Single letters are initials, as in "J. R. R. Tolkien"
+        ABBREVIATIONS.contains(&word.as_str()) || (word.chars().count() == 1 && word.chars().all(char::is_alphabetic))
+    }
+}
+
+// Messages a chat stream sends its consumer, each ending with the stream's ref:
+//
+//   {:stream_started, started, ref}          once, when the first response arrives
+//   {:stream_chunk, text, timing, ref}       for each piece of content
+//   {:stream_tool_call_delta, delta, ref}    for each piece of a tool call
+//   {:stream_usage, usage, ref}              once, just before :stream_done
+//   {:stream_done, ref}                      the server finished or a local stop matched
+//   {:stream_done, :limit_reached, ref}      max_stream_chars/max_stream_tokens cut it short
+//   {:stream_done, done, ref}                either of the above with `include_usage`
+//   {:stream_cancelled, ref}                 the `cancel` token was cancelled
+//   {:stream_error, error, ref}              the stream failed
+//
+// :stream_done, :stream_cancelled and :stream_error are final; nothing follows them.
+// A stream pulled with `next_chunk` returns the same messages, without the ref.
+
+#[derive(NifMap)]
+pub struct StreamStarted {
+    pub id: String,
+    pub model: String,
+}
+
+// Part of a tool call. The first delta of a call has its `id` and `name`; later ones
+// only add to its JSON `arguments`. `index` tells the calls of one response apart.
+#[derive(NifMap)]
+pub struct ToolCallDelta {
+    pub index: i32,
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub arguments: String,
+}
+
+// Counted with the model's tokenizer, since a streamed response doesn't report usage
+// unless asked to with `include_usage`. Prompt tokens counted locally leave out the few
+// tokens of per-message formatting.
+#[derive(NifMap, Clone)]
+pub struct StreamUsage {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub total_tokens: usize,
+}
+
+impl From<CompletionUsage> for StreamUsage {
+    fn from(usage: CompletionUsage) -> Self {
+        StreamUsage {
+            prompt_tokens: usage.prompt_tokens as usize,
+            completion_tokens: usage.completion_tokens as usize,
+            total_tokens: usage.total_tokens as usize,
+        }
+    }
+}
+
+// How a stream with `include_usage` finished. `finish_reason` is the server's, such as
+// "stop" or "length", or "limit_reached" when a local limit cut it short.
+#[derive(NifMap)]
+pub struct StreamDone {
+    pub finish_reason: String,
+    pub usage: StreamUsage,
+}
+
+// Sent as `{:stream_error, error, ref}` when a stream fails, with what was received
+// so far so the caller can keep the partial output or start over
+#[derive(NifMap)]
+pub struct StreamError {
+    pub message: String,
+    // Whether starting the stream over could succeed
+    pub retryable: bool,
+    // All text received before the failure, and how many chunks it came in
+    pub partial: String,
+    pub chunks: usize,
+    // :max_size_exceeded when the stream passed `max_stream_bytes`, :proxy_idle_timeout
+    // when it went quiet and was most likely closed by a proxy, nil otherwise
+    pub code: Option<Atom>,
+}
+
+impl StreamError {
+    pub fn new(message: String, retryable: bool, partial: String, chunks: usize) -> Self {
+        StreamError { message, retryable, partial, chunks, code: None }
+    }
+
+    pub fn max_size_exceeded(partial: String, chunks: usize) -> Self {
+        StreamError {
+            message: "Stream exceeded max_stream_bytes".to_string(),
+            retryable: false,
+            partial,
+            chunks,
+            code: Some(atoms::max_size_exceeded()),
+        }
+    }
+
+    // A proxy closing a stream it saw as idle is worth retrying, perhaps with
+    // keep_warm pings more frequent than the proxy's timeout
+    pub fn proxy_idle_timeout(message: String, partial: String, chunks: usize) -> Self {
+        StreamError { message, retryable: true, partial, chunks, code: Some(atoms::proxy_idle_timeout()) }
+    }
+}
+
+// When a chunk was received, in Erlang monotonic microseconds so it lines up with
+// `System.monotonic_time(:microsecond)` in the consumer
+#[derive(NifMap)]
+pub struct ChunkTiming {
+    at_us: i64,
+    // Gap since the previous chunk of the stream; nil for the first chunk
+    since_previous_us: Option<i64>,
+}
+
+// Timestamps chunks as they arrive, before any mailbox delay
+#[derive(Default)]
+pub struct ChunkClock {
+    previous: Option<i64>,
+}
+
+impl ChunkClock {
+    pub fn tick(&mut self) -> ChunkTiming {
+        let at_us = unsafe { enif_monotonic_time(ErlNifTimeUnit::ERL_NIF_USEC) };
+        let since_previous_us = self.previous.map(|previous| at_us - previous);
+        self.previous = Some(at_us);
+        ChunkTiming { at_us, since_previous_us }
+    }
+}
+
+// Messages a pulled stream can get ahead of its consumer by. Once they are queued the
+// stream's thread stops reading, and the server waits, until `next_chunk` takes some.
+const QUEUED_MESSAGES: usize = 64;
+
+// Where a stream's messages go
+pub enum Consumer {
+    // Sent to a process, with the stream's ref appended. The ref lives in an
+    // environment of its own, since sending clears `env`.
+    Process { pid: LocalPid, env: OwnedEnv, ref_env: OwnedEnv, ref_term: SavedTerm },
+    // Queued for `next_chunk`, each message in an environment of its own
+    Queue(SyncSender<(OwnedEnv, SavedTerm)>),
+}
+
+impl Consumer {
+    pub fn process(pid: LocalPid, ref_term: Term) -> Self {
+        let ref_env = OwnedEnv::new();
+        let ref_term = ref_env.save(ref_term);
+        Consumer::Process { pid, env: OwnedEnv::new(), ref_env, ref_term }
+    }
+
+    // A queue, and the stream handle `next_chunk` takes its messages from
+    pub fn queue() -> (Self, ResourceArc<ChatStream>) {
+        let (sender, receiver) = mpsc::sync_channel(QUEUED_MESSAGES);
+        let stream = ChatStream { queue: Mutex::new(receiver), stop: ResourceArc::new(CancelToken::default()) };
+        (Consumer::Queue(sender), ResourceArc::new(stream))
+    }
+}
+
+// Handle of a stream read with `next_chunk`. Dropping it, once Elixir no longer
+// refers to it, makes the stream's thread stop at its next message.
+pub struct ChatStream {
+    queue: Mutex<Receiver<(OwnedEnv, SavedTerm)>>,
+    // Cancelled by `cancel_chat_stream`; the stream's thread holds it, not the handle
+    pub stop: ResourceArc<CancelToken>,
+}
+
+impl rustler::Resource for ChatStream {}
+
+// Cancels a stream read with `next_chunk`, closing its connection so the server stops
+// generating tokens, even before the first. Messages not taken yet are dropped, which
+// also frees a thread waiting on a full queue; :stream_cancelled then comes last.
+// Idempotent, and streams that already ended are unaffected.
+#[rustler::nif]
+fn cancel_chat_stream(stream: ResourceArc<ChatStream>) -> Atom {
+    stream.stop.cancel();
+    // A consumer waiting in `next_chunk` holds the lock, but then the queue is empty
+    if let Ok(queue) = stream.queue.try_lock() {
+        while queue.try_recv().is_ok() {}
+    }
+    atoms::ok()
+}
+
+// Takes up to `max` of a stream's messages, waiting up to `timeout_ms` for the first
+// if none is queued yet, so a quiet stream never holds a dirty scheduler for longer.
+// Returns :timeout if none arrived by then, and an empty list once the stream has
+// ended and every message was taken.
+#[rustler::nif(schedule = "DirtyIo")]
+fn next_chunk<'a>(env: Env<'a>, stream: ResourceArc<ChatStream>, max: usize, timeout_ms: u64) -> NifResult<Term<'a>> {
+    let queue = stream.queue.lock().map_err(|e| Error::Term(Box::new(format!("Failed to lock stream: {}", e))))?;
+    let first = match queue.recv_timeout(Duration::from_millis(timeout_ms)) {
+        Ok(first) => first,
+        Err(RecvTimeoutError::Timeout) => return Ok(atoms::timeout().encode(env)),
+        Err(RecvTimeoutError::Disconnected) => return Ok(Vec::<Term>::new().encode(env)),
+    };
+    let messages = std::iter::once(first).chain(std::iter::from_fn(|| queue.try_recv().ok()));
+    Ok(messages
+        .take(max.max(1))
+        .map(|(owned, message)| owned.run(|owned_env| message.load(owned_env).in_env(env)))
+        .collect::<Vec<Term>>()
+        .encode(env))
+}
+
+// Sends a stream's messages to its consumer from a thread outside the VM. Content is
+// cut into chunks by the segmenter and kept, so usage and errors can report
+// everything that was delivered.
+pub struct StreamSender {
+    consumer: Consumer,
+    segmenter: Segmenter,
+    clock: ChunkClock,
+    pub received: String,
+    pub chunks: usize,
+}
+
+impl StreamSender {
+    pub fn new(consumer: Consumer, segmenter: Segmenter) -> Self {
+        StreamSender {
+            consumer,
+            segmenter,
+            clock: ChunkClock::default(),
+            received: String::new(),
+            chunks: 0,
+        }
+    }
+
+    // Sends `message`, a tuple, with the ref appended when it goes to a process. False
+    // once the consumer has exited or dropped the handle, after which the stream can be
+    // dropped. A full queue blocks until the consumer takes from it.
+    pub fn send(&mut self, message: impl Encoder) -> bool {
+        match &mut self.consumer {
+            Consumer::Process { pid, env, ref_env, ref_term } => env
+                .send_and_clear(pid, |env| {
+                    let mut items = tuple::get_tuple(message.encode(env)).unwrap_or_default();
+                    items.push(ref_env.run(|owned| ref_term.load(owned).in_env(env)));
+                    tuple::make_tuple(env, &items)
+                })
+                .is_ok(),
+            Consumer::Queue(queue) => {
+                let owned = OwnedEnv::new();
+                let message = owned.run(|env| owned.save(message.encode(env)));
+                queue.send((owned, message)).is_ok()
+            },
+        }
+    }
+
+    // Sends the segments of `text` that are complete as :stream_chunk messages
+    pub fn push(&mut self, text: &str) -> bool {
+        if text.is_empty() {
+            return true;
+        }
+        self.segmenter.push(text).into_iter().all(|segment| self.chunk(segment))
+    }
+
+    // Sends whatever the segmenter held back, once no more text is coming
+    pub fn flush(&mut self) -> bool {
+        match self.segmenter.flush() {
+            Some(segment) => self.chunk(segment),
+            None => true,
+        }
+    }
+
+    fn chunk(&mut self, segment: String) -> bool {
+        self.received.push_str(&segment);
+        self.chunks += 1;
+        let timing = self.clock.tick();
+        self.send((atoms::stream_chunk(), segment, timing))
+    }
+}
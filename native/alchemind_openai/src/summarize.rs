@@ -0,0 +1,141 @@
+use async_openai::types::CreateChatCompletionRequestArgs;
+use futures_util::StreamExt;
+use rustler::{Encoder, Env, Error, LocalPid, NifMap, NifResult, ResourceArc, Term};
+
+use crate::chunking::{self, Strategy};
+use crate::options::{self, Opts};
+use crate::http::Transport;
+use crate::deadline::Deadline;
+use crate::limits::Endpoint;
+use crate::{atoms, request_content, to_request_messages, tokens, Message, OpenAIClientResource};
+
+const MAP_PROMPT: &str = "You are summarizing one section of a longer document. Write a concise summary \
+of the section that preserves key facts, names, numbers, decisions and open questions. Do not add \
+information that is not in the section.";
+
+const REDUCE_PROMPT: &str = "You are given summaries of consecutive sections of a longer document. \
+Combine them into a single coherent summary that preserves key facts, names, numbers, decisions and \
+open questions, removing repetition. Do not add information that is not in the summaries.";
+
+#[derive(NifMap)]
+struct SummaryResult {
+    summary: String,
+    chunks: usize,
+    // Number of reduce passes needed to combine the chunk summaries
+    levels: usize,
+}
+
+// Sends {:summarize_progress, ref, stage, completed, total} to the progress pid, if any
+struct Progress<'a> {
+    env: Env<'a>,
+    pid: Option<LocalPid>,
+    reference: Term<'a>,
+}
+
+impl Progress<'_> {
+    fn report(&self, stage: rustler::Atom, completed: usize, total: usize) {
+        if let Some(pid) = &self.pid {
+            let _ = self.env.send(pid, (atoms::summarize_progress(), self.reference, stage, completed, total));
+        }
+    }
+}
+
+async fn summarize(transport: &Transport, model: &str, system_prompt: &str, text: String) -> Result<String, String> {
+    let messages = vec![
+        Message::new("system", system_prompt.to_string()),
+        Message::new("user", text),
+    ];
+    let request = CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .messages(to_request_messages(messages)?)
+        .temperature(0.2)
+        .build()
+        .map_err(|e| format!("Failed to build request: {}", e))?;
+    request_content(transport, request).await
+}
+
+// Summarize every input concurrently, reporting progress as each one finishes, and
+// return the summaries in input order
+#[allow(clippy::too_many_arguments)]
+async fn summarize_all(
+    transport: &Transport,
+    model: &str,
+    system_prompt: &str,
+    inputs: Vec<String>,
+    concurrency: usize,
+    progress: &Progress<'_>,
+    stage: rustler::Atom,
+) -> Result<Vec<String>, String> {
+    let total = inputs.len();
+    let mut results = futures_util::stream::iter(inputs.into_iter().enumerate())
+        .map(|(i, input)| async move { (i, summarize(transport, model, system_prompt, input).await) })
+        .buffer_unordered(concurrency);
+
+    let mut summaries = vec![String::new(); total];
+    let mut completed = 0;
+    while let Some((i, result)) = results.next().await {
+        summaries[i] = result?;
+        completed += 1;
+        progress.report(stage, completed, total);
+    }
+
+    Ok(summaries)
+}
+
+#[rustler::nif(schedule = "DirtyIo")]
+fn summarize_long_text<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, text: String, opts: Opts<'a>) -> NifResult<SummaryResult> {
+    let chunk_options = chunking::decode_options_with_defaults(&opts, Strategy::Paragraph, 3000, 100)?;
+    let model = options::get_string(&opts, "model")?.unwrap_or_else(|| "gpt-4o-mini".to_string());
+    let concurrency = options::get_usize(&opts, "concurrency")?.unwrap_or(4).max(1);
+    let group_size = options::get_usize(&opts, "group_size")?.unwrap_or(5).max(2);
+    let instructions = options::get_string(&opts, "instructions")?;
+
+    let progress = Progress {
+        env,
+        pid: options::get_pid(&opts, "progress")?,
+        reference: opts.get("ref").copied().unwrap_or_else(|| rustler::types::atom::nil().encode(env)),
+    };
+
+    let (map_prompt, reduce_prompt) = match &instructions {
+        Some(extra) => (format!("{} {}", MAP_PROMPT, extra), format!("{} {}", REDUCE_PROMPT, extra)),
+        None => (MAP_PROMPT.to_string(), REDUCE_PROMPT.to_string()),
+    };
+
+    let chunks: Vec<String> = tokens::with_bpe(&chunk_options.model, |bpe| {
+        chunking::chunk(&text, &chunk_options, bpe)
+            .into_iter()
+            .map(|chunk| chunk.text)
+            .collect()
+    });
+    let chunk_count = chunks.len();
+
+    if chunk_count == 0 {
+        return Ok(SummaryResult { summary: String::new(), chunks: 0, levels: 0 });
+    }
+
+    let transport = client_resource.transport_for(Endpoint::Chat, &opts)?.with_deadline(Deadline::decode(&opts)?);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let result = runtime.block_on(async {
+        let mut summaries = summarize_all(&transport, &model, &map_prompt, chunks, concurrency, &progress, atoms::map()).await?;
+
+        // Combine summaries group by group until a single summary remains
+        let mut levels = 0;
+        while summaries.len() > 1 {
+            let groups = summaries
+                .chunks(group_size)
+                .map(|group| group.join("\n\n---\n\n"))
+                .collect();
+            summaries = summarize_all(&transport, &model, &reduce_prompt, groups, concurrency, &progress, atoms::reduce()).await?;
+            levels += 1;
+        }
+
+        Ok::<_, String>((summaries.pop().unwrap_or_default(), levels))
+    });
+
+    let (summary, levels) = result.map_err(|e| Error::Term(Box::new(e)))?;
+
+    Ok(SummaryResult { summary, chunks: chunk_count, levels })
+}
@@ -0,0 +1,20 @@
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use tiktoken_rs::CoreBPE;
+
+// Run `f` with the shared tokenizer for the given model, falling back to
+// cl100k_base for models tiktoken doesn't know about
+pub fn with_bpe<R>(model: &str, f: impl FnOnce(&CoreBPE) -> R) -> R {
+    let bpe = match get_tokenizer(model) {
+        Some(Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+        Some(Tokenizer::P50kBase) => tiktoken_rs::p50k_base_singleton(),
+        Some(Tokenizer::P50kEdit) => tiktoken_rs::p50k_edit_singleton(),
+        Some(Tokenizer::R50kBase) | Some(Tokenizer::Gpt2) => tiktoken_rs::r50k_base_singleton(),
+        _ => tiktoken_rs::cl100k_base_singleton(),
+    };
+    let guard = bpe.lock();
+    f(&guard)
+}
+
+pub fn count(bpe: &CoreBPE, text: &str) -> usize {
+    bpe.encode_with_special_tokens(text).len()
+}
@@ -0,0 +1,150 @@
+use rustler::{Env, NifResult, Term, TermType};
+use serde_json::{json, Map, Value};
+
+use crate::{is_valid_name, json, options};
+
+// JSON Schema types a parameter may have
+const TYPES: &[&str] = &["string", "integer", "number", "boolean", "array", "object"];
+
+// An atom or string, e.g. a parameter's type
+fn decode_name(term: Term, path: &str) -> NifResult<String> {
+    match term.get_type() {
+        TermType::Atom => term.atom_to_string(),
+        _ => options::decode_at(term, path, "atom or string"),
+    }
+}
+
+// The schema of an object's `params`, listing the required ones
+fn decode_params(term: Term, path: &str) -> NifResult<Value> {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for (name, spec) in options::entries(term, path)? {
+        let param_path = format!("{}.{}", path, name);
+        let (schema, is_required) = decode_param(spec, &param_path)?;
+        if properties.insert(name.clone(), schema).is_some() {
+            return Err(options::invalid(&param_path, "is defined twice"));
+        }
+        if is_required {
+            required.push(Value::String(name));
+        }
+    }
+
+    let mut schema = json!({"type": "object", "properties": properties});
+    if !required.is_empty() {
+        schema["required"] = Value::Array(required);
+    }
+    Ok(schema)
+}
+
+fn decode_param(term: Term, path: &str) -> NifResult<(Value, bool)> {
+    decode_param_entries(options::entries(term, path)?, path)
+}
+
+// One parameter's schema, and whether it is required. `type` may be `{:array, item_type}`
+// as a shorthand for `type: :array, items: [type: item_type]`.
+fn decode_param_entries(entries: Vec<(String, Term)>, path: &str) -> NifResult<(Value, bool)> {
+    let mut kind = None;
+    let mut items = None;
+    let mut params = None;
+    let mut description = None;
+    let mut values = None;
+    let mut required = false;
+    for (key, value) in entries {
+        let key_path = format!("{}.{}", path, key);
+        match key.as_str() {
+            "type" => match rustler::types::tuple::get_tuple(value).ok().as_deref() {
+                Some([tag, item_type]) if tag.atom_to_string().ok().as_deref() == Some("array") => {
+                    kind = Some("array".to_string());
+                    items = Some(decode_type(*item_type, &format!("{}.items", key_path))?);
+                },
+                _ => kind = Some(decode_name(value, &key_path)?),
+            },
+            "items" => items = Some(decode_type(value, &key_path)?),
+            "params" => params = Some(decode_params(value, &key_path)?),
+            "description" => description = Some(options::decode_at::<String>(value, &key_path, "string")?),
+            "enum" => values = Some((json::from_term(value).map_err(|e| options::invalid(&key_path, e))?, key_path)),
+            "required" => required = options::decode_at(value, &key_path, "boolean")?,
+            other => return Err(options::invalid(path, format!("has unknown key :{}", other))),
+        }
+    }
+
+    let kind = kind.ok_or_else(|| options::invalid(path, "needs a type"))?;
+    if !TYPES.contains(&kind.as_str()) {
+        return Err(options::invalid(&format!("{}.type", path), format!("must be one of {}, got {:?}", TYPES.join(", "), kind)));
+    }
+
+    let mut schema = match (kind.as_str(), items, params) {
+        ("array", Some(items), None) => json!({"type": "array", "items": items}),
+        ("array", None, _) => return Err(options::invalid(path, "is an array and needs items")),
+        ("object", None, Some(params)) => params,
+        ("object", None, None) => json!({"type": "object"}),
+        (_, Some(_), _) => return Err(options::invalid(path, "has items but isn't an array")),
+        (_, _, Some(_)) => return Err(options::invalid(path, "has params but isn't an object")),
+        (kind, None, None) => json!({"type": kind}),
+    };
+    if let Some((values, values_path)) = values {
+        let matches = |value: &Value| match kind.as_str() {
+            "string" => value.is_string(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "number" => value.is_number(),
+            "boolean" => value.is_boolean(),
+            _ => false,
+        };
+        match &values {
+            Value::Array(list) if list.is_empty() => return Err(options::invalid(&values_path, "must not be empty")),
+            Value::Array(list) => {
+                if let Some(value) = list.iter().find(|value| !matches(value)) {
+                    return Err(options::invalid(&values_path, format!("has {}, which isn't of type {}", value, kind)));
+                }
+            },
+            _ => return Err(options::invalid(&values_path, "must be a list")),
+        }
+        schema["enum"] = values;
+    }
+    if let Some(description) = description {
+        schema["description"] = Value::String(description);
+    }
+    Ok((schema, required))
+}
+
+// An array's item type: a type name, or a full parameter spec
+fn decode_type(term: Term, path: &str) -> NifResult<Value> {
+    let (schema, _) = match term.get_type() {
+        TermType::Atom | TermType::Binary => decode_param_entries(vec![("type".to_string(), term)], path)?,
+        _ => decode_param(term, path)?,
+    };
+    Ok(schema)
+}
+
+// Builds a tool for the `tools` option from a spec with the function's `name`, an
+// optional `description` and its `params`, each with a `type` and optionally a
+// `description`, an `enum` of allowed values, `required: true`, array `items` and
+// object `params`. Every mistake is reported here, with its path, rather than by
+// the API on first use.
+#[rustler::nif]
+fn build_tool<'a>(env: Env<'a>, spec: Term<'a>) -> NifResult<Term<'a>> {
+    let path = "tool";
+    let mut name = None;
+    let mut description = None;
+    let mut parameters = json!({"type": "object", "properties": {}});
+    for (key, value) in options::entries(spec, path)? {
+        let key_path = format!("{}.{}", path, key);
+        match key.as_str() {
+            "name" => name = Some(options::decode_at::<String>(value, &key_path, "string")?),
+            "description" => description = Some(options::decode_at::<String>(value, &key_path, "string")?),
+            "params" => parameters = decode_params(value, &key_path)?,
+            other => return Err(options::invalid(path, format!("has unknown key :{}", other))),
+        }
+    }
+
+    let name = name.ok_or_else(|| options::invalid(path, "needs a name"))?;
+    if !is_valid_name(&name) {
+        return Err(options::invalid(&format!("{}.name", path), format!("{:?} must be 1 to 64 letters, digits, underscores or hyphens", name)));
+    }
+
+    let mut tool = json!({"name": name, "parameters": parameters});
+    if let Some(description) = description {
+        tool["description"] = Value::String(description);
+    }
+    Ok(json::to_term(env, &tool))
+}
@@ -0,0 +1,300 @@
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use reqwest::multipart::Form;
+use rustler::{Atom, Binary, Encoder, Env, Error, LocalPid, NifMap, NifResult, ResourceArc, Term};
+
+use crate::audio;
+use crate::cancel::{self, CancelToken};
+use crate::deadline::Deadline;
+use crate::http::{ApiError, Transport};
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::redact::Redactor;
+use crate::upload::{UploadCounter, UploadProgress};
+use crate::{atoms, OpenAIClientResource};
+
+// Formats transcribe_audio returns as requested; anything else falls back to text
+pub const TRANSCRIPTION_FORMATS: &[&str] = &["json", "text", "srt", "verbose_json", "vtt"];
+
+// Extensions the API accepts uploads under; it detects the format from the file name
+pub const UPLOAD_FORMATS: &[&str] = &["flac", "m4a", "mp3", "mp4", "mpeg", "mpga", "oga", "ogg", "wav", "webm"];
+
+// Roughly Whisper's 224 token prompt limit
+const DEFAULT_PROMPT_WINDOW: usize = 800;
+
+// A transcript with the duration of its audio, which Whisper bills by
+#[derive(NifMap)]
+pub struct Transcription {
+    pub text: String,
+    // Probed from the audio; nil when its format can't be probed
+    pub audio_duration_s: Option<f64>,
+}
+
+#[derive(NifMap)]
+struct ChunkedTranscript {
+    text: String,
+    // Transcript of each chunk, in order
+    chunks: Vec<String>,
+    // Total of every chunk; nil unless all of them could be probed
+    audio_duration_s: Option<f64>,
+}
+
+#[derive(NifMap)]
+struct FileTranscript {
+    // Position in the input list
+    index: usize,
+    text: Option<String>,
+    audio_duration_s: Option<f64>,
+    error: Option<ApiError>,
+}
+
+// Request settings shared by every file or chunk
+struct Settings {
+    model: String,
+    language: Option<String>,
+    prompt: Option<String>,
+    temperature: Option<f32>,
+    audio_format: Option<String>,
+}
+
+impl Settings {
+    fn decode(opts: &Opts) -> NifResult<Self> {
+        Ok(Settings {
+            model: options::get_string(opts, "model")?.unwrap_or_else(|| "whisper-1".to_string()),
+            language: options::get_string(opts, "language")?,
+            prompt: options::get_string(opts, "prompt")?,
+            temperature: options::get_f32(opts, "temperature")?,
+            audio_format: decode_audio_format(opts)?,
+        })
+    }
+}
+
+// The `audio_format` option, naming the extension binaries are uploaded under
+pub fn decode_audio_format(opts: &Opts) -> NifResult<Option<String>> {
+    match options::get_atom(opts, "audio_format")? {
+        Some(format) if !UPLOAD_FORMATS.contains(&format.as_str()) => {
+            let (last, rest) = UPLOAD_FORMATS.split_last().unwrap();
+            Err(options::invalid(
+                &options::path("audio_format"),
+                format!("must be :{} or :{}, got :{}", rest.join(", :"), last, format),
+            ))
+        },
+        format => Ok(format),
+    }
+}
+
+// Name to upload `audio` under: `stem` with the given extension, or else the one its
+// bytes are sniffed as. Unrecognised audio is sent as webm.
+pub fn upload_name(stem: &str, audio: &[u8], format: Option<&str>) -> String {
+    let extension = format.or_else(|| audio::sniff_extension(audio)).unwrap_or("webm");
+    format!("{}.{}", stem, extension)
+}
+
+// Transcribes one file as JSON, returning its trimmed text. The form is rebuilt
+// for each attempt when rate limited, sharing the one copy of the audio.
+async fn transcribe(
+    transport: &Transport,
+    settings: &Settings,
+    audio: &Bytes,
+    file_name: &str,
+    prompt: Option<&str>,
+    upload: &UploadCounter,
+) -> Result<String, ApiError> {
+    let make_form = || {
+        let file = upload.part(audio).file_name(file_name.to_string());
+        let mut form = Form::new()
+            .part("file", file)
+            .text("model", settings.model.clone())
+            .text("response_format", "json");
+        if let Some(language) = &settings.language {
+            form = form.text("language", language.clone());
+        }
+        if let Some(prompt) = prompt {
+            form = form.text("prompt", prompt.to_string());
+        }
+        if let Some(temperature) = settings.temperature {
+            form = form.text("temperature", temperature.to_string());
+        }
+        Ok(form)
+    };
+
+    let response = transport.post_form("/audio/transcriptions", make_form).await?;
+    let text = response
+        .json::<serde_json::Value>()
+        .map_err(ApiError::local)?
+        .get("text")
+        .and_then(|text| text.as_str())
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    Ok(text)
+}
+
+// An input to transcribe_audio_many: audio data, or `{:path, path}` to read from disk
+enum Input<'a> {
+    Data(&'a [u8]),
+    Path(PathBuf),
+}
+
+impl<'a> Input<'a> {
+    fn decode(term: Term<'a>, index: usize) -> NifResult<Self> {
+        if let Ok(binary) = term.decode::<Binary>() {
+            return Ok(Input::Data(binary.as_slice()));
+        }
+        match term.decode::<(Atom, String)>() {
+            Ok((tag, path)) if tag == atoms::path() => Ok(Input::Path(PathBuf::from(path))),
+            _ => Err(options::invalid(&format!("inputs[{}]", index), "must be a binary or {:path, path}")),
+        }
+    }
+}
+
+// Paths keep their file name, which the API detects the format from
+async fn load(input: &Input<'_>, index: usize, format: Option<&str>) -> Result<(Bytes, String), ApiError> {
+    match input {
+        Input::Path(path) => {
+            let audio = tokio::fs::read(path)
+                .await
+                .map_err(|e| ApiError::local(format!("Failed to read {}: {}", path.display(), e)))?;
+            let file_name = path
+                .file_name()
+                .map_or_else(|| upload_name(&format!("audio-{}", index), &audio, format), |name| name.to_string_lossy().into_owned());
+            Ok((Bytes::from(audio), file_name))
+        },
+        Input::Data(data) => Ok((Bytes::copy_from_slice(data), upload_name(&format!("audio-{}", index), data, format))),
+    }
+}
+
+async fn transcribe_file(transport: &Transport, settings: &Settings, index: usize, input: &Input<'_>) -> Result<Transcription, ApiError> {
+    match load(input, index, settings.audio_format.as_deref()).await {
+        // Files upload concurrently, so there is no single upload to report on
+        Ok((audio, file_name)) => transcribe(transport, settings, &audio, &file_name, settings.prompt.as_deref(), &UploadCounter::default())
+            .await
+            .map(|text| Transcription { text, audio_duration_s: audio::probe_duration(&audio) })
+            .map_err(|e| e.context(&format!("API transcription request failed for {}", file_name))),
+        Err(error) => Err(error),
+    }
+}
+
+// The last `window` characters of `text`, starting at a word boundary
+fn tail(text: &str, window: usize) -> &str {
+    let count = text.chars().count();
+    if count <= window {
+        return text;
+    }
+
+    let start = text.char_indices().nth(count - window).map_or(0, |(i, _)| i);
+    let tail = &text[start..];
+    match tail.find(char::is_whitespace) {
+        Some(space) if !text[..start].ends_with(char::is_whitespace) => tail[space..].trim_start(),
+        _ => tail,
+    }
+}
+
+// Transcribes audio that was split into chunks, in order. Each request is prompted
+// with the end of the transcript so far, so names, casing and sentences carry over
+// chunk boundaries.
+// Upload progress covers all the chunks, as one upload.
+#[rustler::nif(schedule = "DirtyIo")]
+fn transcribe_audio_chunks<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, chunks: Vec<Binary>, opts: Opts<'a>) -> NifResult<ChunkedTranscript> {
+    let settings = Settings::decode(&opts)?;
+    let mut upload = UploadProgress::decode(env, &opts)?;
+    let window = options::get_usize(&opts, "prompt_window")?.unwrap_or(DEFAULT_PROMPT_WINDOW);
+    let redactor = Redactor::decode(&opts)?;
+
+    let transport = client_resource.transport_for(Endpoint::Audio, &opts)?.with_deadline(Deadline::decode(&opts)?);
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let mut transcripts: Vec<String> = Vec::with_capacity(chunks.len());
+    let audio_duration_s = chunks.iter().map(|chunk| audio::probe_duration(chunk.as_slice())).sum();
+    let total = chunks.iter().map(|chunk| chunk.len() as u64).sum();
+    let mut offset = 0;
+    for (index, chunk) in chunks.iter().enumerate() {
+        // The caller's prompt keeps its vocabulary hints; the previous transcript follows it
+        let context = if window > 0 { transcripts.last().map(|previous| tail(previous, window)) } else { None };
+        let chunk_prompt = match (&settings.prompt, context) {
+            (Some(prompt), Some(context)) => Some(format!("{} {}", prompt, context)),
+            (Some(prompt), None) => Some(prompt.clone()),
+            (None, context) => context.map(str::to_string),
+        };
+
+        let file_name = upload_name(&format!("audio-{}", index), chunk.as_slice(), settings.audio_format.as_deref());
+        let audio = Bytes::copy_from_slice(chunk.as_slice());
+        let counter = upload.counter(offset, total);
+        offset += audio.len() as u64;
+        let text = runtime
+            .block_on(upload.track(transcribe(&transport, &settings, &audio, &file_name, chunk_prompt.as_deref(), &counter)))
+            .map_err(|e| e.context(&format!("API transcription request failed for chunk {}", index)))?;
+        transcripts.push(text);
+    }
+
+    // Redact only at the end, so the prompts carry the words the model actually heard
+    let transcripts: Vec<String> = transcripts.iter().map(|text| redactor.apply(text)).collect();
+    let text = transcripts
+        .iter()
+        .filter(|text| !text.is_empty())
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    Ok(ChunkedTranscript { text, chunks: transcripts, audio_duration_s })
+}
+
+// Transcribes independent files (audio binaries or `{:path, path}`) with bounded concurrency.
+// As each file finishes, `pid` receives
+// `{:transcribe_progress, completed, total, index, :ok | :error}`. A failed file
+// doesn't stop the others; its error is returned in its place. When the `cancel`
+// token cuts the batch short, returns `{:cancelled, finished, pending}` with the
+// indices of the files still in flight or not yet started.
+#[rustler::nif(schedule = "DirtyIo")]
+fn transcribe_audio_many<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, inputs: Vec<Term<'a>>, opts: Opts, pid: LocalPid) -> NifResult<Term<'a>> {
+    let inputs = inputs.into_iter().enumerate().map(|(index, input)| Input::decode(input, index)).collect::<NifResult<Vec<_>>>()?;
+    let settings = Settings::decode(&opts)?;
+    let concurrency = options::get_usize(&opts, "concurrency")?.unwrap_or(4).max(1);
+    let redactor = Redactor::decode(&opts)?;
+    let cancel = CancelToken::decode(&opts)?;
+    let total = inputs.len();
+
+    let transport = client_resource.transport_for(Endpoint::Audio, &opts)?.with_deadline(Deadline::decode(&opts)?);
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let (transport, settings, cancel) = (&transport, &settings, cancel.as_deref());
+    let (mut results, mut pending) = runtime.block_on(async {
+        let mut running = futures_util::stream::iter(inputs.iter().enumerate())
+            .map(|(index, input)| async move {
+                (index, cancel::run(cancel, transcribe_file(transport, settings, index, input)).await)
+            })
+            .buffer_unordered(concurrency);
+
+        let mut results = Vec::with_capacity(total);
+        let mut pending = Vec::new();
+        while let Some((index, transcript)) = running.next().await {
+            let Some(transcript) = transcript else {
+                pending.push(index);
+                continue;
+            };
+            let status = if transcript.is_ok() { atoms::ok() } else { atoms::error() };
+            let _ = env.send(&pid, (atoms::transcribe_progress(), results.len() + 1, total, index, status));
+            results.push(match transcript {
+                Ok(transcript) => FileTranscript {
+                    index,
+                    text: Some(redactor.apply(&transcript.text)),
+                    audio_duration_s: transcript.audio_duration_s,
+                    error: None,
+                },
+                Err(error) => FileTranscript { index, text: None, audio_duration_s: None, error: Some(error) },
+            });
+        }
+        (results, pending)
+    });
+
+    results.sort_by_key(|result| result.index);
+    if !pending.is_empty() {
+        pending.sort_unstable();
+        return Ok((atoms::cancelled(), results, pending).encode(env));
+    }
+    Ok(results.encode(env))
+}
@@ -0,0 +1,95 @@
+use std::future::Future;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use reqwest::multipart::Part;
+use reqwest::Body;
+use rustler::{Encoder, Env, LocalPid, NifResult, Term};
+use tokio::sync::mpsc;
+
+use crate::atoms;
+use crate::options::{self, Opts};
+
+// Bytes handed to the connection between progress messages
+const UPLOAD_CHUNK: usize = 256 * 1024;
+
+// Sends {:upload_progress, ref, bytes_sent, total} to the `upload_progress` pid, if
+// any, as an upload body is read. The body is read on the runtime's worker threads,
+// which can't send to processes, so counts reach the NIF's thread over a channel.
+pub struct UploadProgress<'a> {
+    env: Env<'a>,
+    pid: Option<LocalPid>,
+    reference: Term<'a>,
+    sender: mpsc::UnboundedSender<(u64, u64)>,
+    receiver: mpsc::UnboundedReceiver<(u64, u64)>,
+}
+
+impl<'a> UploadProgress<'a> {
+    pub fn decode(env: Env<'a>, opts: &Opts<'a>) -> NifResult<Self> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok(UploadProgress {
+            env,
+            pid: options::get_pid(opts, "upload_progress")?,
+            reference: opts.get("ref").copied().unwrap_or_else(|| rustler::types::atom::nil().encode(env)),
+            sender,
+            receiver,
+        })
+    }
+
+    // Counts a file starting `offset` bytes into an upload of `total` bytes, so files
+    // sent one after another report as one upload
+    pub fn counter(&self, offset: u64, total: u64) -> UploadCounter {
+        UploadCounter { sender: self.pid.map(|_| self.sender.clone()), offset, total }
+    }
+
+    // Runs `future`, the request sending the parts, forwarding progress as it goes
+    pub async fn track<F: Future>(&mut self, future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let output = loop {
+            tokio::select! {
+                output = &mut future => break output,
+                Some((sent, total)) = self.receiver.recv() => self.report(sent, total),
+            }
+        };
+        while let Ok((sent, total)) = self.receiver.try_recv() {
+            self.report(sent, total);
+        }
+        output
+    }
+
+    fn report(&self, sent: u64, total: u64) {
+        if let Some(pid) = &self.pid {
+            let _ = self.env.send(pid, (atoms::upload_progress(), self.reference, sent, total));
+        }
+    }
+}
+
+// Builds file parts that count their bytes as they are read. The default counts
+// nothing, for uploads nobody tracks.
+#[derive(Clone, Default)]
+pub struct UploadCounter {
+    sender: Option<mpsc::UnboundedSender<(u64, u64)>>,
+    offset: u64,
+    total: u64,
+}
+
+impl UploadCounter {
+    // A retried attempt counts from the file's offset again
+    pub fn part(&self, data: &Bytes) -> Part {
+        let Some(sender) = self.sender.clone() else {
+            return Part::stream_with_length(data.clone(), data.len() as u64);
+        };
+
+        let chunks: Vec<Bytes> = (0..data.len())
+            .step_by(UPLOAD_CHUNK)
+            .map(|start| data.slice(start..(start + UPLOAD_CHUNK).min(data.len())))
+            .collect();
+        let (mut sent, total) = (self.offset, self.total);
+        let stream = futures_util::stream::iter(chunks).map(move |chunk| {
+            sent += chunk.len() as u64;
+            let _ = sender.send((sent, total));
+            Ok::<_, std::io::Error>(chunk)
+        });
+        Part::stream_with_length(Body::wrap_stream(stream), data.len() as u64)
+    }
+}
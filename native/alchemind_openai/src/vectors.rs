@@ -0,0 +1,192 @@
+use base64::Engine;
+use rustler::{Binary, Env, Error, NifResult, OwnedBinary};
+
+use crate::options::{self, Opts};
+
+#[derive(Clone, Copy)]
+enum Precision {
+    F32,
+    F16,
+}
+
+impl Precision {
+    fn decode(opts: &Opts) -> NifResult<Self> {
+        match options::get_atom(opts, "precision")?.as_deref() {
+            None | Some("f32") => Ok(Precision::F32),
+            Some("f16") => Ok(Precision::F16),
+            Some(other) => Err(options::invalid(&options::path("precision"), format!("must be :f32 or :f16, got :{}", other))),
+        }
+    }
+
+    fn width(&self) -> usize {
+        match self {
+            Precision::F32 => 4,
+            Precision::F16 => 2,
+        }
+    }
+}
+
+// IEEE 754 half precision, rounding to nearest even
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7f_ffff;
+
+    if exponent == 0xff {
+        // Infinity stays infinity, NaN stays NaN
+        return sign | 0x7c00 | if mantissa != 0 { 0x200 } else { 0 };
+    }
+
+    let exponent = exponent - 127 + 15;
+    if exponent >= 0x1f {
+        return sign | 0x7c00;
+    }
+
+    // Bits shifted out below the result's last bit decide the rounding: round up
+    // past the halfway point, or at exactly halfway when the result is odd
+    let round = |value: u32, shift: u32| {
+        let halfway = 1 << (shift - 1);
+        let truncated = value >> shift;
+        if value & halfway != 0 && value & (3 * halfway - 1) != 0 {
+            truncated + 1
+        } else {
+            truncated
+        }
+    };
+
+    if exponent <= 0 {
+        // Subnormal, or too small to represent at all
+        if exponent < -10 {
+            return sign;
+        }
+        return sign | round(mantissa | 0x80_0000, (14 - exponent) as u32) as u16;
+    }
+
+    // A mantissa that rounds up carries into the exponent, reaching infinity if needed
+    sign | round(((exponent as u32) << 23) | mantissa, 13) as u16
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let negative = half & 0x8000 != 0;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+
+    let magnitude = match exponent {
+        0 => mantissa as f32 * 2f32.powi(-24),
+        0x1f => f32::from_bits(0x7f80_0000 | (mantissa << 13)),
+        _ => f32::from_bits(((exponent + 127 - 15) << 23) | (mantissa << 13)),
+    };
+    if negative {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+fn write_bytes<'a>(env: Env<'a>, len: usize, fill: impl FnOnce(&mut [u8])) -> NifResult<Binary<'a>> {
+    let mut binary = OwnedBinary::new(len).ok_or_else(|| Error::Term(Box::new("Failed to allocate vector binary")))?;
+    fill(binary.as_mut_slice());
+    Ok(binary.release(env))
+}
+
+// Pack vectors into a single little-endian f32 binary
+pub fn pack_f32<'a>(env: Env<'a>, vectors: &[Vec<f32>]) -> NifResult<Binary<'a>> {
+    let len: usize = vectors.iter().map(|v| v.len() * 4).sum();
+    write_bytes(env, len, |bytes| {
+        for (slot, value) in bytes.chunks_exact_mut(4).zip(vectors.iter().flatten()) {
+            slot.copy_from_slice(&value.to_le_bytes());
+        }
+    })
+}
+
+// Pack float lists into one row-major little-endian binary of f32 or f16 values
+#[rustler::nif(schedule = "DirtyCpu")]
+fn pack_vectors<'a>(env: Env<'a>, vectors: Vec<Vec<f64>>, opts: Opts<'a>) -> NifResult<Binary<'a>> {
+    let precision = Precision::decode(&opts)?;
+    if let Some((index, vector)) = vectors.iter().enumerate().find(|(_, v)| v.len() != vectors[0].len()) {
+        return Err(Error::Term(Box::new(format!(
+            "Vector {} has {} dimensions, expected {}",
+            index,
+            vector.len(),
+            vectors[0].len()
+        ))));
+    }
+
+    let values = vectors.iter().flatten().map(|&x| x as f32);
+    let len = vectors.iter().map(Vec::len).sum::<usize>() * precision.width();
+    write_bytes(env, len, |bytes| match precision {
+        Precision::F32 => {
+            for (slot, value) in bytes.chunks_exact_mut(4).zip(values) {
+                slot.copy_from_slice(&value.to_le_bytes());
+            }
+        },
+        Precision::F16 => {
+            for (slot, value) in bytes.chunks_exact_mut(2).zip(values) {
+                slot.copy_from_slice(&f32_to_f16(value).to_le_bytes());
+            }
+        },
+    })
+}
+
+// Split a packed binary back into float lists of `dimensions` values each
+#[rustler::nif(schedule = "DirtyCpu")]
+fn unpack_vectors(packed: Binary, opts: Opts) -> NifResult<Vec<Vec<f64>>> {
+    let precision = Precision::decode(&opts)?;
+    let bytes = packed.as_slice();
+    if !bytes.len().is_multiple_of(precision.width()) {
+        return Err(Error::Term(Box::new(format!(
+            "Binary of {} bytes is not a whole number of {}-byte values",
+            bytes.len(),
+            precision.width()
+        ))));
+    }
+
+    let values: Vec<f64> = match precision {
+        Precision::F32 => bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+            .collect(),
+        Precision::F16 => bytes
+            .chunks_exact(2)
+            .map(|b| f16_to_f32(u16::from_le_bytes([b[0], b[1]])) as f64)
+            .collect(),
+    };
+
+    // Without `dimensions` the whole binary is one vector
+    let dimensions = options::get_usize(&opts, "dimensions")?.unwrap_or(values.len());
+    if dimensions == 0 {
+        return Ok(Vec::new());
+    }
+    if !values.len().is_multiple_of(dimensions) {
+        return Err(Error::Term(Box::new(format!(
+            "{} values don't divide into vectors of {} dimensions",
+            values.len(),
+            dimensions
+        ))));
+    }
+
+    Ok(values.chunks(dimensions).map(<[f64]>::to_vec).collect())
+}
+
+// Decode an embedding returned with `encoding_format: "base64"` (little-endian f32)
+#[rustler::nif]
+fn base64_to_vector(payload: String) -> NifResult<Vec<f64>> {
+    let bytes = base64::engine::general_purpose::STANDARD
+        .decode(payload.trim())
+        .map_err(|e| Error::Term(Box::new(format!("Invalid base64: {}", e))))?;
+    if !bytes.len().is_multiple_of(4) {
+        return Err(Error::Term(Box::new(format!("Payload of {} bytes is not a list of f32 values", bytes.len()))));
+    }
+
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64)
+        .collect())
+}
+
+#[rustler::nif]
+fn vector_to_base64(vector: Vec<f64>) -> String {
+    let bytes: Vec<u8> = vector.iter().flat_map(|&x| (x as f32).to_le_bytes()).collect();
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
@@ -0,0 +1,268 @@
+use async_openai::types::{CreateChatCompletionRequestArgs, CreateSpeechRequest, SpeechModel, SpeechResponseFormat, Voice};
+use futures_util::stream::FuturesOrdered;
+use futures_util::StreamExt;
+use rustler::{Env, Error, LocalPid, NifResult, ResourceArc, Term};
+
+use crate::attribution::Attribution;
+use crate::chunks::Opener;
+use crate::deadline::Deadline;
+use crate::http::{ApiError, Transport};
+use crate::keepwarm;
+use crate::limits::Endpoint;
+use crate::options::{self, Opts};
+use crate::output::Output;
+use crate::params::ChatParams;
+use crate::prompts;
+use crate::resample;
+use crate::retry;
+use crate::streaming::{ChunkClock, Halt, Segmentation, Segmenter, StreamError, StreamFilter};
+use crate::{atoms, to_request_messages, Message, OpenAIClientResource};
+
+// Speech options by the names the API uses. `capabilities/0` reports these same
+// tables, so Elixir pickers can't drift from what the NIFs accept.
+pub const SPEECH_MODELS: &[(&str, SpeechModel)] = &[("tts-1", SpeechModel::Tts1), ("tts-1-hd", SpeechModel::Tts1Hd)];
+
+pub const VOICES: &[(&str, Voice)] = &[
+    ("alloy", Voice::Alloy),
+    ("echo", Voice::Echo),
+    ("fable", Voice::Fable),
+    ("onyx", Voice::Onyx),
+    ("nova", Voice::Nova),
+    ("shimmer", Voice::Shimmer),
+];
+
+pub const SPEECH_FORMATS: &[(&str, SpeechResponseFormat)] = &[
+    ("mp3", SpeechResponseFormat::Mp3),
+    ("opus", SpeechResponseFormat::Opus),
+    ("aac", SpeechResponseFormat::Aac),
+    ("flac", SpeechResponseFormat::Flac),
+    ("pcm", SpeechResponseFormat::Pcm),
+    ("wav", SpeechResponseFormat::Wav),
+];
+
+// File extension for audio in `format`, the format's name in `SPEECH_FORMATS`
+pub fn extension(format: SpeechResponseFormat) -> &'static str {
+    SPEECH_FORMATS.iter().find(|(_, known)| *known == format).map_or("mp3", |(name, _)| name)
+}
+
+pub fn lookup<T: Clone>(table: &[(&str, T)], name: &str) -> Option<T> {
+    table.iter().find(|(known, _)| *known == name).map(|(_, value)| value.clone())
+}
+
+// Speech settings shared by every request; `input` is filled in per request. The
+// pipeline reads the model and format from `speech_*` keys since `model` is the chat model.
+// Unknown models and voices are passed through for newer API versions.
+pub fn decode_speech(opts: &Opts, model_key: &str, format_key: &str) -> NifResult<CreateSpeechRequest> {
+    let model = match options::get_string(opts, model_key)? {
+        None => SpeechModel::Tts1,
+        Some(name) => lookup(SPEECH_MODELS, &name).unwrap_or(SpeechModel::Other(name)),
+    };
+    let voice = match options::get_string(opts, "voice")? {
+        None => Voice::Alloy,
+        Some(name) => lookup(VOICES, &name).unwrap_or(Voice::Other(name)),
+    };
+    let response_format = match options::get_string(opts, format_key)? {
+        None => SpeechResponseFormat::Mp3,
+        Some(name) => lookup(SPEECH_FORMATS, &name)
+            .ok_or_else(|| options::invalid(&options::path(format_key), format!("is not a supported audio format: {:?}", name)))?,
+    };
+
+    Ok(CreateSpeechRequest {
+        input: String::new(),
+        model,
+        voice,
+        response_format: Some(response_format),
+        speed: options::get_f32(opts, "speed")?,
+    })
+}
+
+// Embeds the client's attribution marker, if it has one, in synthesized audio
+pub fn tag(attribution: &Option<Attribution>, audio: Vec<u8>, format: &str, model: &str) -> Vec<u8> {
+    match attribution {
+        Some(attribution) => attribution.tag_audio(audio, format, model),
+        None => audio,
+    }
+}
+
+async fn synthesize(transport: &Transport, speech: &CreateSpeechRequest, index: usize, input: String) -> (usize, Result<Vec<u8>, ApiError>) {
+    let request = CreateSpeechRequest { input, ..speech.clone() };
+    let audio = transport
+        .post_json("/audio/speech", &request)
+        .await
+        .map(|response| response.body)
+        .map_err(|e| e.context("API speech request failed"));
+    (index, audio)
+}
+
+// Streams a completion to `pid` as text segments and, as each segment completes,
+// synthesizes it so audio for the first sentence is on its way while the rest is
+// still being generated. Sends, all tagged with `ref_term`:
+//
+//   {:stream_chunk, text, timing, ref}  for every text segment, in order
+//   {:stream_audio, index, audio, ref}  audio for the index-th segment, in order
+//   {:stream_done, ref} or {:stream_error, error, ref} once everything was sent
+#[rustler::nif(schedule = "DirtyIo")]
+fn speak_completion(env: Env, client_resource: ResourceArc<OpenAIClientResource>, messages: Vec<Message>, model: &str, opts: Opts, pid: LocalPid, ref_term: Term) -> NifResult<rustler::Atom> {
+    let mut filter = StreamFilter::decode(&opts, model)?;
+    let mut segmenter = Segmenter::decode(&opts, Segmentation::Sentence)?;
+    let mut clock = ChunkClock::default();
+    let deadline = Deadline::decode(&opts)?;
+    let speech = decode_speech(&opts, "speech_model", "speech_format")?;
+    let output = Output::decode(&opts)?;
+    let extension = options::get_string(&opts, "speech_format")?.unwrap_or_else(|| "mp3".to_string());
+    let speech_model = options::get_string(&opts, "speech_model")?.unwrap_or_else(|| "tts-1".to_string());
+    let attribution = client_resource.attribution()?;
+
+    let faults = client_resource.faults()?;
+    let stream_retry = client_resource.stream_retry()?;
+    let transport = client_resource.transport_for(Endpoint::Audio, &opts)?.with_deadline(deadline);
+
+    let params = ChatParams::decode(&opts)?;
+    params.check_streamable()?;
+    // Only text is spoken, so a turn spent calling tools would say nothing
+    if options::get_list::<Term>(&opts, "tools")?.is_some() {
+        return Err(options::invalid(&options::path("tools"), "is not supported when speaking"));
+    }
+    let messages = prompts::splice(&client_resource, &opts, messages)?;
+    let opener = Opener::new(&client_resource, &messages, &params, false, deadline)?;
+    let chat_messages = to_request_messages(messages).map_err(|e| Error::Term(Box::new(e)))?;
+    let mut args = CreateChatCompletionRequestArgs::default();
+    args.model(model).messages(chat_messages).stream(true);
+    params.apply(&mut args);
+    resample::decode_schedule(&opts)?[0].apply(&mut args);
+    let request = args
+        .build()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to build request: {}", e))))?;
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    // Every segment sent so far, reported with any error
+    let mut delivered = String::new();
+    let mut segments = 0;
+    // Whether the completion failed because a proxy closed it, for the error's code
+    let mut proxy_idle = false;
+
+    let _stream = client_resource.stats.stream();
+    let result: Result<bool, (String, bool)> = runtime.block_on(async {
+        let mut stream = retry::open_chat_stream(&opener, request, stream_retry, deadline, client_resource.keep_warm, faults.as_ref())
+            .await
+            .map_err(|e| (e, false))?;
+
+        // Audio requests run concurrently but are delivered in segment order
+        let mut pending = FuturesOrdered::new();
+        let mut finished = false;
+        let mut exceeded = false;
+
+        loop {
+            let mut texts = Vec::new();
+            tokio::select! {
+                next = deadline.run(stream.next()), if !finished => {
+                    match next {
+                        None => return Err(("Deadline exceeded".to_string(), false)),
+                        Some(Some(Err(e))) => {
+                            proxy_idle = keepwarm::is_proxy_idle(&e);
+                            return Err((format!("Stream error: {}", e), retry::retryable(&e)));
+                        },
+                        Some(Some(Ok(chunk))) => {
+                            for choice in chunk.response.choices {
+                                if let Some(content) = &choice.delta.content {
+                                    let (text, halted) = filter.push(content);
+                                    texts.push(text);
+                                    // Dropping the stream closes the connection so no more tokens are generated
+                                    exceeded |= matches!(halted, Some(Halt::MaxSizeExceeded));
+                                    finished |= halted.is_some();
+                                }
+                            }
+                        },
+                        Some(None) => {
+                            let (text, halted) = filter.flush();
+                            texts.push(text);
+                            exceeded |= matches!(halted, Some(Halt::MaxSizeExceeded));
+                            finished = true;
+                        },
+                    }
+
+                    let mut completed: Vec<String> = texts
+                        .iter()
+                        .filter(|text| !text.is_empty())
+                        .flat_map(|text| segmenter.push(text))
+                        .collect();
+                    if finished {
+                        completed.extend(segmenter.flush());
+                    }
+
+                    for segment in completed {
+                        let _ = env.send(&pid, (atoms::stream_chunk(), segment.as_str(), clock.tick(), ref_term));
+                        delivered.push_str(&segment);
+                        if !segment.trim().is_empty() {
+                            pending.push_back(synthesize(&transport, &speech, segments, segment));
+                        }
+                        segments += 1;
+                    }
+                },
+                Some((index, audio)) = pending.next(), if !pending.is_empty() => {
+                    let audio = audio.map_err(|e| (e.message, e.retryable))?;
+                    let audio = tag(&attribution, audio, &extension, &speech_model);
+                    let audio = output.encode(env, &audio, &extension).map_err(|e| (e, false))?;
+                    let _ = env.send(&pid, (atoms::stream_audio(), index, audio, ref_term));
+                },
+                else => break,
+            }
+        }
+
+        Ok(exceeded)
+    });
+
+    match result {
+        // The segments within the limit were still spoken
+        Ok(true) => {
+            let _ = env.send(&pid, (atoms::stream_error(), StreamError::max_size_exceeded(delivered, segments), ref_term));
+        },
+        Ok(false) => {
+            let _ = env.send(&pid, (atoms::stream_done(), ref_term));
+        },
+        Err((message, _)) if proxy_idle => {
+            let error = StreamError::proxy_idle_timeout(message, delivered, segments);
+            let _ = env.send(&pid, (atoms::stream_error(), error, ref_term));
+        },
+        Err((message, retryable)) => {
+            let error = StreamError::new(message, retryable, delivered, segments);
+            let _ = env.send(&pid, (atoms::stream_error(), error, ref_term));
+        },
+    }
+
+    Ok(atoms::ok())
+}
+
+// Synthesizes every input with bounded concurrency, returning the audio in input order
+#[rustler::nif(schedule = "DirtyIo")]
+fn text_to_speech_many<'a>(env: Env<'a>, client_resource: ResourceArc<OpenAIClientResource>, inputs: Vec<String>, opts: Opts<'a>) -> NifResult<Vec<Term<'a>>> {
+    let speech = decode_speech(&opts, "model", "response_format")?;
+    let output = Output::decode(&opts)?;
+    let extension = options::get_string(&opts, "response_format")?.unwrap_or_else(|| "mp3".to_string());
+    let model = options::get_string(&opts, "model")?.unwrap_or_else(|| "tts-1".to_string());
+    let attribution = client_resource.attribution()?;
+    let concurrency = options::get_usize(&opts, "concurrency")?.unwrap_or(4).max(1);
+    let transport = client_resource.transport_for(Endpoint::Audio, &opts)?.with_deadline(Deadline::decode(&opts)?);
+
+    let runtime = tokio::runtime::Runtime::new()
+        .map_err(|e| Error::Term(Box::new(format!("Failed to create Tokio runtime: {}", e))))?;
+
+    let results = runtime.block_on(async {
+        futures_util::stream::iter(inputs.into_iter().enumerate())
+            .map(|(index, input)| synthesize(&transport, &speech, index, input))
+            .buffered(concurrency)
+            .collect::<Vec<_>>()
+            .await
+    });
+
+    results
+        .into_iter()
+        .map(|(index, audio)| {
+            let audio = audio.map_err(|e| e.context(&format!("Input {}", index)))?;
+            let audio = tag(&attribution, audio, &extension, &model);
+            output.encode(env, &audio, &extension).map_err(|e| Error::Term(Box::new(e)))
+        })
+        .collect()
+}